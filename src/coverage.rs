@@ -0,0 +1,280 @@
+//!
+//! Output of the code coverage: lcov tracefiles and raw address hit counts
+//!
+
+use crate::debuginfo::LineTable;
+use crate::image::Symbol;
+use std::collections::{BTreeMap, HashSet};
+use std::io;
+use zmu_cortex_m::core::coverage::{BranchCoverage, Coverage};
+
+#[derive(Default)]
+struct FileCoverage<'a> {
+    lines: BTreeMap<u32, u64>,
+    functions: Vec<(u32, &'a str, u64)>,
+    // outcomes of the conditional instructions by line and address,
+    // None for the ones never executed
+    branches: BTreeMap<u32, BTreeMap<u32, Option<BranchCoverage>>>,
+}
+
+///
+/// Write the execution count of each executed address, followed by the
+/// taken and not taken counts of conditional instructions
+///
+pub fn write_hits(out: &mut dyn io::Write, coverage: &Coverage) -> io::Result<()> {
+    let hits: BTreeMap<_, _> = coverage.hits().iter().collect();
+    for (address, count) in hits {
+        match coverage.branches().get(address) {
+            Some(branch) => writeln!(
+                out,
+                "0x{:08x} {} {} {}",
+                address, count, branch.taken, branch.not_taken
+            )?,
+            None => writeln!(out, "0x{:08x} {}", address, count)?,
+        }
+    }
+    Ok(())
+}
+
+///
+/// Write an lcov tracefile with the line, function and branch coverage of
+/// the source files in the line table. Only lines of code at addresses
+/// accepted by `loaded` are included. The branches of the `conditionals`
+/// never executed are listed as not reached.
+///
+pub fn write_lcov(
+    out: &mut dyn io::Write,
+    coverage: &Coverage,
+    lines: &LineTable,
+    symbols: &[Symbol],
+    conditionals: &[u32],
+    loaded: impl Fn(u32) -> bool,
+) -> io::Result<()> {
+    let mut files: BTreeMap<&str, FileCoverage> = BTreeMap::new();
+
+    for (address, location) in lines.locations() {
+        if loaded(address) {
+            files
+                .entry(location.file)
+                .or_default()
+                .lines
+                .entry(location.line)
+                .or_insert(0);
+        }
+    }
+    for (address, count) in coverage.hits() {
+        if let Some(location) = lines.lookup(*address) {
+            let hits = files
+                .entry(location.file)
+                .or_default()
+                .lines
+                .entry(location.line)
+                .or_insert(0);
+            *hits = (*hits).max(*count);
+        }
+    }
+
+    let mut names = HashSet::new();
+    for symbol in symbols {
+        let address = symbol.address & !1;
+        if symbol.size == 0 || !loaded(address) || !names.insert(symbol.name.as_str()) {
+            continue;
+        }
+        if let Some(location) = lines.lookup(address) {
+            let count = coverage.hits().get(&address).copied().unwrap_or(0);
+            files.entry(location.file).or_default().functions.push((
+                location.line,
+                &symbol.name,
+                count,
+            ));
+        }
+    }
+
+    let branches = conditionals
+        .iter()
+        .filter(|address| loaded(**address))
+        .map(|address| (*address, None))
+        .chain(
+            coverage
+                .branches()
+                .iter()
+                .map(|(address, branch)| (*address, Some(*branch))),
+        );
+    for (address, branch) in branches {
+        if let Some(location) = lines.lookup(address) {
+            let line_branches = files
+                .entry(location.file)
+                .or_default()
+                .branches
+                .entry(location.line)
+                .or_default();
+            let entry = line_branches.entry(address).or_default();
+            *entry = entry.or(branch);
+        }
+    }
+
+    for (file, mut file_coverage) in files {
+        writeln!(out, "TN:")?;
+        writeln!(out, "SF:{}", file)?;
+
+        file_coverage.functions.sort();
+        for (line, name, _) in &file_coverage.functions {
+            writeln!(out, "FN:{},{}", line, name)?;
+        }
+        for (_, name, count) in &file_coverage.functions {
+            writeln!(out, "FNDA:{},{}", count, name)?;
+        }
+        writeln!(out, "FNF:{}", file_coverage.functions.len())?;
+        writeln!(
+            out,
+            "FNH:{}",
+            file_coverage
+                .functions
+                .iter()
+                .filter(|(_, _, count)| *count > 0)
+                .count()
+        )?;
+
+        let mut found = 0;
+        let mut hit = 0;
+        for (line, line_branches) in &file_coverage.branches {
+            for (block, branch) in line_branches.values().enumerate() {
+                match branch {
+                    Some(branch) => {
+                        for (i, count) in [branch.taken, branch.not_taken].iter().enumerate() {
+                            writeln!(out, "BRDA:{},{},{},{}", line, block, i, count)?;
+                            if *count > 0 {
+                                hit += 1;
+                            }
+                        }
+                    }
+                    None => {
+                        for i in 0..2 {
+                            writeln!(out, "BRDA:{},{},{},-", line, block, i)?;
+                        }
+                    }
+                }
+                found += 2;
+            }
+        }
+        writeln!(out, "BRF:{}", found)?;
+        writeln!(out, "BRH:{}", hit)?;
+
+        for (line, count) in &file_coverage.lines {
+            writeln!(out, "DA:{},{}", line, count)?;
+        }
+        writeln!(out, "LF:{}", file_coverage.lines.len())?;
+        writeln!(
+            out,
+            "LH:{}",
+            file_coverage
+                .lines
+                .values()
+                .filter(|count| **count > 0)
+                .count()
+        )?;
+        writeln!(out, "end_of_record")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use zmu_cortex_m::core::condition::Condition;
+    use zmu_cortex_m::core::coverage::CoverageRecording;
+    use zmu_cortex_m::core::instruction::Instruction;
+    use zmu_cortex_m::Processor;
+
+    fn run_coverage(executed: &[(u32, Option<bool>)]) -> Processor {
+        let mut processor = Processor::new();
+        processor.coverage(Some(Coverage::new()));
+        let beq = Instruction::B_t13 {
+            cond: Condition::EQ,
+            imm32: 8,
+            thumb32: false,
+        };
+        let nop = Instruction::NOP { thumb32: false };
+        for (pc, branched) in executed {
+            match branched {
+                Some(branched) => processor.coverage_update(&beq, *pc, false, true, *branched),
+                None => processor.coverage_update(&nop, *pc, false, true, false),
+            }
+        }
+        processor
+    }
+
+    #[test]
+    fn test_write_hits() {
+        let processor = run_coverage(&[(0x102, None), (0x100, Some(true)), (0x102, None)]);
+        let mut out = Vec::new();
+        write_hits(&mut out, processor.code_coverage().unwrap()).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "0x00000100 1 1 0\n0x00000102 2\n"
+        );
+    }
+
+    #[test]
+    fn test_write_lcov() {
+        let processor = run_coverage(&[(0x100, Some(true)), (0x104, None)]);
+        let lines = LineTable::from_rows(
+            &["main.c"],
+            &[
+                (0x100, Some((0, 10))),
+                (0x104, Some((0, 11))),
+                (0x108, Some((0, 12))),
+                (0x10c, None),
+            ],
+        );
+        let symbols = [
+            Symbol {
+                name: "main".to_string(),
+                address: 0x101,
+                size: 8,
+            },
+            Symbol {
+                name: "unused".to_string(),
+                address: 0x109,
+                size: 4,
+            },
+        ];
+        let mut out = Vec::new();
+        write_lcov(
+            &mut out,
+            processor.code_coverage().unwrap(),
+            &lines,
+            &symbols,
+            &[0x100, 0x106, 0x10a, 0x108],
+            |address| address < 0x10c,
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "TN:\n\
+             SF:main.c\n\
+             FN:10,main\n\
+             FN:12,unused\n\
+             FNDA:1,main\n\
+             FNDA:0,unused\n\
+             FNF:2\n\
+             FNH:1\n\
+             BRDA:10,0,0,1\n\
+             BRDA:10,0,1,0\n\
+             BRDA:11,0,0,-\n\
+             BRDA:11,0,1,-\n\
+             BRDA:12,0,0,-\n\
+             BRDA:12,0,1,-\n\
+             BRDA:12,1,0,-\n\
+             BRDA:12,1,1,-\n\
+             BRF:8\n\
+             BRH:1\n\
+             DA:10,1\n\
+             DA:11,1\n\
+             DA:12,0\n\
+             LF:3\n\
+             LH:2\n\
+             end_of_record\n"
+        );
+    }
+}
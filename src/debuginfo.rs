@@ -0,0 +1,308 @@
+//!
+//! Source line information from the DWARF `.debug_line` section
+//!
+
+use gimli::{EndianSlice, RunTimeEndian};
+use goblin::elf::Elf;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+
+///
+/// Source file and line of an address
+///
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct SourceLocation<'a> {
+    pub file: &'a str,
+    pub line: u32,
+}
+
+impl fmt::Display for SourceLocation<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)
+    }
+}
+
+// Start of an address range, or the end of a sequence when location is None
+struct Row {
+    address: u32,
+    location: Option<(usize, u32)>,
+}
+
+///
+/// Address to source line lookup table
+///
+#[derive(Default)]
+pub struct LineTable {
+    files: Vec<String>,
+    rows: Vec<Row>,
+}
+
+impl LineTable {
+    ///
+    /// Read the line number programs of all compilation units of an ELF file.
+    /// Images without debug information give an empty table.
+    ///
+    pub fn from_elf(buffer: &[u8], elf: &Elf) -> Self {
+        let mut table = Self::default();
+        if let Err(e) = table.read_dwarf(buffer, elf) {
+            warn!("failed to read DWARF line information: {}", e);
+        }
+        table.sort();
+        table
+    }
+
+    fn read_dwarf(&mut self, buffer: &[u8], elf: &Elf) -> Result<(), gimli::Error> {
+        let endian = if elf.little_endian {
+            RunTimeEndian::Little
+        } else {
+            RunTimeEndian::Big
+        };
+        let section = |id: gimli::SectionId| -> Result<_, gimli::Error> {
+            let data = elf
+                .section_headers
+                .iter()
+                .find(|sh| {
+                    elf.shdr_strtab
+                        .get(sh.sh_name)
+                        .and_then(|name| name.ok())
+                        .is_some_and(|name| name == id.name())
+                })
+                .and_then(|sh| {
+                    buffer.get(sh.sh_offset as usize..(sh.sh_offset + sh.sh_size) as usize)
+                })
+                .unwrap_or(&[]);
+            Ok(EndianSlice::new(data, endian))
+        };
+        let dwarf = gimli::Dwarf::load(section)?;
+
+        let mut file_indices = HashMap::new();
+        let mut units = dwarf.units();
+        while let Some(header) = units.next()? {
+            let unit = dwarf.unit(header)?;
+            let program = match unit.line_program.clone() {
+                Some(program) => program,
+                None => continue,
+            };
+            let comp_dir = unit
+                .comp_dir
+                .map(|dir| dir.to_string_lossy().into_owned())
+                .unwrap_or_default();
+
+            let mut rows = program.rows();
+            while let Some((header, row)) = rows.next_row()? {
+                let address = row.address() as u32;
+                if row.end_sequence() {
+                    self.rows.push(Row {
+                        address,
+                        location: None,
+                    });
+                    continue;
+                }
+                let line = match row.line() {
+                    Some(line) => line.get() as u32,
+                    None => continue,
+                };
+                let file = match row.file(header) {
+                    Some(file) => file,
+                    None => continue,
+                };
+                let mut path = PathBuf::from(&comp_dir);
+                if let Some(dir) = file.directory(header) {
+                    path.push(dwarf.attr_string(&unit, dir)?.to_string_lossy().as_ref());
+                }
+                path.push(
+                    dwarf
+                        .attr_string(&unit, file.path_name())?
+                        .to_string_lossy()
+                        .as_ref(),
+                );
+                let path = path.to_string_lossy().into_owned();
+
+                let files = &mut self.files;
+                let index = *file_indices.entry(path).or_insert_with_key(|path| {
+                    files.push(path.clone());
+                    files.len() - 1
+                });
+                // the last row generated for an address wins
+                if let Some(last) = self.rows.last_mut() {
+                    if last.address == address && last.location.is_some() {
+                        last.location = Some((index, line));
+                        continue;
+                    }
+                }
+                self.rows.push(Row {
+                    address,
+                    location: Some((index, line)),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    // Order by address, with the end of a sequence before a sequence
+    // starting at the same address
+    fn sort(&mut self) {
+        self.rows
+            .sort_by_key(|row| (row.address, row.location.is_some()));
+    }
+
+    ///
+    /// Table of `(address, (file index, line))` rows, with `None` ending
+    /// a sequence
+    ///
+    #[cfg(test)]
+    pub fn from_rows(files: &[&str], rows: &[(u32, Option<(usize, u32)>)]) -> Self {
+        let mut table = Self {
+            files: files.iter().map(|file| file.to_string()).collect(),
+            rows: rows
+                .iter()
+                .map(|(address, location)| Row {
+                    address: *address,
+                    location: *location,
+                })
+                .collect(),
+        };
+        table.sort();
+        table
+    }
+
+    ///
+    /// Source location of the instruction at an address
+    ///
+    pub fn lookup(&self, address: u32) -> Option<SourceLocation<'_>> {
+        let index = self.rows.partition_point(|row| row.address <= address);
+        let (file, line) = self.rows.get(index.checked_sub(1)?)?.location?;
+        Some(SourceLocation {
+            file: &self.files[file],
+            line,
+        })
+    }
+
+    ///
+    /// Start addresses of the table rows with their source locations
+    ///
+    pub fn locations(&self) -> impl Iterator<Item = (u32, SourceLocation<'_>)> {
+        self.rows.iter().filter_map(move |row| {
+            row.location.map(|(file, line)| {
+                (
+                    row.address,
+                    SourceLocation {
+                        file: &self.files[file],
+                        line,
+                    },
+                )
+            })
+        })
+    }
+
+    ///
+    /// Move the addresses of the table by an offset
+    ///
+    pub fn relocate(&mut self, offset: u32) {
+        for row in &mut self.rows {
+            row.address = row.address.wrapping_add(offset);
+        }
+    }
+
+    ///
+    /// Add the lines of another table
+    ///
+    pub fn extend(&mut self, other: Self) {
+        let base = self.files.len();
+        self.files.extend(other.files);
+        self.rows.extend(other.rows.into_iter().map(|row| Row {
+            address: row.address,
+            location: row.location.map(|(file, line)| (file + base, line)),
+        }));
+        self.sort();
+    }
+}
+
+///
+/// Cache of source files read for showing source lines
+///
+#[derive(Default)]
+pub struct SourceFiles {
+    files: HashMap<String, Option<Vec<String>>>,
+}
+
+impl SourceFiles {
+    ///
+    /// Text of a source line, if the file can be read
+    ///
+    pub fn line(&mut self, location: &SourceLocation) -> Option<&str> {
+        let lines = self
+            .files
+            .entry(location.file.to_string())
+            .or_insert_with(|| {
+                fs::read(location.file).ok().map(|data| {
+                    String::from_utf8_lossy(&data)
+                        .lines()
+                        .map(|line| line.replace('\t', "    "))
+                        .collect()
+                })
+            })
+            .as_ref()?;
+        lines
+            .get((location.line as usize).checked_sub(1)?)
+            .map(String::as_str)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup() {
+        let mut table = LineTable {
+            files: vec!["main.c".to_string()],
+            rows: vec![
+                Row {
+                    address: 0x100,
+                    location: Some((0, 10)),
+                },
+                Row {
+                    address: 0x104,
+                    location: Some((0, 11)),
+                },
+                Row {
+                    address: 0x108,
+                    location: None,
+                },
+            ],
+        };
+        let mut other = LineTable {
+            files: vec!["lib.c".to_string()],
+            rows: vec![
+                Row {
+                    address: 0x8,
+                    location: Some((0, 3)),
+                },
+                Row {
+                    address: 0x10,
+                    location: None,
+                },
+            ],
+        };
+        other.relocate(0x100);
+        table.extend(other);
+
+        assert_eq!(table.lookup(0xfe), None);
+        assert_eq!(
+            table.lookup(0x106),
+            Some(SourceLocation {
+                file: "main.c",
+                line: 11
+            })
+        );
+        // the sequence starting where the previous one ends
+        assert_eq!(
+            table.lookup(0x108).map(|location| location.to_string()),
+            Some("lib.c:3".to_string())
+        );
+        assert_eq!(table.lookup(0x110), None);
+    }
+}
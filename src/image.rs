@@ -0,0 +1,581 @@
+//!
+//! Loading of firmware images: ELF, Intel HEX, Motorola S-record and raw binary
+//!
+
+use goblin::elf::program_header::pt_to_str;
+use goblin::Object;
+use std::cmp;
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::debuginfo::LineTable;
+use crate::errors::*;
+
+// ELF header flag of ARM BE8 images, with little-endian code and big-endian data
+const EF_ARM_BE8: u32 = 0x0080_0000;
+
+#[derive(PartialEq, Debug, Copy, Clone)]
+enum Format {
+    Elf,
+    IntelHex,
+    SRecord,
+    Binary,
+}
+
+///
+/// Contiguous block of data loaded to an address
+///
+pub struct Segment {
+    pub address: u32,
+    pub data: Vec<u8>,
+    /// zero-initialised tail of an ELF segment, allowed to overlap other images
+    pub zero_fill: bool,
+}
+
+///
+/// Named address, with the size of the object at the address
+///
+pub struct Symbol {
+    pub name: String,
+    pub address: u32,
+    pub size: u64,
+}
+
+///
+/// Loaded firmware image
+///
+pub struct Image {
+    pub segments: Vec<Segment>,
+    pub symbols: Vec<Symbol>,
+    pub big_endian: bool,
+    /// start address given in the file, if any
+    pub entry: Option<u32>,
+    /// source lines of the code, from DWARF debug information
+    pub lines: LineTable,
+}
+
+impl Image {
+    ///
+    /// Load an image, detecting the format from the ELF header, the file
+    /// name extension or the content. `base_address` places a raw binary.
+    ///
+    pub fn load(buffer: &[u8], filename: &str, base_address: Option<u32>) -> Result<Self> {
+        let extension = Path::new(filename)
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(str::to_lowercase);
+        let text = std::str::from_utf8(buffer).ok().map(str::trim_start);
+
+        let format = if buffer.starts_with(b"\x7fELF") {
+            Format::Elf
+        } else {
+            match extension.as_deref() {
+                Some("hex") | Some("ihex") | Some("ihx") => Format::IntelHex,
+                Some("srec") | Some("s19") | Some("s28") | Some("s37") | Some("mot") => {
+                    Format::SRecord
+                }
+                Some("bin") => Format::Binary,
+                _ => match text {
+                    Some(text) if text.starts_with(':') => Format::IntelHex,
+                    Some(text) if text.starts_with('S') => Format::SRecord,
+                    _ if base_address.is_some() => Format::Binary,
+                    _ => bail!("Unsupported file format, use --base-address for raw binaries."),
+                },
+            }
+        };
+        if base_address.is_some() && format != Format::Binary {
+            bail!("--base-address is only valid for raw binaries.");
+        }
+
+        match format {
+            Format::Elf => Self::from_elf(buffer),
+            Format::IntelHex => Self::from_ihex(text.ok_or("Intel HEX file is not text")?),
+            Format::SRecord => Self::from_srec(text.ok_or("S-record file is not text")?),
+            Format::Binary => Ok(Self::from_binary(buffer, base_address.unwrap_or(0))),
+        }
+    }
+
+    fn from_elf(buffer: &[u8]) -> Result<Self> {
+        let elf = match Object::parse(buffer) {
+            Ok(Object::Elf(elf)) => elf,
+            _ => bail!("Unsupported file format."),
+        };
+        debug!("Detected ELF file.");
+
+        // big-endian images run in the BE8 configuration, where the code stays little-endian
+        let big_endian = !elf.little_endian;
+        if big_endian && elf.header.e_flags & EF_ARM_BE8 == 0 {
+            bail!("Big-endian ELF file is not BE8, link it with --be8.");
+        }
+
+        // File data is placed at the load address, as a debugger would do, and the
+        // zero-initialised tail at the run address. The two differ for initialised
+        // data that the startup code copies from flash to RAM.
+        let mut segments = Vec::new();
+        for ph in &elf.program_headers {
+            if ph.p_type == goblin::elf::program_header::PT_LOAD && ph.p_memsz > 0 {
+                debug!(
+                    "PT_LOAD section at 0x{:08x} - 0x{:08x} (size = {} bytes), run address 0x{:08x} (size = {} bytes)",
+                    ph.p_paddr,
+                    ph.p_paddr + ph.p_filesz,
+                    ph.p_filesz,
+                    ph.p_vaddr,
+                    ph.p_memsz
+                );
+                if ph.p_filesz > 0 {
+                    let start = ph.p_offset as usize;
+                    let end = (ph.p_offset + ph.p_filesz) as usize;
+                    if end > buffer.len() {
+                        bail!(
+                            "ELF segment at 0x{:08x} extends past end of file",
+                            ph.p_paddr
+                        );
+                    }
+                    segments.push(Segment {
+                        address: ph.p_paddr as u32,
+                        data: buffer[start..end].to_vec(),
+                        zero_fill: false,
+                    });
+                }
+                if ph.p_memsz > ph.p_filesz {
+                    segments.push(Segment {
+                        address: (ph.p_vaddr + ph.p_filesz) as u32,
+                        data: vec![0; (ph.p_memsz - ph.p_filesz) as usize],
+                        zero_fill: true,
+                    });
+                }
+            } else {
+                debug!(
+                    "ignoring section : {} (size = {} bytes)",
+                    pt_to_str(ph.p_type),
+                    ph.p_filesz
+                );
+            }
+        }
+
+        let symbols = elf
+            .syms
+            .iter()
+            .filter(|sym| {
+                sym.st_type() != goblin::elf::sym::STT_FILE
+                    && sym.st_type() != goblin::elf::sym::STT_SECTION
+            })
+            .filter_map(|sym| {
+                elf.strtab.get(sym.st_name).map(|name| Symbol {
+                    name: name.unwrap_or("unknown").to_string(),
+                    address: sym.st_value as u32,
+                    size: sym.st_size,
+                })
+            })
+            // ARM mapping symbols $a, $t and $d mark code and data, not objects
+            .filter(|symbol| !symbol.name.is_empty() && !symbol.name.starts_with('$'))
+            .collect();
+
+        Ok(Self {
+            segments,
+            symbols,
+            big_endian,
+            entry: Some(elf.entry as u32).filter(|entry| *entry != 0),
+            lines: LineTable::from_elf(buffer, &elf),
+        })
+    }
+
+    fn from_ihex(text: &str) -> Result<Self> {
+        debug!("Detected Intel HEX file.");
+        let mut segments = Vec::new();
+        let mut base = 0_u32;
+        let mut entry = None;
+        let mut end_of_file = false;
+
+        for (n, line) in text.lines().map(str::trim).enumerate() {
+            if line.is_empty() {
+                continue;
+            }
+            let record = parse_record(line, ':', n)?;
+            if record.len() < 5 || record.len() != usize::from(record[0]) + 5 {
+                bail!("line {}: invalid record length", n + 1);
+            }
+            if record.iter().fold(0_u8, |sum, b| sum.wrapping_add(*b)) != 0 {
+                bail!("line {}: checksum mismatch", n + 1);
+            }
+            let offset = (u32::from(record[1]) << 8) | u32::from(record[2]);
+            let data = &record[4..record.len() - 1];
+            match record[3] {
+                0x00 => add_data(&mut segments, base.wrapping_add(offset), data),
+                0x01 => {
+                    end_of_file = true;
+                    break;
+                }
+                0x02 if data.len() == 2 => {
+                    base = ((u32::from(data[0]) << 8) | u32::from(data[1])) << 4;
+                }
+                0x04 if data.len() == 2 => {
+                    base = ((u32::from(data[0]) << 8) | u32::from(data[1])) << 16;
+                }
+                0x03 if data.len() == 4 => {
+                    let segment = (u32::from(data[0]) << 8) | u32::from(data[1]);
+                    let offset = (u32::from(data[2]) << 8) | u32::from(data[3]);
+                    entry = Some((segment << 4) + offset);
+                }
+                0x05 if data.len() == 4 => entry = Some(be_address(data)),
+                kind => bail!("line {}: invalid record type {:02x}", n + 1, kind),
+            }
+        }
+        if !end_of_file {
+            bail!("missing end of file record");
+        }
+
+        Ok(Self {
+            segments,
+            symbols: Vec::new(),
+            big_endian: false,
+            entry,
+            lines: LineTable::default(),
+        })
+    }
+
+    fn from_srec(text: &str) -> Result<Self> {
+        debug!("Detected Motorola S-record file.");
+        let mut segments = Vec::new();
+        let mut entry = None;
+
+        for (n, line) in text.lines().map(str::trim).enumerate() {
+            if line.is_empty() {
+                continue;
+            }
+            let digits = match line.strip_prefix('S') {
+                Some(digits) => digits,
+                None => bail!("line {}: invalid record '{}'", n + 1, line),
+            };
+            let kind = digits.chars().next().unwrap_or(' ');
+            let record = parse_record(digits, kind, n)?;
+            if record.is_empty() || record.len() != usize::from(record[0]) + 1 {
+                bail!("line {}: invalid record length", n + 1);
+            }
+            if record.iter().fold(0_u8, |sum, b| sum.wrapping_add(*b)) != 0xff {
+                bail!("line {}: checksum mismatch", n + 1);
+            }
+            let address_len = match kind {
+                '1' | '9' => 2,
+                '2' | '8' => 3,
+                '3' | '7' => 4,
+                // header and record counts
+                '0' | '5' | '6' => continue,
+                _ => bail!("line {}: invalid record type S{}", n + 1, kind),
+            };
+            if record.len() < address_len + 2 {
+                bail!("line {}: invalid record length", n + 1);
+            }
+            let address = be_address(&record[1..=address_len]);
+            match kind {
+                '7' | '8' | '9' => entry = Some(address),
+                _ => add_data(
+                    &mut segments,
+                    address,
+                    &record[address_len + 1..record.len() - 1],
+                ),
+            }
+        }
+
+        Ok(Self {
+            segments,
+            symbols: Vec::new(),
+            big_endian: false,
+            entry,
+            lines: LineTable::default(),
+        })
+    }
+
+    fn from_binary(buffer: &[u8], base_address: u32) -> Self {
+        debug!("Loading raw binary at 0x{:08x}.", base_address);
+        Self {
+            segments: vec![Segment {
+                address: base_address,
+                data: buffer.to_vec(),
+                zero_fill: false,
+            }],
+            symbols: Vec::new(),
+            big_endian: false,
+            entry: None,
+            lines: LineTable::default(),
+        }
+    }
+
+    ///
+    /// Move the image by `offset` bytes
+    ///
+    pub fn relocate(&mut self, offset: u32) {
+        for segment in &mut self.segments {
+            segment.address = segment.address.wrapping_add(offset);
+        }
+        for symbol in &mut self.symbols {
+            symbol.address = symbol.address.wrapping_add(offset);
+        }
+        self.entry = self.entry.map(|entry| entry.wrapping_add(offset));
+        self.lines.relocate(offset);
+    }
+
+    ///
+    /// Lowest address of the loaded data, where the vector table of a
+    /// Cortex-M image is
+    ///
+    pub fn vector_table(&self) -> Option<u32> {
+        self.segments
+            .iter()
+            .filter(|segment| !segment.zero_fill)
+            .map(|segment| segment.address)
+            .min()
+    }
+
+    ///
+    /// Combine named images into one, failing if their data overlaps.
+    /// The entry point is the one of the first image having one.
+    ///
+    pub fn merge(images: Vec<(String, Self)>) -> Result<Self> {
+        for (i, (name_a, a)) in images.iter().enumerate() {
+            for (name_b, b) in &images[i + 1..] {
+                if a.big_endian != b.big_endian {
+                    bail!("images {} and {} differ in endianness", name_a, name_b);
+                }
+                for sa in a.segments.iter().filter(|s| !s.zero_fill) {
+                    for sb in b.segments.iter().filter(|s| !s.zero_fill) {
+                        let end_a = u64::from(sa.address) + sa.data.len() as u64;
+                        let end_b = u64::from(sb.address) + sb.data.len() as u64;
+                        if u64::from(sa.address) < end_b && u64::from(sb.address) < end_a {
+                            bail!(
+                                "images {} and {} overlap at 0x{:08x}",
+                                name_a,
+                                name_b,
+                                cmp::max(sa.address, sb.address)
+                            );
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut merged = Self {
+            segments: Vec::new(),
+            symbols: Vec::new(),
+            big_endian: images.first().is_some_and(|(_, image)| image.big_endian),
+            entry: images.iter().find_map(|(_, image)| image.entry),
+            lines: LineTable::default(),
+        };
+        for (_, image) in images {
+            merged.segments.extend(image.segments);
+            merged.symbols.extend(image.symbols);
+            merged.lines.extend(image.lines);
+        }
+        Ok(merged)
+    }
+
+    ///
+    /// Map every half-word address of each symbol to the symbol name
+    ///
+    pub fn symbol_table(&self) -> HashMap<u32, String> {
+        let mut symboltable = HashMap::new();
+        for sym in &self.symbols {
+            let mut count = 0;
+            let mut pos = sym.address;
+            while count <= sym.size {
+                // Align addresses to 2 byte alignment
+                symboltable.insert(pos & 0xffff_fffe, sym.name.clone());
+                pos += 2;
+                count += 2;
+            }
+        }
+        symboltable
+    }
+
+    ///
+    /// Find the address of a symbol by name
+    ///
+    pub fn find_symbol(&self, name: &str) -> Option<u32> {
+        self.symbols
+            .iter()
+            .find(|sym| sym.name == name)
+            .map(|sym| sym.address)
+    }
+}
+
+// Decode a record of hexadecimal digit pairs following the start character
+fn parse_record(line: &str, start: char, n: usize) -> Result<Vec<u8>> {
+    let digits = match line.strip_prefix(start) {
+        Some(digits) if digits.is_ascii() && digits.len() % 2 == 0 => digits,
+        _ => bail!("line {}: invalid record '{}'", n + 1, line),
+    };
+    (0..digits.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&digits[i..i + 2], 16)
+                .map_err(|_| format!("line {}: invalid hex digits in '{}'", n + 1, line).into())
+        })
+        .collect()
+}
+
+// Big-endian address of 2 to 4 bytes
+fn be_address(bytes: &[u8]) -> u32 {
+    bytes
+        .iter()
+        .fold(0_u32, |address, b| (address << 8) | u32::from(*b))
+}
+
+// Append data to the last segment when contiguous, otherwise start a new segment
+fn add_data(segments: &mut Vec<Segment>, address: u32, data: &[u8]) {
+    if let Some(last) = segments.last_mut() {
+        if u64::from(last.address) + last.data.len() as u64 == u64::from(address) {
+            last.data.extend_from_slice(data);
+            return;
+        }
+    }
+    segments.push(Segment {
+        address,
+        data: data.to_vec(),
+        zero_fill: false,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Minimal little-endian ARM executable with a program header for each
+    // (load address, run address, file data, memory size) of `segments`
+    fn elf(segments: &[(u32, u32, &[u8], u32)]) -> Vec<u8> {
+        let phoff = 52;
+        let mut offset = phoff + 32 * segments.len() as u32;
+        let mut buffer = vec![0x7f, b'E', b'L', b'F', 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        for (value, size) in &[
+            (2, 2),           // e_type: executable
+            (40, 2),          // e_machine: ARM
+            (1, 4),           // e_version
+            (0x0800_0009, 4), // e_entry
+            (phoff, 4),       // e_phoff
+            (0, 4),           // e_shoff
+            (0x0500_0000, 4), // e_flags: EABI version 5
+            (52, 2),          // e_ehsize
+            (32, 2),          // e_phentsize
+            (segments.len() as u32, 2),
+            (40, 2), // e_shentsize
+            (0, 2),  // e_shnum
+            (0, 2),  // e_shstrndx
+        ] {
+            buffer.extend_from_slice(&u32::to_le_bytes(*value)[..*size]);
+        }
+        for (paddr, vaddr, data, memsz) in segments {
+            let header = [1, offset, *vaddr, *paddr, data.len() as u32, *memsz, 6, 4];
+            for value in &header {
+                buffer.extend_from_slice(&value.to_le_bytes());
+            }
+            offset += data.len() as u32;
+        }
+        for (_, _, data, _) in segments {
+            buffer.extend_from_slice(data);
+        }
+        buffer
+    }
+
+    #[test]
+    fn test_elf() {
+        let buffer = elf(&[
+            (0x0800_0000, 0x0800_0000, &[1, 2, 3, 4], 4),
+            // initialised data stored in flash, run from RAM, with a zero-initialised tail
+            (0x0800_0004, 0x2000_0000, &[5, 6], 8),
+            // zero-initialised only
+            (0x2000_0100, 0x2000_0100, &[], 0x10),
+        ]);
+        let image = Image::from_elf(&buffer).map_err(|e| e.to_string()).unwrap();
+
+        let segments: Vec<_> = image
+            .segments
+            .iter()
+            .map(|segment| (segment.address, segment.data.clone(), segment.zero_fill))
+            .collect();
+        assert_eq!(
+            segments,
+            vec![
+                (0x0800_0000, vec![1, 2, 3, 4], false),
+                (0x0800_0004, vec![5, 6], false),
+                (0x2000_0002, vec![0; 6], true),
+                (0x2000_0100, vec![0; 0x10], true),
+            ]
+        );
+        assert_eq!(image.entry, Some(0x0800_0009));
+        assert!(!image.big_endian);
+
+        let mut truncated = elf(&[(0x0800_0000, 0x0800_0000, &[1, 2, 3, 4], 4)]);
+        truncated.truncate(truncated.len() - 1);
+        assert!(Image::from_elf(&truncated).is_err());
+    }
+
+    #[test]
+    fn test_ihex() {
+        let image = Image::from_ihex(
+            ":020000040800F2\n\
+             :0400000000100020CC\n\
+             :0400040009000000EF\n\
+             :021000000010DE\n\
+             :0400000508000009E6\n\
+             :00000001FF\n",
+        );
+        let image = image.map_err(|e| e.to_string()).unwrap();
+        assert_eq!(image.segments.len(), 2);
+        assert_eq!(image.segments[0].address, 0x0800_0000);
+        assert_eq!(
+            image.segments[0].data,
+            vec![0x00, 0x10, 0x00, 0x20, 0x09, 0x00, 0x00, 0x00]
+        );
+        assert_eq!(image.segments[1].address, 0x0800_1000);
+        assert_eq!(image.entry, Some(0x0800_0009));
+
+        assert!(Image::from_ihex(":0400000000100020BD\n:00000001FF\n").is_err());
+        assert!(Image::from_ihex(":0400000000100020CC\n").is_err());
+        assert!(Image::from_ihex(":0400000000100é0CC\n:00000001FF\n").is_err());
+    }
+
+    #[test]
+    fn test_srec() {
+        let image = Image::from_srec(
+            "S00600004844521B\n\
+             S3090800000000100020BE\n\
+             S3090800000409000000E1\n\
+             S70508000000F2\n",
+        );
+        let image = image.map_err(|e| e.to_string()).unwrap();
+        assert_eq!(image.segments.len(), 1);
+        assert_eq!(image.segments[0].address, 0x0800_0000);
+        assert_eq!(
+            image.segments[0].data,
+            vec![0x00, 0x10, 0x00, 0x20, 0x09, 0x00, 0x00, 0x00]
+        );
+        assert_eq!(image.entry, Some(0x0800_0000));
+
+        assert!(Image::from_srec("S3090800000000100020BF\n").is_err());
+        assert!(Image::from_srec("S4090800000000100020BE\n").is_err());
+        assert!(Image::from_srec("S30908000000001é020BE\n").is_err());
+    }
+
+    #[test]
+    fn test_merge() {
+        let boot = Image::from_binary(&[0; 0x100], 0x0800_0000);
+        let mut app = Image::from_binary(&[0; 0x100], 0);
+        app.relocate(0x0800_0100);
+        app.segments.push(Segment {
+            address: 0x0800_0000,
+            data: vec![0; 0x10],
+            zero_fill: true,
+        });
+        assert_eq!(app.vector_table(), Some(0x0800_0100));
+
+        let image = Image::merge(vec![("boot".to_string(), boot), ("app".to_string(), app)]);
+        assert_eq!(image.map(|image| image.segments.len()).ok(), Some(3));
+
+        let boot = Image::from_binary(&[0; 0x100], 0x0800_0000);
+        let app = Image::from_binary(&[0; 0x100], 0x0800_00fc);
+        let result = Image::merge(vec![("boot".to_string(), boot), ("app".to_string(), app)]);
+        assert_eq!(
+            result.map_err(|e| e.to_string()).err(),
+            Some("images boot and app overlap at 0x080000fc".to_string())
+        );
+    }
+}
@@ -5,6 +5,7 @@ extern crate error_chain;
 
 #[macro_use]
 extern crate clap;
+extern crate gimli;
 extern crate goblin;
 extern crate pad;
 extern crate tabwriter;
@@ -15,167 +16,418 @@ extern crate log;
 extern crate stderrlog;
 
 use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
-use goblin::elf::program_header::pt_to_str;
-use goblin::Object;
 use std::fs::File;
 use std::io;
 use std::io::prelude::*;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
+mod coverage;
+mod debuginfo;
+mod image;
+mod profile;
 mod semihost;
 mod trace;
 
+use crate::coverage::{write_hits, write_lcov};
+use crate::debuginfo::SourceFiles;
+use crate::image::{Image, Segment};
+use crate::profile::{write_callgrind, write_flat_profile};
 use crate::semihost::get_semihost_func;
-use crate::trace::format_trace_entry;
+use crate::trace::{
+    format_trace_entry, parse_trace_fields, StructuredTrace, TraceField, TraceFilter, TraceFormat,
+};
 
-use std::cmp;
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::ops::RangeInclusive;
+use std::rc::Rc;
 use tabwriter::TabWriter;
-use zmu_cortex_m::memory::map::MemoryMapConfig;
-use zmu_cortex_m::Processor;
+use zmu_cortex_m::bus::trace::{BusAccess, BusTrace};
+use zmu_cortex_m::core::coverage::{Coverage, CoverageRecording};
+use zmu_cortex_m::core::memcheck::{Memcheck, MemcheckError, Memchecking};
+use zmu_cortex_m::core::profile::{Profiler, Profiling};
+use zmu_cortex_m::core::stack::{StackMonitor, StackMonitoring};
+use zmu_cortex_m::memory::config::{
+    parse_address, parse_address_range, parse_memory_map, parse_size, validate_memory_map,
+    MemoryKind, MemoryRegionConfig,
+};
+use zmu_cortex_m::memory::map::{MapMemory, MemoryMapConfig};
+use zmu_cortex_m::{Processor, ProcessorMode};
 
-use zmu_cortex_m::system::simulation::simulate_trace;
-use zmu_cortex_m::system::simulation::{simulate, SimulationError};
+use zmu_cortex_m::system::simulation::{
+    simulate_processor, simulate_processor_trace, RunLimits, SimulationError, StopReason,
+};
 
 mod errors {
     // Create the Error, ErrorKind, ResultExt, and Result types
-    error_chain! {}
+    error_chain! {
+        errors {
+            FaultTrap {
+                description("fault trap")
+                display("trap")
+            }
+            Lockup(pc: u32, location: String) {
+                description("processor lockup")
+                display("processor locked up by a fault at pc 0x{:08x}{}", pc, location)
+            }
+            RunLimit(reason: String) {
+                description("run limit reached")
+                display("{}", reason)
+            }
+            ApplicationExit(code: u32) {
+                description("application exited with a failure")
+                display("application exited with status {}", code)
+            }
+        }
+    }
 }
 
 use crate::errors::*;
-use error_chain::State;
 
 impl From<SimulationError> for errors::Error {
-    fn from(_error: SimulationError) -> Self {
-        errors::Error(ErrorKind::Msg("trap".to_string()), State::default())
+    fn from(error: SimulationError) -> Self {
+        match error {
+            SimulationError::FaultTrap => ErrorKind::FaultTrap.into(),
+        }
     }
 }
 
-fn run_bin(
-    buffer: &[u8],
-    trace: bool,
-    option_trace_start: Option<u64>,
-    itm_file: Option<Box<dyn io::Write + 'static>>,
-) -> Result<()> {
-    let res = Object::parse(buffer).unwrap();
+// Exit codes of the process
+const EXIT_ERROR: i32 = 1;
+const EXIT_FAULT: i32 = 2;
+const EXIT_LOCKUP: i32 = 3;
+const EXIT_TIMEOUT: i32 = 124;
 
-    let elf = match res {
-        Object::Elf(elf) => elf,
-        _ => {
-            bail!("Unsupported file format.");
-        }
-    };
+// Exit code for a non-zero status of the application. Only the low byte of the
+// code reaches the parent process, so a status that would read as success is
+// reported as a failure.
+fn application_exit_code(status: u32) -> i32 {
+    if status & 0xff == 0 {
+        EXIT_ERROR
+    } else {
+        status as i32
+    }
+}
 
-    debug!("Detected ELF file.");
+///
+/// Options for running an executable
+///
+struct RunOptions {
+    trace: bool,
+    trace_source: bool,
+    trace_format: TraceFormat,
+    trace_fields: Vec<TraceField>,
+    trace_file: Option<String>,
+    trace_filter: TraceFilter,
+    itm_file: Option<Box<dyn io::Write + 'static>>,
+    memory: Vec<MemoryRegionConfig>,
+    flash_size: Option<usize>,
+    flash_image: Option<String>,
+    memory_trace: Option<Vec<RangeInclusive<u32>>>,
+    memcheck: bool,
+    stack_usage: bool,
+    stack_limit: Option<u32>,
+    vector_table: Option<u32>,
+    initial_sp: Option<u32>,
+    entry_point: Option<u32>,
+    profile: bool,
+    callgrind: Option<String>,
+    command: String,
+    coverage: Option<String>,
+    lcov: Option<String>,
+    limits: RunLimits,
+}
 
-    // auto detection of required flash size:
-    // loop 1: determine lower bound and upper bound
+fn run_bin(mut image: Image, options: RunOptions) -> Result<()> {
+    if image.segments.is_empty() {
+        bail!("image has no loadable data");
+    }
 
-    let mut min_address = 0xffff_ffff;
-    let mut max_address = 0;
-    debug!("Determining ELF code sections");
-    for ph in &elf.program_headers {
-        if ph.p_type == goblin::elf::program_header::PT_LOAD && ph.p_filesz > 0 {
-            let dst_addr = ph.p_paddr as usize;
-            let dst_end_addr = (ph.p_paddr + ph.p_filesz) as usize;
+    let mut processor = Processor::new();
+    processor.memory_regions(&options.memory);
 
-            debug!(
-                "PT_LOAD section at 0x{:08x} - 0x{:08x} (size = {} bytes)",
-                dst_addr, dst_end_addr, ph.p_filesz
-            );
-            min_address = cmp::min(dst_addr, min_address);
-            max_address = cmp::max(dst_end_addr, max_address);
-        } else {
-            debug!(
-                "ignoring section : {} (size = {} bytes)",
-                pt_to_str(ph.p_type),
-                ph.p_filesz
-            );
-        }
-    }
+    let (region_segments, flash_segments) = partition_segments(&processor, &image.segments);
 
-    let flash_start_address = min_address as u32;
-    let flash_size = (max_address - min_address) as usize;
+    let (flash_start_address, flash_size) = flash_window(&flash_segments, options.flash_size)?;
     info!(
         "Auto configuring flash: address space is 0x{:x}..0x{:x}, size= {} bytes",
-        flash_start_address, max_address, flash_size
+        flash_start_address,
+        flash_start_address as usize + flash_size,
+        flash_size
     );
-    let mut flash_mem = vec![0; flash_size];
-
-    // loop 2: load data by offset
-    for ph in &elf.program_headers {
-        if ph.p_type == goblin::elf::program_header::PT_LOAD && ph.p_filesz > 0 {
-            let dst_addr = (ph.p_paddr - u64::from(flash_start_address)) as usize;
-            let dst_end_addr =
-                ((ph.p_paddr + ph.p_filesz) - u64::from(flash_start_address)) as usize;
+    let flash_end = flash_start_address as usize + flash_size;
+    if let Some(region) = processor.regions.iter().find(|region| {
+        flash_size > 0
+            && (region.base as usize) < flash_end
+            && (flash_start_address as usize) < region.base as usize + region.size
+    }) {
+        bail!(
+            "image data at 0x{:08x}..0x{:08x} overlaps memory region '{}'",
+            flash_start_address,
+            flash_end,
+            region.name
+        );
+    }
 
-            let src_addr = ph.p_offset as usize;
-            let src_end_addr = (ph.p_offset + ph.p_filesz) as usize;
+    // flash between and beyond the images is erased, or restored from the
+    // persisted flash image. The loaded image always replaces the persisted
+    // contents it overlaps, so that rebuilt firmware takes effect.
+    let mut flash_mem = vec![0xff; flash_size];
 
-            flash_mem[dst_addr..dst_end_addr].copy_from_slice(&buffer[src_addr..src_end_addr]);
+    if let Some(filename) = &options.flash_image {
+        if let Ok(mut f) = File::open(filename) {
+            let mut image = Vec::new();
+            f.read_to_end(&mut image)
+                .chain_err(|| "failed to read flash image")?;
+            if image.len() != flash_size {
+                bail!(
+                    "flash image {} is {} bytes, expected {} bytes",
+                    filename,
+                    image.len(),
+                    flash_size
+                );
+            }
+            info!("Loaded persistent flash contents from {}", filename);
+            flash_mem = image;
         }
     }
 
-    let trace_start = option_trace_start.unwrap_or(0);
+    for segment in &flash_segments {
+        let offset = (segment.address - flash_start_address) as usize;
+        flash_mem[offset..offset + segment.data.len()].copy_from_slice(&segment.data);
+    }
+
+    let symbols = Rc::new(image.symbol_table());
+    let lines = Rc::new(std::mem::take(&mut image.lines));
     let semihost_func = Box::new(get_semihost_func(Instant::now()));
 
-    let statistics = if trace {
-        debug!("Configuring tracing.");
+    processor.itm(options.itm_file);
+    processor.big_endian(image.big_endian);
+    processor.semihost(Some(semihost_func));
+    processor.flash_memory_at(flash_start_address, flash_size, &flash_mem);
+    if let Some(address) = options.vector_table {
+        processor.vector_table(address);
+    }
+    processor.initial_sp(options.initial_sp);
+    processor.entry_point(options.entry_point);
+    for segment in region_segments {
+        let address = processor.map_address(segment.address);
+        debug!("Loading {} bytes to 0x{:08x}", segment.data.len(), address);
+        processor.load_memory(address, &segment.data)?;
+    }
+    // boot alias of the flash at address 0, unless the memory map decides what is there
+    let boots_from_configured_memory = options.memory.iter().any(|region| match region.kind {
+        MemoryKind::Remap { source, .. } => source == 0,
+        _ => region.contains(0),
+    });
+    if flash_start_address != 0 && !boots_from_configured_memory {
+        processor.memory_map(Some(MemoryMapConfig::new(
+            0,
+            flash_start_address,
+            flash_size,
+        )));
+    }
+    // accesses of the current instruction, for the memory field of structured traces
+    let memory_accesses = if options.trace && options.trace_fields.contains(&TraceField::Memory) {
+        Some(Rc::new(RefCell::new(Vec::new())))
+    } else {
+        None
+    };
+    if options.memory_trace.is_some() || memory_accesses.is_some() {
+        debug!("Configuring memory access tracing.");
+        let print = options.memory_trace.is_some();
+        let ranges = options.memory_trace.unwrap_or_default();
+        let accesses = memory_accesses.clone();
+        let trace_func = move |access: &BusAccess| {
+            if print {
+                println!("mem {}", access);
+            }
+            if let Some(accesses) = &accesses {
+                accesses.borrow_mut().push(access.clone());
+            }
+        };
+        processor.bus_trace(Some(BusTrace::new(Box::new(trace_func), &ranges)));
+    }
 
-        let mut symboltable = HashMap::new();
-        let mut trace_stdout = TabWriter::new(io::stdout()).minwidth(16).padding(1);
-
-        for sym in elf.syms {
-            if sym.st_type() != goblin::elf::sym::STT_FILE {
-                if let Some(maybe_name) = elf.strtab.get(sym.st_name) {
-                    let name = maybe_name.unwrap_or("unknown");
-                    let mut count = 0;
-                    let mut pos = sym.st_value as u32;
-                    while count <= sym.st_size {
-                        // Align addresses to 2 byte alignment
-                        symboltable.insert(pos & 0xffff_fffe, name);
-                        pos += 2;
-                        count += 2;
-                    }
-                }
+    if options.memcheck {
+        debug!("Enabling memcheck.");
+        let symbols = Rc::clone(&symbols);
+        let lines = Rc::clone(&lines);
+        let report_func = move |error: &MemcheckError| {
+            eprintln!("==memcheck== {}", error.kind);
+            for (i, address) in error.backtrace.iter().enumerate() {
+                eprintln!(
+                    "==memcheck==    {} 0x{:08x}: {}{}",
+                    if i == 0 { "at" } else { "by" },
+                    address,
+                    symbols.get(&(address & !1)).map_or("???", String::as_str),
+                    lines
+                        .lookup(address & !1)
+                        .map_or_else(String::new, |location| format!(" ({})", location))
+                );
             }
-        }
+        };
+        processor.memcheck(Some(Memcheck::new(Box::new(report_func))));
+    }
+
+    if options.stack_usage || options.stack_limit.is_some() {
+        debug!("Enabling stack monitoring.");
+        processor.stack_monitor(Some(StackMonitor::new(options.stack_limit)));
+    }
+
+    processor.run_limits(options.limits);
+
+    if options.profile || options.callgrind.is_some() {
+        debug!("Enabling profiling.");
+        processor.profiler(Some(Profiler::new()));
+    }
+
+    if options.coverage.is_some() || options.lcov.is_some() {
+        debug!("Enabling coverage recording.");
+        processor.coverage(Some(Coverage::new()));
+    }
+
+    let statistics = if options.trace {
+        debug!("Configuring tracing.");
+
+        let trace_out: Box<dyn io::Write> = match &options.trace_file {
+            Some(filename) => Box::new(io::BufWriter::new(
+                File::create(filename).chain_err(|| "unable to create trace file")?,
+            )),
+            None => Box::new(io::stdout()),
+        };
+        let (mut structured_trace, mut text_trace) = match options.trace_format {
+            TraceFormat::Text => (
+                None,
+                Some(TabWriter::new(trace_out).minwidth(16).padding(1)),
+            ),
+            format => (
+                Some(
+                    StructuredTrace::new(format, options.trace_fields, trace_out)
+                        .chain_err(|| "failed to write trace")?,
+                ),
+                None,
+            ),
+        };
+        let mut trace_result = Ok(());
+
+        let trace_source = options.trace_source;
+        let filter = options.trace_filter;
+        let mut sources = SourceFiles::default();
+        let mut last_location = None;
 
         let tracefunc = |processor: &Processor| {
-            if processor.instruction_count >= trace_start {
-                let trace_entry = format_trace_entry(processor, &symboltable);
-                writeln!(&mut trace_stdout, "{}", trace_entry).unwrap();
-                let _ = trace_stdout.flush();
+            let accesses = memory_accesses
+                .as_ref()
+                .map(|accesses| accesses.replace(Vec::new()))
+                .unwrap_or_default();
+            if !filter.matches(processor) || trace_result.is_err() {
+                return;
+            }
+            if let Some(trace) = &mut structured_trace {
+                trace_result = trace.write(processor, &accesses, &symbols, &lines);
+            } else if let Some(trace_out) = &mut text_trace {
+                if trace_source {
+                    let location = lines.lookup(processor.last_pc);
+                    if let Some(location) = location.filter(|_| location != last_location) {
+                        let text = sources.line(&location).unwrap_or("");
+                        writeln!(trace_out, "{}  {}", location, text).unwrap();
+                    }
+                    last_location = location;
+                }
+                let trace_entry = format_trace_entry(processor, &symbols, &lines);
+                writeln!(trace_out, "{}", trace_entry).unwrap();
             }
         };
         debug!("Starting simulation with trace.");
 
-        simulate_trace(
-            &flash_mem,
-            tracefunc,
-            semihost_func,
-            itm_file,
-            if flash_start_address != 0 {
-                Some(MemoryMapConfig::new(flash_start_address, 0, flash_size))
-            } else {
-                None
-            },
-            flash_size,
-        )?
+        let statistics = simulate_processor_trace(&mut processor, tracefunc)?;
+        trace_result.chain_err(|| "failed to write trace")?;
+        if let Some(trace) = &mut structured_trace {
+            trace.flush().chain_err(|| "failed to write trace")?;
+        }
+        if let Some(trace_out) = &mut text_trace {
+            trace_out.flush().chain_err(|| "failed to write trace")?;
+        }
+        statistics
     } else {
         debug!("Starting simulation.");
-        simulate(
-            &flash_mem,
-            semihost_func,
-            itm_file,
-            if flash_start_address != 0 {
-                Some(MemoryMapConfig::new(flash_start_address, 0, flash_size))
-            } else {
-                None
-            },
-            flash_size,
-        )?
+        simulate_processor(&mut processor)?
+    };
+
+    if let Some((errors, contexts)) = processor.memcheck_error_counts() {
+        eprintln!(
+            "==memcheck== ERROR SUMMARY: {} errors from {} contexts",
+            errors, contexts
+        );
+    }
+
+    if let Some(monitor) = processor.stack_usage() {
+        if options.stack_usage {
+            print_stack_usage(monitor);
+        }
+    }
+
+    if let Some(profiler) = processor.profile() {
+        if options.profile {
+            write_flat_profile(&mut io::stderr(), profiler, &symbols)
+                .chain_err(|| "failed to write profile")?;
+        }
+        if let Some(filename) = &options.callgrind {
+            let mut f = File::create(filename).chain_err(|| "unable to create profile file")?;
+            write_callgrind(&mut f, profiler, &symbols, &lines, &options.command)
+                .chain_err(|| "failed to write profile")?;
+            info!("Saved callgrind profile to {}", filename);
+        }
+    }
+
+    let conditionals = if options.lcov.is_some() {
+        // Thumb function symbols have the lowest address bit set
+        let mut conditionals = Vec::new();
+        for symbol in &image.symbols {
+            if symbol.address & 1 == 1 {
+                let (address, len) = (symbol.address & !1, symbol.size as usize);
+                conditionals.extend(processor.conditional_instructions(address, len));
+            }
+        }
+        conditionals
+    } else {
+        Vec::new()
     };
+    if let Some(coverage) = processor.code_coverage() {
+        if let Some(filename) = &options.coverage {
+            let mut f = File::create(filename).chain_err(|| "unable to create coverage file")?;
+            write_hits(&mut f, coverage).chain_err(|| "failed to write coverage")?;
+            info!("Saved coverage to {}", filename);
+        }
+        if let Some(filename) = &options.lcov {
+            let loaded = |address: u32| {
+                image.segments.iter().any(|segment| {
+                    !segment.zero_fill
+                        && address >= segment.address
+                        && u64::from(address)
+                            < u64::from(segment.address) + segment.data.len() as u64
+                })
+            };
+            let mut f = File::create(filename).chain_err(|| "unable to create coverage file")?;
+            write_lcov(
+                &mut f,
+                coverage,
+                &lines,
+                &image.symbols,
+                &conditionals,
+                loaded,
+            )
+            .chain_err(|| "failed to write coverage")?;
+            info!("Saved lcov tracefile to {}", filename);
+        }
+    }
+
+    if let Some(filename) = &options.flash_image {
+        let mut f = File::create(filename).chain_err(|| "unable to create flash image")?;
+        f.write_all(processor.code.data())
+            .chain_err(|| "failed to write flash image")?;
+        info!("Saved flash contents to {}", filename);
+    }
 
     let duration_in_secs = statistics.duration.as_secs() as f64
         + (f64::from(statistics.duration.subsec_nanos()) / 1_000_000_000f64);
@@ -193,7 +445,133 @@ fn run_bin(
         cycles_per_sec,
         cycles_per_sec / 1_000_000.0,
     );
-    Ok(())
+
+    if let Some(overflow) = processor.stack_usage().and_then(StackMonitor::overflow) {
+        bail!(
+            "stack overflow: MSP 0x{:08x} below limit 0x{:08x} at pc 0x{:08x}{}",
+            overflow.sp,
+            overflow.limit,
+            overflow.pc,
+            lines
+                .lookup(overflow.pc)
+                .map_or_else(String::new, |location| format!(" ({})", location))
+        );
+    }
+
+    match statistics.stop_reason {
+        StopReason::Exit => match statistics.exit_code {
+            Some(code) if code != 0 => bail!(ErrorKind::ApplicationExit(code)),
+            _ => Ok(()),
+        },
+        StopReason::InstructionLimit => bail!(ErrorKind::RunLimit(format!(
+            "instruction limit reached after {} instructions",
+            statistics.instruction_count
+        ))),
+        StopReason::CycleLimit => bail!(ErrorKind::RunLimit(format!(
+            "cycle limit reached after {} cycles",
+            statistics.cycle_count
+        ))),
+        StopReason::Timeout => bail!(ErrorKind::RunLimit(format!(
+            "timeout after {:?}",
+            statistics.duration
+        ))),
+        StopReason::Lockup { pc } => bail!(ErrorKind::Lockup(
+            pc,
+            lines
+                .lookup(pc)
+                .map_or_else(String::new, |location| format!(" ({})", location))
+        )),
+    }
+}
+
+///
+/// Split the image data to the segments loaded to the RAM or ROM region owning
+/// their address, and to the rest of the image that is loaded to flash
+///
+fn partition_segments<'a>(
+    processor: &Processor,
+    segments: &'a [Segment],
+) -> (Vec<&'a Segment>, Vec<&'a Segment>) {
+    segments.iter().partition(|segment| {
+        processor
+            .memory_region(processor.map_address(segment.address))
+            .is_some()
+    })
+}
+
+// Largest flash assumed when the flash size is detected from the image
+const MAX_AUTO_FLASH_SIZE: usize = 16 * 1024 * 1024;
+
+///
+/// Start address and size of the flash holding the image data that is not
+/// in a memory region. The flash starts from the lowest address of the data
+/// and is `flash_size` bytes, or by default just large enough for the data,
+/// up to `MAX_AUTO_FLASH_SIZE` bytes. Data outside of the flash is an error.
+///
+fn flash_window(segments: &[&Segment], flash_size: Option<usize>) -> Result<(u32, usize)> {
+    let start = segments
+        .iter()
+        .map(|segment| segment.address as usize)
+        .min()
+        .unwrap_or(0);
+    let limit = flash_size.unwrap_or(MAX_AUTO_FLASH_SIZE);
+    let mut end = start;
+    for segment in segments {
+        let segment_end = segment.address as usize + segment.data.len();
+        if segment_end > 0x1_0000_0000 {
+            bail!(
+                "image data at 0x{:08x} extends past end of address space",
+                segment.address
+            );
+        }
+        if segment_end - start > limit {
+            bail!(
+                "image data at 0x{:08x}..0x{:08x} is neither in a memory region nor in the flash at 0x{:08x}..0x{:08x}",
+                segment.address,
+                segment_end,
+                start,
+                start + limit
+            );
+        }
+        end = end.max(segment_end);
+    }
+    Ok((start as u32, flash_size.unwrap_or(end - start)))
+}
+
+///
+/// Split an image argument of form FILE[@OFFSET] to the file name and the offset
+///
+fn parse_image_spec(spec: &str) -> (&str, Option<u32>) {
+    if let Some(pos) = spec.rfind('@') {
+        if let Ok(offset) = parse_address(&spec[pos + 1..]) {
+            return (&spec[..pos], Some(offset));
+        }
+    }
+    (spec, None)
+}
+
+fn print_stack_usage(monitor: &StackMonitor) {
+    eprintln!("Stack usage:");
+    let stacks = monitor
+        .main_stack()
+        .into_iter()
+        .map(|usage| ("MSP".to_string(), *usage))
+        .chain(
+            monitor
+                .process_stacks()
+                .iter()
+                .enumerate()
+                .map(|(i, usage)| (format!("PSP {}", i), *usage)),
+        );
+    for (name, usage) in stacks {
+        eprintln!(
+            "  {:<6} top 0x{:08x}  low-water 0x{:08x}  max depth {} bytes",
+            name,
+            usage.top,
+            usage.low,
+            usage.max_depth()
+        );
+    }
 }
 
 fn open_itm_file(filename: &str) -> Option<Box<dyn io::Write + 'static>> {
@@ -205,39 +583,230 @@ fn open_itm_file(filename: &str) -> Option<Box<dyn io::Write + 'static>> {
     }
 }
 
+///
+/// Resolve a command line value given as a symbol name or an address
+///
+fn resolve_address(image: &Image, value: &str, what: &str) -> Result<u32> {
+    match image.find_symbol(value) {
+        Some(address) => Ok(address),
+        None => parse_address(value)
+            .map_err(|_| format!("{} '{}' is not an address or a symbol", what, value).into()),
+    }
+}
+
 fn run(args: &ArgMatches) -> Result<()> {
     match args.subcommand() {
         ("run", Some(run_matches)) => {
-            let filename = run_matches
-                .value_of("EXECUTABLE")
+            let filenames = run_matches
+                .values_of("EXECUTABLE")
                 .chain_err(|| "filename missing")?;
+            let command = filenames.clone().collect::<Vec<_>>().join(" ");
 
-            let trace_start = match run_matches.value_of("trace-start") {
-                Some(instr) => Some(
+            let trace_format = match run_matches.value_of("trace-format") {
+                Some(format) => format.parse::<TraceFormat>()?,
+                None => TraceFormat::Text,
+            };
+            let trace_fields =
+                parse_trace_fields(run_matches.value_of("trace-fields"), trace_format)?;
+            let mut trace_filter = TraceFilter::default();
+            if let Some(instr) = run_matches.value_of("trace-start") {
+                trace_filter.start = instr
+                    .parse::<u64>()
+                    .chain_err(|| "invalid trace start point")?;
+            }
+            if let Some(instr) = run_matches.value_of("trace-end") {
+                trace_filter.end = Some(
                     instr
                         .parse::<u64>()
-                        .chain_err(|| "invalid trace start point")?,
-                ),
+                        .chain_err(|| "invalid trace end point")?,
+                );
+            }
+            trace_filter.mode = match run_matches.value_of("trace-mode") {
+                Some("thread") => Some(ProcessorMode::ThreadMode),
+                Some("handler") => Some(ProcessorMode::HandlerMode),
+                Some(mode) => bail!("invalid trace mode '{}'", mode),
                 None => None,
             };
+            if let Some(values) = run_matches.values_of("trace-range") {
+                for value in values {
+                    trace_filter.ranges.push(parse_address_range(value)?);
+                }
+            }
 
             let itm_output = match run_matches.value_of("itm") {
                 Some(filename) => open_itm_file(filename),
                 None => None,
             };
 
-            let buffer = {
-                let mut v = Vec::new();
-                let mut f = File::open(&filename).chain_err(|| "unable to open file")?;
-                f.read_to_end(&mut v).chain_err(|| "failed to read file")?;
-                v
+            let mut memory = Vec::new();
+            if let Some(filename) = run_matches.value_of("memory-map") {
+                let mut text = String::new();
+                File::open(filename)
+                    .chain_err(|| "unable to open memory map file")?
+                    .read_to_string(&mut text)
+                    .chain_err(|| "failed to read memory map file")?;
+                memory.extend(parse_memory_map(&text).map_err(|e| format!("{}: {}", filename, e))?);
+            }
+            if let Some(regions) = run_matches.values_of("memory") {
+                for region in regions {
+                    memory.push(region.parse::<MemoryRegionConfig>()?);
+                }
+            }
+            validate_memory_map(&memory)?;
+
+            let flash_size = match run_matches.value_of("flash-size") {
+                Some(size) => {
+                    Some(parse_size(size).map_err(|e| format!("invalid flash size: {}", e))?)
+                }
+                None => None,
+            };
+
+            let memory_trace = if run_matches.is_present("trace-memory")
+                || run_matches.is_present("trace-memory-range")
+            {
+                let mut ranges = Vec::new();
+                if let Some(values) = run_matches.values_of("trace-memory-range") {
+                    for value in values {
+                        ranges.push(parse_address_range(value)?);
+                    }
+                }
+                Some(ranges)
+            } else {
+                None
+            };
+
+            let base_address = match run_matches.value_of("base-address") {
+                Some(address) => Some(
+                    parse_address(address).map_err(|e| format!("invalid base address: {}", e))?,
+                ),
+                None => None,
+            };
+
+            let mut images = Vec::new();
+            for spec in filenames {
+                let (filename, offset) = parse_image_spec(spec);
+                let buffer = {
+                    let mut v = Vec::new();
+                    let mut f = File::open(filename)
+                        .chain_err(|| format!("unable to open file {}", filename))?;
+                    f.read_to_end(&mut v).chain_err(|| "failed to read file")?;
+                    v
+                };
+                let mut image = Image::load(&buffer, filename, base_address)
+                    .chain_err(|| format!("failed to load {}", filename))?;
+                if let Some(offset) = offset {
+                    image.relocate(offset);
+                }
+                images.push((filename.to_string(), image));
+            }
+
+            let vector_table = match run_matches.value_of("boot-image") {
+                Some(n) => {
+                    let index = n
+                        .parse::<usize>()
+                        .ok()
+                        .filter(|index| (1..=images.len()).contains(index))
+                        .chain_err(|| format!("invalid boot image {}", n))?;
+                    let (filename, image) = &images[index - 1];
+                    let address = image
+                        .vector_table()
+                        .chain_err(|| format!("{} has no vector table", filename))?;
+                    info!(
+                        "Booting from vector table of {} at 0x{:08x}",
+                        filename, address
+                    );
+                    Some(address)
+                }
+                None => None,
+            };
+            let image = Image::merge(images)?;
+
+            let vector_table = match run_matches.value_of("vtor") {
+                Some(value) => Some(resolve_address(&image, value, "vector table")?),
+                None => vector_table,
+            };
+            let initial_sp = match run_matches.value_of("initial-sp") {
+                Some(value) => Some(resolve_address(&image, value, "initial stack pointer")?),
+                None => None,
+            };
+            let entry_point = match run_matches.value_of("entry") {
+                Some(value) => Some(resolve_address(&image, value, "entry point")?),
+                None if run_matches.is_present("image-entry") => {
+                    Some(image.entry.chain_err(|| "image has no entry point")?)
+                }
+                None => None,
+            };
+            if let Some(names) = run_matches.values_of("trace-symbol") {
+                for name in names {
+                    let symbol = image
+                        .symbols
+                        .iter()
+                        .find(|symbol| symbol.name == name)
+                        .chain_err(|| format!("trace symbol '{}' not found", name))?;
+                    let start = symbol.address & !1;
+                    let end = start.saturating_add((symbol.size.max(1) - 1) as u32);
+                    trace_filter.ranges.push(start..=end);
+                }
+            }
+
+            let stack_limit = match run_matches.value_of("stack-limit") {
+                Some(value) => Some(resolve_address(&image, value, "stack limit")?),
+                None => None,
+            };
+
+            let limits = RunLimits {
+                max_instructions: match run_matches.value_of("max-instructions") {
+                    Some(value) => Some(
+                        value
+                            .parse::<u64>()
+                            .chain_err(|| format!("invalid instruction limit '{}'", value))?,
+                    ),
+                    None => None,
+                },
+                max_cycles: match run_matches.value_of("max-cycles") {
+                    Some(value) => Some(
+                        value
+                            .parse::<u64>()
+                            .chain_err(|| format!("invalid cycle limit '{}'", value))?,
+                    ),
+                    None => None,
+                },
+                timeout: match run_matches.value_of("timeout") {
+                    Some(value) => Some(
+                        parse_duration(value)
+                            .chain_err(|| format!("invalid timeout '{}'", value))?,
+                    ),
+                    None => None,
+                },
             };
 
             run_bin(
-                &buffer,
-                run_matches.is_present("trace"),
-                trace_start,
-                itm_output,
+                image,
+                RunOptions {
+                    trace: run_matches.is_present("trace"),
+                    trace_source: run_matches.is_present("trace-source"),
+                    trace_format,
+                    trace_fields,
+                    trace_file: run_matches.value_of("trace-file").map(String::from),
+                    trace_filter,
+                    itm_file: itm_output,
+                    memory,
+                    flash_size,
+                    flash_image: run_matches.value_of("flash-image").map(String::from),
+                    memory_trace,
+                    memcheck: run_matches.is_present("memcheck"),
+                    stack_usage: run_matches.is_present("stack-usage"),
+                    stack_limit,
+                    vector_table,
+                    initial_sp,
+                    entry_point,
+                    profile: run_matches.is_present("profile"),
+                    callgrind: run_matches.value_of("callgrind").map(String::from),
+                    command,
+                    coverage: run_matches.value_of("coverage").map(String::from),
+                    lcov: run_matches.value_of("lcov").map(String::from),
+                    limits,
+                },
             )?;
         }
         ("", None) => bail!("No sub command found"),
@@ -247,6 +816,26 @@ fn run(args: &ArgMatches) -> Result<()> {
     Ok(())
 }
 
+///
+/// Parse a duration given in seconds, e.g. "10", "2.5", or with a unit of
+/// ms, s, m or h, e.g. "500ms"
+///
+fn parse_duration(s: &str) -> Result<Duration> {
+    let (value, unit) = match s.find(|c: char| c.is_ascii_alphabetic()) {
+        Some(pos) => s.split_at(pos),
+        None => (s, "s"),
+    };
+    let value = value.trim().parse::<f64>().chain_err(|| "invalid number")?;
+    let scale = match unit {
+        "ms" => 0.001,
+        "s" => 1.0,
+        "m" => 60.0,
+        "h" => 3600.0,
+        _ => bail!("unknown unit '{}'", unit),
+    };
+    Duration::try_from_secs_f64(value * scale).chain_err(|| "invalid duration")
+}
+
 fn main() {
     let args = App::new("zmu")
         .version(crate_version!())
@@ -261,29 +850,230 @@ fn main() {
         .subcommand(
             SubCommand::with_name("run")
                 .about("Load and run <EXECUTABLE>")
+                .after_help("EXIT STATUS:\n    0    the application exited successfully\n    1    error, or the application exited with a failure\n    2    fault during reset\n    3    processor lockup\n    124  instruction limit, cycle limit or timeout reached\n    other exit status of the application, modulo 256\n\nAn application exit status of 1, 2, 3 or 124 is indistinguishable from the statuses\nof the simulator, and a non-zero status that is a multiple of 256 exits with 1.")
                 .arg(
                     Arg::with_name("trace")
                         .short("t")
                         .long("trace")
                         .help("Print instruction trace to stdout"),
                 )
+                .arg(
+                    Arg::with_name("trace-source")
+                        .long("trace-source")
+                        .help("Print source lines, from the DWARF line information of the image, interleaved with the trace")
+                        .requires("trace"),
+                )
                 .arg(
                     Arg::with_name("trace-start")
                         .long("trace-start")
                         .help("Instruction on which to start tracing")
                         .takes_value(true),
                 )
+                .arg(
+                    Arg::with_name("trace-end")
+                        .long("trace-end")
+                        .help("Instruction after which to stop tracing")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("trace-range")
+                        .long("trace-range")
+                        .help("Trace only instructions within an address range, given as start-end or start+size")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("trace-symbol")
+                        .long("trace-symbol")
+                        .help("Trace only instructions within a function or other symbol")
+                        .value_name("SYMBOL")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("trace-mode")
+                        .long("trace-mode")
+                        .help("Trace only instructions run in thread mode or in exception handlers")
+                        .possible_values(&["thread", "handler"])
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("trace-format")
+                        .long("trace-format")
+                        .help("Format of the instruction trace: text, JSON lines or compact binary records")
+                        .possible_values(&["text", "json", "binary"])
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("trace-fields")
+                        .long("trace-fields")
+                        .help("Comma separated fields of JSON and binary traces: count, cycles, pc, opcode, registers, sp, lr, psr, mode, memory and, in JSON only, instruction, symbol, location and flags. Default: count,pc,opcode,instruction,symbol,flags,registers, or count,pc,opcode,registers in binary")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("trace-file")
+                        .long("trace-file")
+                        .help("Name of file to which the instruction trace is written instead of stdout")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("trace-memory")
+                        .long("trace-memory")
+                        .help("Print memory access trace to stdout"),
+                )
+                .arg(
+                    Arg::with_name("trace-memory-range")
+                        .long("trace-memory-range")
+                        .help("Limit memory access trace to an address range, given as start-end or start+size, e.g. 0x40000000+1K. Implies --trace-memory.")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("memcheck")
+                        .long("memcheck")
+                        .help("Report uses of uninitialised memory, accesses to unmapped memory and accesses below the stack pointer"),
+                )
+                .arg(
+                    Arg::with_name("stack-usage")
+                        .long("stack-usage")
+                        .help("Report maximum depth of the main stack and each process stack on exit"),
+                )
+                .arg(
+                    Arg::with_name("max-instructions")
+                        .long("max-instructions")
+                        .help("Stop with an error after executing COUNT instructions")
+                        .value_name("COUNT")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("max-cycles")
+                        .long("max-cycles")
+                        .help("Stop with an error after simulating COUNT clock cycles")
+                        .value_name("COUNT")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("timeout")
+                        .long("timeout")
+                        .help("Stop with an error after running for DURATION of wallclock time, given in seconds or with a unit of ms, s, m or h, e.g. 500ms")
+                        .value_name("DURATION")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("stack-limit")
+                        .long("stack-limit")
+                        .help("Stop when the main stack pointer drops below LIMIT, given as an address or a symbol such as __StackLimit or _stack_end")
+                        .value_name("LIMIT")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("profile")
+                        .long("profile")
+                        .help("Print a flat profile of the instructions and cycles spent in each function on exit"),
+                )
+                .arg(
+                    Arg::with_name("callgrind")
+                        .long("callgrind")
+                        .help("Name of file to which the function profile is written in callgrind format, for viewing in KCachegrind")
+                        .value_name("FILE")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("coverage")
+                        .long("coverage")
+                        .help("Name of file to which the execution count of each instruction address is written, with the taken and not taken counts of conditional instructions")
+                        .value_name("FILE")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("lcov")
+                        .long("lcov")
+                        .help("Name of file to which line, function and branch coverage is written as an lcov tracefile, using the DWARF line information of the image")
+                        .value_name("FILE")
+                        .takes_value(true),
+                )
                 .arg(
                     Arg::with_name("itm")
                         .long("itm")
                         .help("Name of file to which itm trace data is written to. ")
                         .takes_value(true),
                 )
+                .arg(
+                    Arg::with_name("memory")
+                        .long("memory")
+                        .help("Add a memory region, e.g. \"ram,base=0x20000000,size=112K,access=rwx,fill=0xcd\". Kinds are ram, rom, alias (with target=<address>) and remap (a remap register with source, length, targets=<address>:<address>... and boot).")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("memory-map")
+                        .long("memory-map")
+                        .help("Name of file describing memory regions, one region per line in --memory syntax")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("flash-size")
+                        .long("flash-size")
+                        .help("Size of flash, e.g. 128K. Flash beyond the loaded image is erased.")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("flash-image")
+                        .long("flash-image")
+                        .help("Name of file from which flash contents are loaded, if it exists, and to which they are saved on exit. The loaded image replaces the persisted contents it overlaps.")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("base-address")
+                        .long("base-address")
+                        .help("Load address of a raw binary image, 0 by default")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("boot-image")
+                        .long("boot-image")
+                        .help("Number of the image, counting from 1, whose vector table is used at reset. By default the vector table is at address 0.")
+                        .value_name("N")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("vtor")
+                        .long("vtor")
+                        .help("Address or symbol of the vector table used at reset")
+                        .value_name("ADDRESS")
+                        .takes_value(true)
+                        .conflicts_with("boot-image"),
+                )
+                .arg(
+                    Arg::with_name("initial-sp")
+                        .long("initial-sp")
+                        .help("Address or symbol to use as the initial main stack pointer instead of the first vector table entry")
+                        .value_name("ADDRESS")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("entry")
+                        .long("entry")
+                        .help("Address or symbol to start execution from instead of the reset vector")
+                        .value_name("ADDRESS")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("image-entry")
+                        .long("image-entry")
+                        .help("Start execution from the entry point given in the image, e.g. ELF e_entry")
+                        .conflicts_with("entry"),
+                )
                 .arg(
                     Arg::with_name("EXECUTABLE")
                         .index(1)
-                        .help("Set executable to load")
-                        .required(true),
+                        .help("Set executables to load: ELF, Intel HEX (.hex), S-record (.srec, .s19, .s28, .s37) or raw binary (.bin), each optionally followed by @OFFSET to move it, e.g. app.bin@0x08004000")
+                        .required(true)
+                        .multiple(true),
                 ),
         )
         .get_matches();
@@ -307,6 +1097,90 @@ fn main() {
             error!("backtrace: {:?}", backtrace);
         }
 
-        ::std::process::exit(1);
+        let code = match e.kind() {
+            ErrorKind::FaultTrap => EXIT_FAULT,
+            ErrorKind::Lockup(..) => EXIT_LOCKUP,
+            ErrorKind::RunLimit(_) => EXIT_TIMEOUT,
+            ErrorKind::ApplicationExit(status) => application_exit_code(*status),
+            _ => EXIT_ERROR,
+        };
+        ::std::process::exit(code);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segment(address: u32, size: usize) -> Segment {
+        Segment {
+            address,
+            data: vec![0; size],
+            zero_fill: false,
+        }
+    }
+
+    #[test]
+    fn test_application_exit_code() {
+        assert_eq!(application_exit_code(42), 42);
+        assert_eq!(application_exit_code(0x101), 0x101);
+        assert_eq!(application_exit_code(0x100), EXIT_ERROR);
+        assert_eq!(application_exit_code(0xffff_ff00), EXIT_ERROR);
+        assert_eq!(application_exit_code(0xffff_ffff), -1);
+    }
+
+    #[test]
+    fn test_flash_window() {
+        let segments = [
+            segment(0x0800_0000, 0x100),
+            segment(0x0800_4000, 0x10),
+            segment(0x1000_0000, 0x10),
+        ];
+        let flash: Vec<&Segment> = segments.iter().collect();
+
+        let window = |segments: &[&Segment], flash_size| {
+            flash_window(segments, flash_size).map_err(|e| e.to_string())
+        };
+        assert_eq!(window(&flash[..2], None), Ok((0x0800_0000, 0x4010)));
+        assert_eq!(
+            window(&flash[..2], Some(0x1_0000)),
+            Ok((0x0800_0000, 0x1_0000))
+        );
+        assert_eq!(
+            window(&flash[..2], Some(0x1000)),
+            Err("image data at 0x08004000..0x08004010 is neither in a memory region nor in the flash at 0x08000000..0x08001000".to_string())
+        );
+        assert_eq!(
+            window(&flash, None),
+            Err("image data at 0x10000000..0x10000010 is neither in a memory region nor in the flash at 0x08000000..0x09000000".to_string())
+        );
+        assert_eq!(window(&[], None), Ok((0, 0)));
+    }
+
+    #[test]
+    fn test_partition_segments() {
+        let mut processor = Processor::new();
+        processor.memory_regions(&[
+            MemoryRegionConfig::ram("SRAM", 0x2000_0000, 0x1000),
+            MemoryRegionConfig::rom("ROM", 0x1FFF_0000, 0x1000),
+        ]);
+        processor.memory_map(Some(MemoryMapConfig::new(0x1000_0000, 0x2000_0000, 0x1000)));
+        let segments = vec![
+            segment(0x0800_0000, 0x100),
+            segment(0x2000_0000, 0x10),
+            segment(0x1FFF_0000, 0x10),
+            segment(0x1000_0100, 0x10),
+            segment(0x0800_0400, 0x10),
+        ];
+
+        let (region_segments, flash_segments) = partition_segments(&processor, &segments);
+        let addresses = |segments: Vec<&Segment>| -> Vec<u32> {
+            segments.iter().map(|segment| segment.address).collect()
+        };
+        assert_eq!(
+            addresses(region_segments),
+            vec![0x2000_0000, 0x1FFF_0000, 0x1000_0100]
+        );
+        assert_eq!(addresses(flash_segments), vec![0x0800_0000, 0x0800_0400]);
     }
 }
@@ -0,0 +1,279 @@
+//!
+//! Output of the function profile: a flat profile and the callgrind format
+//! read by KCachegrind
+//!
+
+use crate::debuginfo::LineTable;
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use zmu_cortex_m::core::profile::{Cost, Profiler};
+
+fn function_name(symbols: &HashMap<u32, String>, address: u32) -> String {
+    symbols
+        .get(&address)
+        .cloned()
+        .unwrap_or_else(|| format!("0x{:08x}", address))
+}
+
+///
+/// Write the cost of each function, the most expensive first
+///
+pub fn write_flat_profile(
+    out: &mut dyn io::Write,
+    profiler: &Profiler,
+    symbols: &HashMap<u32, String>,
+) -> io::Result<()> {
+    let mut functions: HashMap<String, (Cost, Cost, u64)> = HashMap::new();
+    for ((function, _), cost) in profiler.costs() {
+        functions
+            .entry(function_name(symbols, *function))
+            .or_default()
+            .0 += *cost;
+    }
+    for call in profiler.calls() {
+        let callee = function_name(symbols, call.callee);
+        let recursive = function_name(symbols, call.caller) == callee;
+        let entry = functions.entry(callee).or_default();
+        if !recursive {
+            entry.1 += call.inclusive;
+        }
+        entry.2 += call.count;
+    }
+    let total = profiler.total();
+
+    let mut functions: Vec<_> = functions.into_iter().collect();
+    functions.sort_by(|a, b| (b.1).0.cycles.cmp(&(a.1).0.cycles).then(a.0.cmp(&b.0)));
+
+    writeln!(out, "Flat profile:")?;
+    writeln!(
+        out,
+        "  {:>7}  {:>12}  {:>12}  {:>12}  {:>8}  function",
+        "%cycles", "self cycles", "total cycles", "instructions", "calls"
+    )?;
+    for (name, (self_cost, inclusive, calls)) in functions {
+        // functions never called, such as the reset handler, run all the time
+        let inclusive = if calls == 0 { total } else { inclusive };
+        writeln!(
+            out,
+            "  {:>7.2}  {:>12}  {:>12}  {:>12}  {:>8}  {}",
+            100.0 * self_cost.cycles as f64 / total.cycles.max(1) as f64,
+            self_cost.cycles,
+            inclusive.cycles,
+            self_cost.instructions,
+            calls,
+            name
+        )?;
+    }
+    Ok(())
+}
+
+///
+/// Write the profile in the callgrind format, with the costs of each
+/// instruction and source line
+///
+pub fn write_callgrind(
+    out: &mut dyn io::Write,
+    profiler: &Profiler,
+    symbols: &HashMap<u32, String>,
+    lines: &LineTable,
+    command: &str,
+) -> io::Result<()> {
+    let file = |address: u32| {
+        lines
+            .lookup(address)
+            .map_or("???", |location| location.file)
+    };
+    let line = |address: u32| lines.lookup(address).map_or(0, |location| location.line);
+
+    let mut functions: BTreeMap<u32, (BTreeMap<u32, Cost>, Vec<_>)> = BTreeMap::new();
+    for ((function, pc), cost) in profiler.costs() {
+        functions.entry(*function).or_default().0.insert(*pc, *cost);
+    }
+    for call in profiler.calls() {
+        functions.entry(call.caller).or_default().1.push(call);
+    }
+
+    let total = profiler.total();
+    writeln!(out, "# callgrind format")?;
+    writeln!(out, "version: 1")?;
+    writeln!(out, "creator: zmu")?;
+    writeln!(out, "cmd: {}", command)?;
+    writeln!(out, "positions: instr line")?;
+    writeln!(out, "events: Instructions Cycles")?;
+    writeln!(out, "summary: {} {}", total.instructions, total.cycles)?;
+
+    for (function, (costs, mut calls)) in functions {
+        let function_file = file(function);
+        writeln!(out)?;
+        writeln!(out, "fl={}", function_file)?;
+        writeln!(out, "fn={}", function_name(symbols, function))?;
+
+        let mut current_file = function_file;
+        let mut set_file = |out: &mut dyn io::Write, address: u32| -> io::Result<()> {
+            let address_file = file(address);
+            if address_file != current_file {
+                writeln!(out, "fi={}", address_file)?;
+                current_file = address_file;
+            }
+            Ok(())
+        };
+
+        for (pc, cost) in costs {
+            set_file(out, pc)?;
+            writeln!(
+                out,
+                "0x{:08x} {} {} {}",
+                pc,
+                line(pc),
+                cost.instructions,
+                cost.cycles
+            )?;
+        }
+
+        calls.sort_by_key(|call| (call.call_site, call.callee));
+        for call in calls {
+            set_file(out, call.call_site)?;
+            writeln!(out, "cfl={}", file(call.callee))?;
+            writeln!(out, "cfn={}", function_name(symbols, call.callee))?;
+            writeln!(
+                out,
+                "calls={} 0x{:08x} {}",
+                call.count,
+                call.callee,
+                line(call.callee)
+            )?;
+            writeln!(
+                out,
+                "0x{:08x} {} {} {}",
+                call.call_site,
+                line(call.call_site),
+                call.inclusive.instructions,
+                call.inclusive.cycles
+            )?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use zmu_cortex_m::core::instruction::Instruction;
+    use zmu_cortex_m::core::profile::Profiling;
+    use zmu_cortex_m::core::register::{BaseReg, Reg};
+    use zmu_cortex_m::Processor;
+
+    // main calls f at 0x100, f calls itself at 0x202 and returns at 0x206
+    fn recursive_profile() -> Processor {
+        let mut processor = Processor::new();
+        processor.profiler(Some(Profiler::new()));
+        let cmp = Instruction::NOP { thumb32: false };
+        let bl = Instruction::BL { imm32: 0 };
+        let bx = Instruction::BX { rm: Reg::LR };
+
+        let mut execute = |pc: u32, instruction: &Instruction, cycles: u32, target: Option<u32>| {
+            processor.profile_before(pc);
+            if let Some(target) = target {
+                if let Instruction::BL { .. } = instruction {
+                    processor.set_r(Reg::LR, (pc + 4) | 1);
+                }
+                processor.set_pc(target);
+            }
+            processor.profile_instruction(instruction, pc, cycles, target.is_some());
+        };
+        execute(0x100, &bl, 1, Some(0x200));
+        execute(0x200, &cmp, 1, None);
+        execute(0x202, &bl, 2, Some(0x200));
+        execute(0x200, &cmp, 1, None);
+        execute(0x206, &bx, 3, Some(0x206));
+        execute(0x206, &bx, 3, Some(0x104));
+        execute(0x104, &cmp, 1, None);
+        processor
+    }
+
+    fn symbols() -> HashMap<u32, String> {
+        [(0x100, "main"), (0x200, "f")]
+            .iter()
+            .map(|(address, name)| (*address, name.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_write_flat_profile() {
+        let processor = recursive_profile();
+        let mut out = Vec::new();
+        write_flat_profile(&mut out, processor.profile().unwrap(), &symbols()).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let rows: Vec<Vec<&str>> = out
+            .lines()
+            .skip(2)
+            .map(|row| row.split_whitespace().collect())
+            .collect();
+
+        // the recursive call is not counted twice, main runs all the time
+        assert_eq!(
+            rows,
+            vec![
+                vec!["83.33", "10", "10", "5", "2", "f"],
+                vec!["16.67", "2", "12", "2", "0", "main"],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_write_callgrind() {
+        let processor = recursive_profile();
+        let lines = LineTable::from_rows(
+            &["main.c", "f.c", "f.h"],
+            &[
+                (0x100, Some((0, 3))),
+                (0x104, Some((0, 4))),
+                (0x106, None),
+                (0x200, Some((1, 10))),
+                (0x202, Some((1, 11))),
+                (0x206, Some((2, 5))),
+                (0x208, None),
+            ],
+        );
+        let mut out = Vec::new();
+        write_callgrind(
+            &mut out,
+            processor.profile().unwrap(),
+            &symbols(),
+            &lines,
+            "zmu run test.elf",
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "# callgrind format\n\
+             version: 1\n\
+             creator: zmu\n\
+             cmd: zmu run test.elf\n\
+             positions: instr line\n\
+             events: Instructions Cycles\n\
+             summary: 7 12\n\
+             \n\
+             fl=main.c\n\
+             fn=main\n\
+             0x00000100 3 1 1\n\
+             0x00000104 4 1 1\n\
+             cfl=f.c\n\
+             cfn=f\n\
+             calls=1 0x00000200 10\n\
+             0x00000100 3 5 10\n\
+             \n\
+             fl=f.c\n\
+             fn=f\n\
+             0x00000200 10 2 2\n\
+             0x00000202 11 1 2\n\
+             fi=f.h\n\
+             0x00000206 5 2 6\n\
+             fi=f.c\n\
+             cfl=f.c\n\
+             cfn=f\n\
+             calls=1 0x00000200 10\n\
+             0x00000202 11 2 4\n"
+        );
+    }
+}
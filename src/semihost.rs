@@ -152,23 +152,29 @@ pub fn get_semihost_func(start: Instant) -> impl FnMut(&SemihostingCommand) -> S
             }
             SemihostingCommand::SysException { ref reason } => {
                 // println!("sysexception {:?}", reason);
-                let stop = match reason {
-                    SysExceptionReason::ADPStoppedApplicationExit
-                    | SysExceptionReason::ADPStopped => true,
-                    _ => false,
-                };
 
+                // any other reason than a normal exit is a failure
                 SemihostingResponse::SysException {
                     success: true,
-                    stop,
+                    stop: true,
+                    exit_code: if reason == &SysExceptionReason::ADPStoppedApplicationExit {
+                        0
+                    } else {
+                        1
+                    },
                 }
             }
-            SemihostingCommand::SysExitExtended { ref reason, .. } => {
+            SemihostingCommand::SysExitExtended { reason, subcode } => {
                 // println!("sys exit {:?}", reason);
 
                 SemihostingResponse::SysExitExtended {
                     success: true,
-                    stop: reason == &SysExceptionReason::ADPStoppedApplicationExit,
+                    stop: true,
+                    exit_code: if *reason == SysExceptionReason::ADPStoppedApplicationExit {
+                        *subcode
+                    } else {
+                        1
+                    },
                 }
             }
             SemihostingCommand::SysErrno { .. } => {
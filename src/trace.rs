@@ -1,14 +1,25 @@
 extern crate zmu_cortex_m;
 
+use crate::debuginfo::LineTable;
 use pad::PadStr;
+use serde_json::{json, Map, Value};
 use std::collections::HashMap;
+use std::io;
+use std::io::Write;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+use zmu_cortex_m::bus::trace::BusAccess;
 use zmu_cortex_m::core::fetch::Fetch;
-use zmu_cortex_m::core::register::{Apsr, PSR};
+use zmu_cortex_m::core::register::{Apsr, BaseReg, Reg, PSR};
 use zmu_cortex_m::core::thumb::ThumbCode;
 use zmu_cortex_m::decoder::Decoder;
-use zmu_cortex_m::Processor;
+use zmu_cortex_m::{Processor, ProcessorMode};
 
-pub fn format_trace_entry(processor: &Processor, symboltable: &HashMap<u32, &str>) -> String {
+pub fn format_trace_entry(
+    processor: &Processor,
+    symboltable: &HashMap<u32, String>,
+    lines: &LineTable,
+) -> String {
     let pc = processor.last_pc;
 
     let thumb = processor.fetch(pc).unwrap();
@@ -21,14 +32,21 @@ pub fn format_trace_entry(processor: &Processor, symboltable: &HashMap<u32, &str
 
     let instruction_str = format!("{}", instruction).with_exact_width(32);
 
-    let symbol = symboltable.get(&pc).unwrap_or(&"").with_exact_width(20);
+    let symbol = symboltable
+        .get(&pc)
+        .map_or("", String::as_str)
+        .with_exact_width(20);
 
     let psr = PSR {
         value: processor.psr.value,
     };
 
+    let location = lines
+        .lookup(pc)
+        .map_or_else(String::new, |location| format!(" {}", location));
+
     format!(
-        "{0:}  {1:} {2:08X}  {3:}  {4:} {5:}{6:}{7:}{8:}{9:} r0:{10:08x} 1:{11:08x} 2:{12:08x} 3:{13:08x} 4:{14:08x} 5:{15:08x} 6:{16:08x} 7:{17:08x} 8:{18:08x} 9:{19:08x} 10:{20:08x} 11:{21:08x} 12:{22:08x}",
+        "{0:}  {1:} {2:08X}  {3:}  {4:} {5:}{6:}{7:}{8:}{9:} r0:{10:08x} 1:{11:08x} 2:{12:08x} 3:{13:08x} 4:{14:08x} 5:{15:08x} 6:{16:08x} 7:{17:08x} 8:{18:08x} 9:{19:08x} 10:{20:08x} 11:{21:08x} 12:{22:08x}{23:}",
         opcode_str, instruction_str, pc, symbol, processor.instruction_count,
         if psr.get_q() {'Q'} else {'q'},
         if psr.get_v() {'V'} else {'v'},
@@ -36,5 +54,389 @@ pub fn format_trace_entry(processor: &Processor, symboltable: &HashMap<u32, &str
         if psr.get_z() {'Z'} else {'z'},
         if psr.get_n() {'N'} else {'n'},
         processor.r0_12[0], processor.r0_12[1], processor.r0_12[2], processor.r0_12[3], processor.r0_12[4], processor.r0_12[5], processor.r0_12[6], processor.r0_12[7], processor.r0_12[8], processor.r0_12[9], processor.r0_12[10], processor.r0_12[11], processor.r0_12[12],
+        location,
     )
 }
+
+///
+/// Output format of the instruction trace
+///
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub enum TraceFormat {
+    Text,
+    Json,
+    Binary,
+}
+
+impl FromStr for TraceFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "text" => Ok(TraceFormat::Text),
+            "json" => Ok(TraceFormat::Json),
+            "binary" => Ok(TraceFormat::Binary),
+            _ => Err(format!("unknown trace format '{}'", s)),
+        }
+    }
+}
+
+impl TraceFormat {
+    ///
+    /// Fields used when none are selected
+    ///
+    pub fn default_fields(self) -> &'static str {
+        match self {
+            TraceFormat::Binary => "count,pc,opcode,registers",
+            _ => "count,pc,opcode,instruction,symbol,flags,registers",
+        }
+    }
+}
+
+///
+/// Field of a structured trace record. Binary records hold the numeric
+/// fields in this order.
+///
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub enum TraceField {
+    Count,
+    Cycles,
+    Pc,
+    Opcode,
+    Registers,
+    Sp,
+    Lr,
+    Psr,
+    Mode,
+    Memory,
+    Instruction,
+    Symbol,
+    Location,
+    Flags,
+}
+
+const TRACE_FIELDS: [(&str, TraceField); 14] = [
+    ("count", TraceField::Count),
+    ("cycles", TraceField::Cycles),
+    ("pc", TraceField::Pc),
+    ("opcode", TraceField::Opcode),
+    ("registers", TraceField::Registers),
+    ("sp", TraceField::Sp),
+    ("lr", TraceField::Lr),
+    ("psr", TraceField::Psr),
+    ("mode", TraceField::Mode),
+    ("memory", TraceField::Memory),
+    ("instruction", TraceField::Instruction),
+    ("symbol", TraceField::Symbol),
+    ("location", TraceField::Location),
+    ("flags", TraceField::Flags),
+];
+
+///
+/// Parse a comma separated list of trace fields, e.g. "count,pc,memory",
+/// or use the default fields of the format if none are given
+///
+pub fn parse_trace_fields(s: Option<&str>, format: TraceFormat) -> Result<Vec<TraceField>, String> {
+    let s = match s {
+        Some(_) if format == TraceFormat::Text => {
+            return Err("trace fields are not available in text format".to_string())
+        }
+        Some(s) => s,
+        None => format.default_fields(),
+    };
+    let mut fields: Vec<TraceField> = Vec::new();
+    for name in s.split(',').map(str::trim) {
+        let field = TRACE_FIELDS
+            .iter()
+            .find(|(field_name, _)| *field_name == name)
+            .map(|(_, field)| *field)
+            .ok_or_else(|| format!("unknown trace field '{}'", name))?;
+        if format == TraceFormat::Binary && field as u8 >= TraceField::Instruction as u8 {
+            return Err(format!(
+                "trace field '{}' is not available in binary format",
+                name
+            ));
+        }
+        if !fields.contains(&field) {
+            fields.push(field);
+        }
+    }
+    // binary records have a fixed field order
+    fields.sort_by_key(|field| *field as u8);
+    Ok(fields)
+}
+
+///
+/// Selection of the traced instructions
+///
+#[derive(Default)]
+pub struct TraceFilter {
+    /// address ranges of the traced instructions, empty for all
+    pub ranges: Vec<RangeInclusive<u32>>,
+    /// instruction count of the first traced instruction
+    pub start: u64,
+    /// instruction count after which tracing stops
+    pub end: Option<u64>,
+    /// execution mode of the traced instructions
+    pub mode: Option<ProcessorMode>,
+}
+
+impl TraceFilter {
+    pub fn matches(&self, processor: &Processor) -> bool {
+        let count = processor.instruction_count;
+        let pc = processor.last_pc;
+        count >= self.start
+            && self.end.is_none_or(|end| count <= end)
+            && self.mode.is_none_or(|mode| mode == last_mode(processor))
+            && (self.ranges.is_empty() || self.ranges.iter().any(|range| range.contains(&pc)))
+    }
+}
+
+///
+/// Writer of JSON lines and binary traces
+///
+pub struct StructuredTrace {
+    format: TraceFormat,
+    fields: Vec<TraceField>,
+    out: Box<dyn Write>,
+}
+
+// Magic and version at the start of a binary trace
+const BINARY_TRACE_MAGIC: &[u8; 8] = b"ZMUTRACE";
+const BINARY_TRACE_VERSION: u8 = 1;
+
+impl StructuredTrace {
+    pub fn new(
+        format: TraceFormat,
+        fields: Vec<TraceField>,
+        mut out: Box<dyn Write>,
+    ) -> Result<Self, io::Error> {
+        if format == TraceFormat::Binary {
+            // header: magic, version and the bit mask of the recorded fields
+            let mask = fields
+                .iter()
+                .fold(0_u16, |mask, field| mask | (1 << *field as u16));
+            out.write_all(BINARY_TRACE_MAGIC)?;
+            out.write_all(&[BINARY_TRACE_VERSION])?;
+            out.write_all(&mask.to_le_bytes())?;
+        }
+        Ok(Self {
+            format,
+            fields,
+            out,
+        })
+    }
+
+    pub fn write(
+        &mut self,
+        processor: &Processor,
+        accesses: &[BusAccess],
+        symboltable: &HashMap<u32, String>,
+        lines: &LineTable,
+    ) -> Result<(), io::Error> {
+        match self.format {
+            TraceFormat::Binary => self.write_binary(processor, accesses),
+            _ => self.write_json(processor, accesses, symboltable, lines),
+        }
+    }
+
+    fn write_json(
+        &mut self,
+        processor: &Processor,
+        accesses: &[BusAccess],
+        symboltable: &HashMap<u32, String>,
+        lines: &LineTable,
+    ) -> Result<(), io::Error> {
+        let pc = processor.last_pc;
+        let mut record = Map::new();
+        for field in &self.fields {
+            match field {
+                TraceField::Count => {
+                    record.insert("count".into(), json!(processor.instruction_count));
+                }
+                TraceField::Cycles => {
+                    record.insert("cycles".into(), json!(processor.cycle_count));
+                }
+                TraceField::Pc => {
+                    record.insert("pc".into(), json!(pc));
+                }
+                TraceField::Opcode => {
+                    let thumb = fetch(processor, pc)?;
+                    record.insert("opcode".into(), json!(opcode(thumb)));
+                }
+                TraceField::Registers => {
+                    record.insert("registers".into(), json!(processor.r0_12));
+                }
+                TraceField::Sp => {
+                    record.insert("sp".into(), json!(processor.get_r(Reg::SP)));
+                }
+                TraceField::Lr => {
+                    record.insert("lr".into(), json!(processor.get_r(Reg::LR)));
+                }
+                TraceField::Psr => {
+                    record.insert("psr".into(), json!(processor.psr.value));
+                }
+                TraceField::Mode => {
+                    let handler = processor.last_isr_number != 0;
+                    record.insert(
+                        "mode".into(),
+                        json!(if handler { "handler" } else { "thread" }),
+                    );
+                    if handler {
+                        record.insert("exception".into(), json!(processor.last_isr_number));
+                    }
+                }
+                TraceField::Memory => {
+                    let memory: Vec<Value> = accesses
+                        .iter()
+                        .map(|access| {
+                            let mut entry = json!({
+                                "address": access.address,
+                                "size": access.size,
+                                "write": access.write,
+                                "value": access.value,
+                                "region": access.region,
+                            });
+                            if let Some(fault) = access.fault {
+                                entry["fault"] = json!(format!("{:?}", fault));
+                            }
+                            entry
+                        })
+                        .collect();
+                    record.insert("memory".into(), Value::Array(memory));
+                }
+                TraceField::Instruction => {
+                    let instruction = processor.decode(fetch(processor, pc)?);
+                    record.insert("instruction".into(), json!(instruction.to_string()));
+                }
+                TraceField::Symbol => {
+                    record.insert("symbol".into(), json!(symboltable.get(&pc)));
+                }
+                TraceField::Location => {
+                    let location = lines.lookup(pc).map(|location| location.to_string());
+                    record.insert("location".into(), json!(location));
+                }
+                TraceField::Flags => {
+                    record.insert("flags".into(), json!(flags(processor)));
+                }
+            }
+        }
+        serde_json::to_writer(&mut self.out, &Value::Object(record))?;
+        writeln!(self.out)
+    }
+
+    fn write_binary(
+        &mut self,
+        processor: &Processor,
+        accesses: &[BusAccess],
+    ) -> Result<(), io::Error> {
+        let pc = processor.last_pc;
+        let mut record = Vec::with_capacity(128);
+        for field in &self.fields {
+            match field {
+                TraceField::Count => record.extend(&processor.instruction_count.to_le_bytes()),
+                TraceField::Cycles => record.extend(&processor.cycle_count.to_le_bytes()),
+                TraceField::Pc => record.extend(&pc.to_le_bytes()),
+                TraceField::Opcode => record.extend(&opcode(fetch(processor, pc)?).to_le_bytes()),
+                TraceField::Registers => {
+                    for r in &processor.r0_12 {
+                        record.extend(&r.to_le_bytes());
+                    }
+                }
+                TraceField::Sp => record.extend(&processor.get_r(Reg::SP).to_le_bytes()),
+                TraceField::Lr => record.extend(&processor.get_r(Reg::LR).to_le_bytes()),
+                TraceField::Psr => record.extend(&processor.psr.value.to_le_bytes()),
+                TraceField::Mode => {
+                    // exception number, 0 in thread mode
+                    record.extend(&(processor.last_isr_number as u16).to_le_bytes());
+                }
+                TraceField::Memory => {
+                    record.push(accesses.len().min(255) as u8);
+                    for access in accesses.iter().take(255) {
+                        // bit 0: write, bit 1: fault, bits 4-7: size in bytes
+                        let kind = u8::from(access.write)
+                            | (u8::from(access.fault.is_some()) << 1)
+                            | (access.size << 4);
+                        record.push(kind);
+                        record.extend(&access.address.to_le_bytes());
+                        record.extend(&access.value.to_le_bytes());
+                    }
+                }
+                _ => unreachable!(),
+            }
+        }
+        self.out.write_all(&record)
+    }
+
+    pub fn flush(&mut self) -> Result<(), io::Error> {
+        self.out.flush()
+    }
+}
+
+// Mode the traced instruction ran in
+fn last_mode(processor: &Processor) -> ProcessorMode {
+    if processor.last_isr_number == 0 {
+        ProcessorMode::ThreadMode
+    } else {
+        ProcessorMode::HandlerMode
+    }
+}
+
+// Instruction at the traced address, which may no longer be readable
+fn fetch(processor: &Processor, pc: u32) -> Result<ThumbCode, io::Error> {
+    processor.fetch(pc).map_err(|fault| {
+        io::Error::other(format!(
+            "unable to fetch instruction at 0x{:08x}: {:?}",
+            pc, fault
+        ))
+    })
+}
+
+fn opcode(thumb: ThumbCode) -> u32 {
+    match thumb {
+        ThumbCode::Thumb32 { opcode } => opcode,
+        ThumbCode::Thumb16 { opcode } => u32::from(opcode),
+    }
+}
+
+fn flags(processor: &Processor) -> String {
+    let psr = PSR {
+        value: processor.psr.value,
+    };
+    [
+        (psr.get_q(), 'Q'),
+        (psr.get_v(), 'V'),
+        (psr.get_c(), 'C'),
+        (psr.get_z(), 'Z'),
+        (psr.get_n(), 'N'),
+    ]
+    .iter()
+    .map(|(set, flag)| {
+        if *set {
+            *flag
+        } else {
+            flag.to_ascii_lowercase()
+        }
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_trace_fields() {
+        assert_eq!(
+            parse_trace_fields(Some("memory, pc,count,pc"), TraceFormat::Json),
+            Ok(vec![TraceField::Count, TraceField::Pc, TraceField::Memory])
+        );
+        assert!(parse_trace_fields(Some("pc,symbol"), TraceFormat::Json).is_ok());
+        assert!(parse_trace_fields(Some("pc,symbol"), TraceFormat::Binary).is_err());
+        assert!(parse_trace_fields(Some("pc,bogus"), TraceFormat::Json).is_err());
+        assert!(parse_trace_fields(Some("pc"), TraceFormat::Text).is_err());
+        for format in &[TraceFormat::Text, TraceFormat::Json, TraceFormat::Binary] {
+            assert!(parse_trace_fields(None, *format).is_ok());
+        }
+    }
+}
@@ -92,11 +92,7 @@ echo "========================================"
 echo "TEST: cortex-m-rtfm crate examples"
 echo "========================================"
 
-#
-# TODO: not yet working examples: 
-# - "ramfunc" => ramfuncs currently not supported by the emulator because of the caching
-
-declare -a arr=("baseline" "binds" "capacity" "generics" "idle" "init" "interrupt" "late" "lock" "message" "periodic" "not-send" "not-sync" "resource" "singleton" "smallest" "schedule" "static" "task")
+declare -a arr=("baseline" "binds" "capacity" "generics" "idle" "init" "interrupt" "late" "lock" "message" "periodic" "not-send" "not-sync" "ramfunc" "resource" "singleton" "smallest" "schedule" "static" "task")
 cd tests/cortex-m-rtfm
 cargo build
 for i in "${arr[@]}"
@@ -3,15 +3,25 @@
 //!
 
 
+pub mod trace;
+
 use crate::Processor;
 
+use crate::bus::trace::BusTracing;
+use crate::core::bits::Bits;
 use crate::core::fault::Fault;
+use crate::core::icache::InstructionCache;
+use crate::core::memcheck::Memchecking;
+use crate::device::peripheral::Peripherals;
 use crate::peripheral::dwt::Dwt;
 use crate::peripheral::itm::InstrumentationTraceMacrocell;
 use crate::peripheral::nvic::NVIC;
 use crate::peripheral::scb::SystemControlBlock;
 use crate::peripheral::systick::SysTick;
+use crate::memory::flash::FlashErase;
 use crate::memory::map::MapMemory;
+#[cfg(any(armv7m, armv7em))]
+use crate::memory::bitband::bitband_target;
 
 
 
@@ -48,9 +58,111 @@ pub trait Bus {
     fn in_range(&self, addr: u32) -> bool;
 }
 
-impl Bus for Processor {
-    fn read8(&self, bus_addr: u32) -> Result<u8, Fault> {
+///
+/// Bus accesses that are not seen by the bus trace, used for instruction
+/// fetches and for the accesses done on behalf of another access.
+///
+pub(crate) trait BusInternal {
+    fn bus_read32(&mut self, addr: u32) -> Result<u32, Fault>;
+    fn bus_read16(&self, addr: u32) -> Result<u16, Fault>;
+    fn bus_read8(&self, addr: u32) -> Result<u8, Fault>;
+    fn bus_write32(&mut self, addr: u32, value: u32) -> Result<(), Fault>;
+    fn bus_write16(&mut self, addr: u32, value: u16) -> Result<(), Fault>;
+    fn bus_write8(&mut self, addr: u32, value: u8) -> Result<(), Fault>;
+}
+
+trait BusHelper {
+    fn check_ppb_access(&self, addr: u32) -> Result<(), Fault>;
+    fn swaps_data(&self, addr: u32) -> bool;
+    fn flash_erase(&mut self);
+    #[cfg(any(armv7m, armv7em))]
+    fn bitband_read(&mut self, addr: u32) -> Result<u32, Fault>;
+    #[cfg(any(armv7m, armv7em))]
+    fn bitband_read_byte(&self, addr: u32) -> Result<u32, Fault>;
+    #[cfg(any(armv7m, armv7em))]
+    fn bitband_write(&mut self, addr: u32, value: u32) -> Result<(), Fault>;
+}
+
+impl BusHelper for Processor {
+    // Unprivileged accesses to the Private Peripheral Bus fault, apart
+    // from the ITM stimulus ports and STIR (when CCR.USERSETMPEND is set).
+    #[inline(always)]
+    fn check_ppb_access(&self, addr: u32) -> Result<(), Fault> {
+        match addr {
+            0xE000_0000..=0xE00F_FFFF if !self.current_mode_is_privileged() => match addr {
+                0xE000_0000..=0xE000_007F => Ok(()),
+                0xE000_EF00..=0xE000_EF03 if self.ccr.get_bit(1) => Ok(()),
+                _ => Err(Fault::DAccViol),
+            },
+            _ => Ok(()),
+        }
+    }
+
+    // Data accesses are byte-swapped in the big-endian (BE8) configuration,
+    // apart from the accesses to the always little-endian Private Peripheral Bus
+    // and to the bit-band aliases, where the bit is always in bit 0 of the value.
+    #[inline(always)]
+    fn swaps_data(&self, addr: u32) -> bool {
+        #[cfg(any(armv7m, armv7em))]
+        let bitband = bitband_target(addr).is_some();
+        #[cfg(armv6m)]
+        let bitband = false;
+        self.is_big_endian() && !(0xE000_0000..=0xE00F_FFFF).contains(&addr) && !bitband
+    }
+
+    // Apply the erase operation started via the device flash controller.
+    fn flash_erase(&mut self) {
+        match self.device.flash_erase_request() {
+            Some(FlashErase::Page { address, size }) => {
+                let start = self.map_address(address & !(size as u32 - 1));
+                if self.code.in_range(start) {
+                    self.code.erase(start, size);
+                    self.invalidate_decoded(start, size);
+                }
+            }
+            Some(FlashErase::Mass) => {
+                self.code.erase_all();
+                self.invalidate_decoded(self.code.start_address(), self.code.len());
+            }
+            None => (),
+        }
+    }
+
+    // Reads of a bit-band alias word return the addressed bit in bit 0.
+    // The bit is read from the containing aligned word, as device models
+    // may only implement word accesses.
+    #[cfg(any(armv7m, armv7em))]
+    fn bitband_read(&mut self, addr: u32) -> Result<u32, Fault> {
+        let (byte_addr, bit) = bitband_target(addr).unwrap();
+        let bit = (byte_addr & 3) as usize * 8 + bit;
+        Ok(u32::from(self.bus_read32(byte_addr & !3)?.get_bit(bit)))
+    }
+
+    // Byte and halfword reads of a bit-band alias only have shared access
+    // to the bus, so they read the addressed bit from the target byte.
+    #[cfg(any(armv7m, armv7em))]
+    fn bitband_read_byte(&self, addr: u32) -> Result<u32, Fault> {
+        let (byte_addr, bit) = bitband_target(addr).unwrap();
+        Ok(u32::from(self.bus_read8(byte_addr)?.get_bit(bit)))
+    }
+
+    // Writes of a bit-band alias word set or clear the addressed bit
+    // according to bit 0 of the value, with a read-modify-write of the
+    // containing aligned word.
+    #[cfg(any(armv7m, armv7em))]
+    fn bitband_write(&mut self, addr: u32, value: u32) -> Result<(), Fault> {
+        let (byte_addr, bit) = bitband_target(addr).unwrap();
+        let word_addr = byte_addr & !3;
+        let mut word = self.bus_read32(word_addr)?;
+        word.set_bit((byte_addr & 3) as usize * 8 + bit, value.get_bit(0));
+        self.bus_write32(word_addr, word)
+    }
+}
+
+impl BusInternal for Processor {
+    fn bus_read8(&self, bus_addr: u32) -> Result<u8, Fault> {
         let addr = self.map_address(bus_addr);
+        self.check_ppb_access(addr)?;
 
         let result = match addr {
             0xE000_E400..=0xE000_E5EC => {
@@ -62,12 +174,18 @@ impl Bus for Processor {
             0xE000_ED1C..=0xE000_ED1F => self.read_shpr2_u8((addr - 0xE000_ED1C) as usize),
             #[cfg(any(armv7m, armv7em))]
             0xE000_ED20..=0xE000_ED23 => self.read_shpr3_u8((addr - 0xE000_ED20) as usize),
+            #[cfg(any(armv7m, armv7em))]
+            0x2200_0000..=0x23FF_FFFF | 0x4200_0000..=0x43FF_FFFF => {
+                self.bitband_read_byte(addr)? as u8
+            }
 
             _ => {
-                if self.sram.in_range(addr) {
-                    return self.sram.read8(addr);
+                if let Some(region) = self.regions.iter().find(|r| r.in_range(addr)) {
+                    return region.read8(addr);
                 } else if self.code.in_range(addr) {
                     return self.code.read8(addr);
+                } else if let Some(p) = self.attached_peripheral(addr) {
+                    return p.peripheral.borrow_mut().read8(addr - p.base);
                 } else if self.device.in_range(addr) {
                     return self.device.read8(addr);
                 } else {
@@ -78,8 +196,9 @@ impl Bus for Processor {
         Ok(result)
     }
 
-    fn read16(&self, bus_addr: u32) -> Result<u16, Fault> {
+    fn bus_read16(&self, bus_addr: u32) -> Result<u16, Fault> {
         let addr = self.map_address(bus_addr);
+        self.check_ppb_access(addr)?;
         match addr {
             #[cfg(any(armv7m, armv7em))]
             0xE000_ED18..=0xE000_ED1B => {
@@ -96,12 +215,18 @@ impl Bus for Processor {
             0xE000_E400..=0xE000_E5EC => {
                 Ok(self.nvic_read_ipr_u16(((addr - 0xE000_E400) >> 1) as usize))
             }
+            #[cfg(any(armv7m, armv7em))]
+            0x2200_0000..=0x23FF_FFFF | 0x4200_0000..=0x43FF_FFFF => {
+                Ok(self.bitband_read_byte(addr)? as u16)
+            }
 
             _ => {
-                if self.sram.in_range(addr) {
-                    self.sram.read16(addr)
+                if let Some(region) = self.regions.iter().find(|r| r.in_range(addr)) {
+                    region.read16(addr)
                 } else if self.code.in_range(addr) {
                     self.code.read16(addr)
+                } else if let Some(p) = self.attached_peripheral(addr) {
+                    p.peripheral.borrow_mut().read16(addr - p.base)
                 } else if self.device.in_range(addr) {
                     self.device.read16(addr)
                 } else {
@@ -111,8 +236,9 @@ impl Bus for Processor {
         }
     }
 
-    fn read32(&mut self, bus_addr: u32) -> Result<u32, Fault> {
+    fn bus_read32(&mut self, bus_addr: u32) -> Result<u32, Fault> {
         let addr = self.map_address(bus_addr);
+        self.check_ppb_access(addr)?;
         
         let result = match addr {
             0xE000_0000 => self.read_stim0(),
@@ -144,7 +270,7 @@ impl Bus for Processor {
             0xE000_ED1C => self.read_shpr2(),
             #[cfg(any(armv7m, armv7em))]
             0xE000_ED20 => self.read_shpr3(),
-            0xE000_ED24 => self.shcsr,
+            0xE000_ED24 => self.read_shcsr(),
             0xE000_ED28 => self.cfsr,
             0xE000_ED2C => self.hfsr,
             0xE000_ED30 => self.dfsr,
@@ -166,11 +292,15 @@ impl Bus for Processor {
 
             // DWT
             0xE000_1000 => self.dwt_ctrl,
+            #[cfg(any(armv7m, armv7em))]
+            0x2200_0000..=0x23FF_FFFF | 0x4200_0000..=0x43FF_FFFF => self.bitband_read(addr)?,
             _ => {
-                if self.sram.in_range(addr) {
-                    self.sram.read32(addr)?
+                if let Some(region) = self.regions.iter_mut().find(|r| r.in_range(addr)) {
+                    region.read32(addr)?
                 } else if self.code.in_range(addr) {
                     self.code.read32(addr)?
+                } else if let Some(p) = self.attached_peripheral(addr) {
+                    p.peripheral.borrow_mut().read32(addr - p.base)?
                 } else if self.device.in_range(addr) {
                     self.device.read32(addr)?
                 } else {
@@ -181,7 +311,9 @@ impl Bus for Processor {
         Ok(result)
     }
 
-    fn write32(&mut self, addr: u32, value: u32) -> Result<(), Fault> {
+    fn bus_write32(&mut self, bus_addr: u32, value: u32) -> Result<(), Fault> {
+        let addr = self.map_address(bus_addr);
+        self.check_ppb_access(addr)?;
         match addr {
             0xE000_0000..=0xE000_007C => {
                 self.write_stim_u32(((addr - 0xE000_0000) >> 2) as u8, value)
@@ -193,12 +325,18 @@ impl Bus for Processor {
             0xE000_ED04 => self.write_icsr(value),
             0xE000_ED08 => self.write_vtor(value),
             0xE000_ED10 => self.write_scr(value),
+            0xE000_ED14 => self.write_ccr(value),
             #[cfg(any(armv7m, armv7em))]
             0xE000_ED18 => self.write_shpr1(value),
             #[cfg(any(armv7m, armv7em))]
             0xE000_ED1C => self.write_shpr2(value),
             #[cfg(any(armv7m, armv7em))]
             0xE000_ED20 => self.write_shpr3(value),
+            0xE000_ED24 => self.write_shcsr(value),
+            #[cfg(any(armv7m, armv7em))]
+            0xE000_ED28 => self.write_cfsr(value),
+            #[cfg(any(armv7m, armv7em))]
+            0xE000_ED2C => self.write_hfsr(value),
 
             0xE000_EDFC => self.write_demcr(value),
 
@@ -223,13 +361,25 @@ impl Bus for Processor {
 
             #[cfg(any(armv7m, armv7em))]
             0xE000_EF00 => self.write_stir(value),
+            #[cfg(any(armv7m, armv7em))]
+            0x2200_0000..=0x23FF_FFFF | 0x4200_0000..=0x43FF_FFFF => {
+                self.bitband_write(addr, value)?
+            }
             _ => {
-                if self.sram.in_range(addr) {
-                    return self.sram.write32(addr, value);
+                if let Some(region) = self.regions.iter_mut().find(|r| r.in_range(addr)) {
+                    region.write32(addr, value)?;
+                    self.invalidate_decoded(addr, 4);
+                    return Ok(());
                 } else if self.code.in_range(addr) {
                     return self.code.write32(addr, value);
+                } else if let Some(p) = self.attached_peripheral(addr) {
+                    p.peripheral.borrow_mut().write32(addr - p.base, value)?;
+                    self.peripherals_remap();
+                    return Ok(());
                 } else if self.device.in_range(addr) {
-                    return self.device.write32(addr, value);
+                    self.device.write32(addr, value)?;
+                    self.flash_erase();
+                    return Ok(());
                 } else {
                     return Err(Fault::DAccViol);
                 }
@@ -238,7 +388,9 @@ impl Bus for Processor {
         Ok(())
     }
 
-    fn write16(&mut self, addr: u32, value: u16) -> Result<(), Fault> {
+    fn bus_write16(&mut self, bus_addr: u32, value: u16) -> Result<(), Fault> {
+        let addr = self.map_address(bus_addr);
+        self.check_ppb_access(addr)?;
         match addr {
             0xE000_0000..=0xE000_007C => {
                 self.write_stim_u16(((addr - 0xE000_0000) >> 2) as u8, value)
@@ -258,11 +410,23 @@ impl Bus for Processor {
             0xE000_E400..=0xE000_E5EC => {
                 self.nvic_write_ipr_u16(((addr - 0xE000_E400) >> 1) as usize, value)
             }
+            #[cfg(any(armv7m, armv7em))]
+            0x2200_0000..=0x23FF_FFFF | 0x4200_0000..=0x43FF_FFFF => {
+                self.bitband_write(addr, u32::from(value))?
+            }
             _ => {
-                if self.sram.in_range(addr) {
-                    return self.sram.write16(addr, value);
+                if let Some(region) = self.regions.iter_mut().find(|r| r.in_range(addr)) {
+                    region.write16(addr, value)?;
+                    self.invalidate_decoded(addr, 2);
+                    return Ok(());
                 } else if self.code.in_range(addr) {
-                    return self.code.write16(addr, value);
+                    self.device.flash_program(&mut self.code, addr, value)?;
+                    self.invalidate_decoded(addr, 2);
+                    return Ok(());
+                } else if let Some(p) = self.attached_peripheral(addr) {
+                    p.peripheral.borrow_mut().write16(addr - p.base, value)?;
+                    self.peripherals_remap();
+                    return Ok(());
                 } else if self.device.in_range(addr) {
                     return self.device.write16(addr, value);
                 } else {
@@ -273,7 +437,9 @@ impl Bus for Processor {
         Ok(())
     }
 
-    fn write8(&mut self, addr: u32, value: u8) -> Result<(), Fault> {
+    fn bus_write8(&mut self, bus_addr: u32, value: u8) -> Result<(), Fault> {
+        let addr = self.map_address(bus_addr);
+        self.check_ppb_access(addr)?;
         match addr {
             0xE000_0000..=0xE000_007C => {
                 self.write_stim_u8(((addr - 0xE000_0000) >> 2) as u8, value)
@@ -287,12 +453,22 @@ impl Bus for Processor {
             0xE000_ED1C..=0xE000_ED1F => self.write_shpr2_u8((addr - 0xE000_ED1C) as usize, value),
             #[cfg(any(armv7m, armv7em))]
             0xE000_ED20..=0xE000_ED23 => self.write_shpr3_u8((addr - 0xE000_ED20) as usize, value),
+            #[cfg(any(armv7m, armv7em))]
+            0x2200_0000..=0x23FF_FFFF | 0x4200_0000..=0x43FF_FFFF => {
+                self.bitband_write(addr, u32::from(value))?
+            }
 
             _ => {
-                if self.sram.in_range(addr) {
-                    return self.sram.write8(addr, value);
+                if let Some(region) = self.regions.iter_mut().find(|r| r.in_range(addr)) {
+                    region.write8(addr, value)?;
+                    self.invalidate_decoded(addr, 1);
+                    return Ok(());
                 } else if self.code.in_range(addr) {
                     return self.code.write8(addr, value);
+                } else if let Some(p) = self.attached_peripheral(addr) {
+                    p.peripheral.borrow_mut().write8(addr - p.base, value)?;
+                    self.peripherals_remap();
+                    return Ok(());
                 } else if self.device.in_range(addr) {
                     return self.device.write8(addr, value);
                 } else {
@@ -302,9 +478,72 @@ impl Bus for Processor {
         }
         Ok(())
     }
+}
+
+impl Bus for Processor {
+    fn read8(&self, addr: u32) -> Result<u8, Fault> {
+        let result = self.bus_read8(addr);
+        self.trace_access(addr, 1, false, result.map(u32::from));
+        self.memcheck_access(addr, 1, false, result.is_err());
+        result
+    }
+
+    fn read16(&self, addr: u32) -> Result<u16, Fault> {
+        let swap = self.swaps_data(addr);
+        let result = self
+            .bus_read16(addr)
+            .map(|value| if swap { value.swap_bytes() } else { value });
+        self.trace_access(addr, 2, false, result.map(u32::from));
+        self.memcheck_access(addr, 2, false, result.is_err());
+        result
+    }
+
+    fn read32(&mut self, addr: u32) -> Result<u32, Fault> {
+        let swap = self.swaps_data(addr);
+        let result = self
+            .bus_read32(addr)
+            .map(|value| if swap { value.swap_bytes() } else { value });
+        self.trace_access(addr, 4, false, result);
+        self.memcheck_access(addr, 4, false, result.is_err());
+        result
+    }
+
+    fn write32(&mut self, addr: u32, value: u32) -> Result<(), Fault> {
+        let data = if self.swaps_data(addr) {
+            value.swap_bytes()
+        } else {
+            value
+        };
+        let result = self.bus_write32(addr, data);
+        self.trace_access(addr, 4, true, result.map(|_| value));
+        self.memcheck_access(addr, 4, true, result.is_err());
+        result
+    }
+
+    fn write16(&mut self, addr: u32, value: u16) -> Result<(), Fault> {
+        let data = if self.swaps_data(addr) {
+            value.swap_bytes()
+        } else {
+            value
+        };
+        let result = self.bus_write16(addr, data);
+        self.trace_access(addr, 2, true, result.map(|_| u32::from(value)));
+        self.memcheck_access(addr, 2, true, result.is_err());
+        result
+    }
+
+    fn write8(&mut self, addr: u32, value: u8) -> Result<(), Fault> {
+        let result = self.bus_write8(addr, value);
+        self.trace_access(addr, 1, true, result.map(|_| u32::from(value)));
+        self.memcheck_access(addr, 1, true, result.is_err());
+        result
+    }
 
     #[allow(unused)]
     fn in_range(&self, addr: u32) -> bool {
-        self.code.in_range(addr) || self.sram.in_range(addr) ||self.device.in_range(addr)
+        self.code.in_range(addr)
+            || self.regions.iter().any(|r| r.in_range(addr))
+            || self.attached_peripheral(addr).is_some()
+            || self.device.in_range(addr)
     }
 }
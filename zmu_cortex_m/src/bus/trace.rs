@@ -0,0 +1,226 @@
+//!
+//! Tracing of data accesses on the processor bus
+//!
+//! When a trace function is configured with `Processor::bus_trace`, every
+//! data read and write done via the `Bus` of the processor is reported to
+//! it, optionally limited to a set of address ranges. Instruction fetches
+//! are not traced.
+//!
+
+use crate::bus::Bus;
+use crate::core::fault::Fault;
+use crate::device::peripheral::Peripherals;
+use crate::memory::map::MapMemory;
+use crate::Processor;
+use std::cell::RefCell;
+use std::fmt;
+use std::ops::RangeInclusive;
+
+///
+/// A single data access on the bus
+///
+#[derive(PartialEq, Debug, Clone)]
+pub struct BusAccess {
+    /// address of the instruction doing the access
+    pub pc: u32,
+    /// accessed address, as seen by the processor
+    pub address: u32,
+    /// access size in bytes: 1, 2 or 4
+    pub size: u8,
+    /// true for writes, false for reads
+    pub write: bool,
+    /// value read or written, 0 if the access faulted
+    pub value: u32,
+    /// fault caused by the access, if any
+    pub fault: Option<Fault>,
+    /// name of the memory region or peripheral serving the access
+    pub region: String,
+}
+
+impl fmt::Display for BusAccess {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = if self.write { 'W' } else { 'R' };
+        let value = match self.fault {
+            Some(fault) => format!("{fault:?}"),
+            None => format!(
+                "{:0width$x}",
+                self.value,
+                width = usize::from(self.size) * 2
+            ),
+        };
+        write!(
+            f,
+            "{}{:<2} 0x{:08x} {:>8} pc:0x{:08x} {}",
+            kind,
+            u32::from(self.size) * 8,
+            self.address,
+            value,
+            self.pc,
+            self.region
+        )
+    }
+}
+
+///
+/// Function called for each traced bus access
+///
+pub type BusTraceFunc = Box<dyn FnMut(&BusAccess)>;
+
+///
+/// Configured bus trace: the trace function and the traced address ranges
+///
+pub struct BusTrace {
+    func: RefCell<BusTraceFunc>,
+    ranges: Vec<RangeInclusive<u32>>,
+}
+
+impl BusTrace {
+    ///
+    /// Create a bus trace, with empty `ranges` meaning all addresses
+    ///
+    #[must_use]
+    pub fn new(func: BusTraceFunc, ranges: &[RangeInclusive<u32>]) -> Self {
+        Self {
+            func: RefCell::new(func),
+            ranges: ranges.to_vec(),
+        }
+    }
+
+    ///
+    /// Check if an access of `size` bytes at `address` is traced
+    ///
+    pub fn is_traced(&self, address: u32, size: u8) -> bool {
+        let last = address.saturating_add(u32::from(size) - 1);
+        self.ranges.is_empty()
+            || self
+                .ranges
+                .iter()
+                .any(|range| address <= *range.end() && last >= *range.start())
+    }
+}
+
+///
+/// Trait for naming memory and reporting the bus accesses to the bus trace
+///
+pub trait BusTracing {
+    ///
+    /// Name of the memory region or peripheral serving given address
+    ///
+    fn region_name(&self, address: u32) -> String;
+
+    ///
+    /// Report an access to the bus trace, if it is configured and the
+    /// address is within the traced ranges
+    ///
+    fn trace_access(&self, address: u32, size: u8, write: bool, result: Result<u32, Fault>);
+}
+
+impl BusTracing for Processor {
+    fn region_name(&self, address: u32) -> String {
+        let addr = self.map_address(address);
+        match addr {
+            0xE000_0000..=0xE000_0FFF => "ITM".to_string(),
+            0xE000_1000..=0xE000_1FFF => "DWT".to_string(),
+            0xE000_E000..=0xE000_EFFF => "SCS".to_string(),
+            0xE000_0000..=0xE00F_FFFF => "PPB".to_string(),
+            #[cfg(any(armv7m, armv7em))]
+            0x2200_0000..=0x23FF_FFFF | 0x4200_0000..=0x43FF_FFFF => "bit-band".to_string(),
+            _ => {
+                if let Some(region) = self.memory_region(addr) {
+                    region.name.clone()
+                } else if self.code.in_range(addr) {
+                    "flash".to_string()
+                } else if let Some(p) = self.attached_peripheral(addr) {
+                    p.peripheral.borrow().name().to_string()
+                } else if self.device.in_range(addr) {
+                    "device".to_string()
+                } else {
+                    "unmapped".to_string()
+                }
+            }
+        }
+    }
+
+    #[inline(always)]
+    fn trace_access(&self, address: u32, size: u8, write: bool, result: Result<u32, Fault>) {
+        if let Some(trace) = &self.bus_trace {
+            if !trace.is_traced(address, size) {
+                return;
+            }
+            let access = BusAccess {
+                pc: self.pc,
+                address,
+                size,
+                write,
+                value: *result.as_ref().unwrap_or(&0),
+                fault: result.err(),
+                region: self.region_name(address),
+            };
+            (trace.func.borrow_mut())(&access);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+
+    fn traced_processor(
+        ranges: &[RangeInclusive<u32>],
+    ) -> (Processor, Rc<RefCell<Vec<BusAccess>>>) {
+        let accesses = Rc::new(RefCell::new(Vec::new()));
+        let sink = Rc::clone(&accesses);
+        let mut processor = Processor::new();
+        processor.bus_trace(Some(BusTrace::new(
+            Box::new(move |access: &BusAccess| sink.borrow_mut().push(access.clone())),
+            ranges,
+        )));
+        (processor, accesses)
+    }
+
+    #[test]
+    fn test_trace_reads_and_writes() {
+        let (mut processor, accesses) = traced_processor(&[]);
+        processor.pc = 0x100;
+
+        processor.write16(0x2000_0002, 0xbeef).unwrap();
+        processor.read32(0x2000_0000).unwrap();
+        assert!(processor.read8(0x6000_0000).is_err());
+
+        let accesses = accesses.borrow();
+        assert_eq!(accesses.len(), 3);
+        assert_eq!(
+            accesses[0],
+            BusAccess {
+                pc: 0x100,
+                address: 0x2000_0002,
+                size: 2,
+                write: true,
+                value: 0xbeef,
+                fault: None,
+                region: "SRAM".to_string(),
+            }
+        );
+        assert_eq!(accesses[1].value, 0xbeef_cdcd);
+        assert_eq!(accesses[2].fault, Some(Fault::DAccViol));
+        assert_eq!(accesses[2].region, "unmapped");
+        assert_eq!(
+            accesses[0].to_string(),
+            "W16 0x20000002     beef pc:0x00000100 SRAM"
+        );
+    }
+
+    #[test]
+    fn test_trace_address_ranges() {
+        let (mut processor, accesses) = traced_processor(&[0x2000_0010..=0x2000_001F]);
+
+        processor.write32(0x2000_0000, 1).unwrap();
+        processor.write32(0x2000_000E, 2).unwrap();
+        processor.write8(0x2000_001F, 3).unwrap();
+        processor.write8(0x2000_0020, 4).unwrap();
+
+        let addresses: Vec<_> = accesses.borrow().iter().map(|a| a.address).collect();
+        assert_eq!(addresses, vec![0x2000_000E, 0x2000_001F]);
+    }
+}
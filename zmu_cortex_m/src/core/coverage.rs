@@ -0,0 +1,234 @@
+//!
+//! Code coverage
+//!
+//! Counts the executions of each instruction address and the outcomes of
+//! conditional instructions: conditional branches, `CBZ`/`CBNZ` and the
+//! instructions of `IT` blocks.
+//!
+
+use crate::core::condition::Condition;
+use crate::core::icache::InstructionCache;
+use crate::core::instruction::Instruction;
+use crate::Processor;
+use std::collections::HashMap;
+
+///
+/// Outcomes of a conditional instruction
+///
+#[derive(PartialEq, Debug, Default, Copy, Clone)]
+pub struct BranchCoverage {
+    /// times the condition passed, or the branch was taken
+    pub taken: u64,
+    /// times the condition failed
+    pub not_taken: u64,
+}
+
+///
+/// Coverage of a simulation run
+///
+#[derive(Debug, Default, Clone)]
+pub struct Coverage {
+    hits: HashMap<u32, u64>,
+    branches: HashMap<u32, BranchCoverage>,
+}
+
+impl Coverage {
+    ///
+    /// Create an empty coverage record
+    ///
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    ///
+    /// Execution counts of the instruction addresses
+    ///
+    #[must_use]
+    pub fn hits(&self) -> &HashMap<u32, u64> {
+        &self.hits
+    }
+
+    ///
+    /// Outcomes of the executed conditional instructions
+    ///
+    #[must_use]
+    pub fn branches(&self) -> &HashMap<u32, BranchCoverage> {
+        &self.branches
+    }
+
+    fn update(&mut self, pc: u32, branch: Option<bool>) {
+        *self.hits.entry(pc).or_default() += 1;
+        if let Some(taken) = branch {
+            let branch = self.branches.entry(pc).or_default();
+            if taken {
+                branch.taken += 1;
+            } else {
+                branch.not_taken += 1;
+            }
+        }
+    }
+}
+
+///
+/// Trait for recording the code coverage of the processor
+///
+pub trait CoverageRecording {
+    ///
+    /// Record an instruction executed at `pc`. `taken` tells if its condition
+    /// passed and `branched` if it wrote the pc.
+    ///
+    fn coverage_update(
+        &mut self,
+        instruction: &Instruction,
+        pc: u32,
+        in_it_block: bool,
+        taken: bool,
+        branched: bool,
+    );
+
+    ///
+    /// Coverage so far, if recording is enabled
+    ///
+    fn code_coverage(&self) -> Option<&Coverage>;
+
+    ///
+    /// Addresses of the conditional instructions decoded from `len` bytes of
+    /// code at `address`, executed or not. Decoding stops at the first
+    /// address outside executable memory.
+    ///
+    fn conditional_instructions(&mut self, address: u32, len: usize) -> Vec<u32>;
+}
+
+impl CoverageRecording for Processor {
+    #[inline(always)]
+    fn coverage_update(
+        &mut self,
+        instruction: &Instruction,
+        pc: u32,
+        in_it_block: bool,
+        taken: bool,
+        branched: bool,
+    ) {
+        if let Some(coverage) = &mut self.coverage {
+            let branch = match instruction {
+                Instruction::B_t13 { cond, .. } if *cond != Condition::AL => Some(branched),
+                Instruction::CBZ { .. } => Some(branched),
+                _ if in_it_block => Some(taken),
+                _ => None,
+            };
+            coverage.update(pc, branch);
+        }
+    }
+
+    fn code_coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+
+    fn conditional_instructions(&mut self, address: u32, len: usize) -> Vec<u32> {
+        let end = u64::from(address) + len as u64;
+        let mut conditionals = Vec::new();
+        let mut it_remaining = 0;
+        let mut pc = u64::from(address);
+        while pc < end {
+            let Ok((instruction, size)) = self.fetch_decoded(pc as u32) else {
+                break;
+            };
+            let conditional = match instruction {
+                Instruction::B_t13 { cond, .. } if cond != Condition::AL => true,
+                Instruction::CBZ { .. } => true,
+                _ => it_remaining > 0,
+            };
+            if conditional {
+                conditionals.push(pc as u32);
+            }
+            it_remaining = match instruction {
+                Instruction::IT { x, y, z, .. } => {
+                    1 + [x, y, z].iter().filter(|cond| cond.is_some()).count()
+                }
+                _ => it_remaining.saturating_sub(1),
+            };
+            pc += size as u64;
+        }
+        conditionals
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::core::register::Reg;
+
+    #[test]
+    fn test_branch_coverage() {
+        let mut processor = Processor::new();
+        processor.coverage(Some(Coverage::new()));
+
+        let beq = Instruction::B_t13 {
+            cond: Condition::EQ,
+            imm32: 8,
+            thumb32: false,
+        };
+        let cbz = Instruction::CBZ {
+            rn: Reg::R0,
+            nonzero: false,
+            imm32: 4,
+        };
+        let nop = Instruction::NOP { thumb32: false };
+
+        processor.coverage_update(&beq, 0x100, false, true, true);
+        processor.coverage_update(&beq, 0x100, false, false, false);
+        // CBZ that does not branch still completes
+        processor.coverage_update(&cbz, 0x102, false, true, false);
+        processor.coverage_update(&nop, 0x104, true, false, false);
+        processor.coverage_update(&nop, 0x106, false, true, false);
+
+        let coverage = processor.code_coverage().unwrap();
+        assert_eq!(coverage.hits()[&0x100], 2);
+        assert_eq!(
+            coverage.branches()[&0x100],
+            BranchCoverage {
+                taken: 1,
+                not_taken: 1
+            }
+        );
+        assert_eq!(
+            coverage.branches()[&0x102],
+            BranchCoverage {
+                taken: 0,
+                not_taken: 1
+            }
+        );
+        assert_eq!(coverage.branches()[&0x104].not_taken, 1);
+        assert_eq!(coverage.hits()[&0x106], 1);
+        assert!(!coverage.branches().contains_key(&0x106));
+    }
+
+    #[test]
+    fn test_conditional_instructions() {
+        let mut processor = Processor::new();
+        processor.memory_regions(&["ram,base=0x20000000,size=1K".parse().unwrap()]);
+        processor.cache_instructions();
+
+        let code: [u16; 6] = [
+            0xd001, // beq
+            0xb108, // cbz r0
+            0xe7fe, // b .
+            0xbf0c, // ite eq
+            0x2001, // moveq r0, #1
+            0x2000, // movne r0, #0
+        ];
+        for (i, halfword) in code.iter().enumerate() {
+            processor
+                .write16(0x2000_0000 + 2 * i as u32, *halfword)
+                .unwrap();
+        }
+        processor.write16(0x2000_000c, 0x4770).unwrap(); // bx lr
+
+        assert_eq!(
+            processor.conditional_instructions(0x2000_0000, 14),
+            vec![0x2000_0000, 0x2000_0002, 0x2000_0008, 0x2000_000a]
+        );
+    }
+}
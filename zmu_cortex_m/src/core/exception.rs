@@ -6,8 +6,11 @@
 use crate::bus::Bus;
 use crate::core::bits::Bits;
 use crate::core::fault::Fault;
+use crate::core::memcheck::Memchecking;
+use crate::core::profile::Profiling;
 use crate::core::register::{BaseReg, Ipsr, Reg};
 use crate::core::reset::Reset;
+use crate::core::sleep::Sleep;
 use crate::peripheral::nvic::NVIC;
 use crate::Processor;
 use crate::ProcessorMode;
@@ -84,6 +87,26 @@ pub trait ExceptionHandling {
     ///          
     fn exception_active(&self, exception: Exception) -> bool;
 
+    ///
+    /// Set the active state of an exception directly, as done via SHCSR writes.
+    ///
+    fn set_exception_active(&mut self, exception: Exception, active: bool);
+
+    ///
+    /// Check if given exception is currently pending
+    ///
+    fn exception_pending(&self, exception: Exception) -> bool;
+
+    ///
+    /// Enter the exception handling a synchronous fault.
+    ///
+    /// Fault status registers are updated and the fault is escalated to HardFault
+    /// in case the configurable fault handler is disabled or cannot preempt the
+    /// current execution. A fault in the HardFault or NMI handler locks up
+    /// the processor, which stops the simulation.
+    ///
+    fn fault_entry(&mut self, fault: Fault, return_address: u32) -> Result<(), Fault>;
+
     ///
     /// Set priority of an exception. Smaller priority number has higher urgency.
     ///          
@@ -117,8 +140,23 @@ trait ExceptionHandlingHelpers {
     fn push_stack(&mut self, exception_type: Exception, return_address: u32) -> Result<(), Fault>;
     fn pop_stack(&mut self, frameptr: u32, exc_return: u32) -> Result<(), Fault>;
     fn exception_active_bit_count(&self) -> usize;
+    fn fault_exception(&mut self, fault: Fault) -> Exception;
 }
 
+const CCR_STKALIGN: usize = 9;
+
+#[cfg(any(armv7m, armv7em))]
+const SHCSR_MEMFAULTENA: usize = 16;
+#[cfg(any(armv7m, armv7em))]
+const SHCSR_BUSFAULTENA: usize = 17;
+#[cfg(any(armv7m, armv7em))]
+const SHCSR_USGFAULTENA: usize = 18;
+
+#[cfg(any(armv7m, armv7em))]
+const HFSR_VECTTBL: usize = 1;
+#[cfg(any(armv7m, armv7em))]
+const HFSR_FORCED: usize = 30;
+
 #[derive(PartialEq, Debug, Copy, Clone)]
 ///
 /// List of supported Exceptions
@@ -175,11 +213,13 @@ impl ExceptionHandlingHelpers for Processor {
 
         self.execution_priority = self.get_execution_priority();
 
-        // SetEventRegister();
+        self.set_event();
         // InstructionSynchronizationBarrier();
         let vtor = self.vtor;
         let offset: u32 = usize::from(exception) as u32 * 4;
-        let start = self.read32(vtor + offset)?;
+        let start = self
+            .read32(vtor + offset)
+            .map_err(|_| Fault::VectorTable)?;
         self.blx_write_pc(start);
         Ok(())
     }
@@ -216,18 +256,59 @@ impl ExceptionHandlingHelpers for Processor {
             .filter(|&(_, exp)| exp.active)
             .fold(0, |acc, _| acc + 1)
     }
+    #[cfg(armv6m)]
+    fn fault_exception(&mut self, _fault: Fault) -> Exception {
+        // all faults are mapped to hardfaults on armv6m
+        Exception::HardFault
+    }
+
+    #[cfg(any(armv7m, armv7em))]
+    fn fault_exception(&mut self, fault: Fault) -> Exception {
+        // (exception, enable bit in SHCSR, status bit in CFSR)
+        let (exception, enable, status) = match fault {
+            Fault::VectorTable => {
+                self.hfsr.set_bit(HFSR_VECTTBL, true);
+                return Exception::HardFault;
+            }
+            Fault::Forced => {
+                self.hfsr.set_bit(HFSR_FORCED, true);
+                return Exception::HardFault;
+            }
+            Fault::Msunskerr => (Exception::MemoryManagementFault, SHCSR_MEMFAULTENA, 3),
+            Fault::Mstkerr => (Exception::MemoryManagementFault, SHCSR_MEMFAULTENA, 4),
+            Fault::IAccViol => (Exception::BusFault, SHCSR_BUSFAULTENA, 8),
+            Fault::DAccViol => (Exception::BusFault, SHCSR_BUSFAULTENA, 9),
+            Fault::Stkerr => (Exception::BusFault, SHCSR_BUSFAULTENA, 12),
+            Fault::UndefInstr => (Exception::UsageFault, SHCSR_USGFAULTENA, 16),
+            Fault::Invstate => (Exception::UsageFault, SHCSR_USGFAULTENA, 17),
+            Fault::InvPc => (Exception::UsageFault, SHCSR_USGFAULTENA, 18),
+            Fault::Unaligned => (Exception::UsageFault, SHCSR_USGFAULTENA, 24),
+            Fault::DivByZero => (Exception::UsageFault, SHCSR_USGFAULTENA, 25),
+        };
+        self.cfsr.set_bit(status, true);
+
+        if self.shcsr.get_bit(enable)
+            && self.get_exception_priority(exception) < self.execution_priority
+        {
+            exception
+        } else {
+            self.hfsr.set_bit(HFSR_FORCED, true);
+            Exception::HardFault
+        }
+    }
+
     fn return_address(&self, exception_type: Exception, return_address: u32) -> u32 {
         match exception_type {
             Exception::NMI
             | Exception::HardFault
             | Exception::MemoryManagementFault
             | Exception::BusFault
+            | Exception::UsageFault
             | Exception::SVCall
             | Exception::DebugMonitor
             | Exception::PendSV
             | Exception::SysTick
             | Exception::Interrupt { .. } => return_address,
-            Exception::UsageFault => return_address - 4,
             _ => panic!("unsupported exception"),
         }
     }
@@ -235,9 +316,8 @@ impl ExceptionHandlingHelpers for Processor {
         const FRAME_SIZE: u32 = 0x20;
 
         //TODO FP extensions
-        //TODO forcealign
-        // forces 8 byte alignment on the stack
-        let forcealign = true;
+        // CCR.STKALIGN forces 8 byte alignment on the stack
+        let forcealign = self.ccr.get_bit(CCR_STKALIGN);
         let spmask = ((forcealign as u32) << 2) ^ 0xFFFF_FFFF;
 
         let (frameptr, frameptralign) =
@@ -246,7 +326,7 @@ impl ExceptionHandlingHelpers for Processor {
                 self.set_psp((self.psp.wrapping_sub(FRAME_SIZE)) & spmask);
                 (self.psp, align)
             } else {
-                let align = self.msp.get_bit(2) as u32;
+                let align = (self.msp.get_bit(2) & forcealign) as u32;
                 self.set_msp((self.msp.wrapping_sub(FRAME_SIZE)) & spmask);
                 (self.msp, align)
             };
@@ -270,6 +350,7 @@ impl ExceptionHandlingHelpers for Processor {
         let xpsr = (self.psr.value & 0b1111_1111_1111_1111_1111_1101_1111_1111)
             | (frameptralign << 9) as u32;
         self.write32(frameptr.wrapping_add(0x1c), xpsr)?;
+        self.memcheck_exception_entry(frameptr);
 
         if self.mode == ProcessorMode::HandlerMode {
             self.lr = 0xFFFF_FFF1;
@@ -286,8 +367,7 @@ impl ExceptionHandlingHelpers for Processor {
 
         const FRAME_SIZE: u32 = 0x20;
 
-        //let forcealign = ccr.stkalign;
-        let forcealign = true;
+        let forcealign = self.ccr.get_bit(CCR_STKALIGN);
 
         let r0 = self.read32(frameptr)?;
         self.set_r(Reg::R0, r0);
@@ -344,6 +424,25 @@ impl ExceptionHandling for Processor {
         self.exceptions[&usize::from(exception)].active
     }
 
+    fn set_exception_active(&mut self, exception: Exception, active: bool) {
+        self.exceptions.get_mut(&exception.into()).unwrap().active = active;
+        self.execution_priority = self.get_execution_priority();
+    }
+
+    fn exception_pending(&self, exception: Exception) -> bool {
+        self.exceptions[&usize::from(exception)].pending
+    }
+
+    fn fault_entry(&mut self, fault: Fault, return_address: u32) -> Result<(), Fault> {
+        if self.execution_priority < 0 {
+            self.lockup = Some(return_address);
+            self.state.set_bit(0, false);
+            return Ok(());
+        }
+        let exception = self.fault_exception(fault);
+        self.exception_entry(exception, return_address)
+    }
+
     fn set_exception_priority(&mut self, exception: Exception, priority: u8) {
         self.exceptions.get_mut(&exception.into()).unwrap().priority = i16::from(priority);
     }
@@ -361,8 +460,11 @@ impl ExceptionHandling for Processor {
         for (_, exp) in self.exceptions.iter().filter(|&(_, e)| e.active) {
             if exp.priority < highestpri {
                 highestpri = exp.priority;
-                let subgroupvalue = highestpri % groupvalue;
-                highestpri -= subgroupvalue;
+                // the fixed negative priorities of NMI and HardFault are not grouped
+                if highestpri > 0 {
+                    let subgroupvalue = highestpri % groupvalue;
+                    highestpri -= subgroupvalue;
+                }
             }
         }
         if self.basepri != 0 {
@@ -393,6 +495,7 @@ impl ExceptionHandling for Processor {
         if !exp.pending {
             exp.pending = true;
             self.pending_exception_count += 1;
+            self.set_event_on_pend();
         }
     }
 
@@ -435,7 +538,9 @@ impl ExceptionHandling for Processor {
                 self.nvic_unpend_interrupt(n);
             }
             self.push_stack(exception, return_address)?;
-            self.exception_taken(exception)
+            self.exception_taken(exception)?;
+            self.profile_exception_entry(return_address);
+            Ok(())
         }
     }
 
@@ -486,7 +591,10 @@ impl ExceptionHandling for Processor {
             }
 
             self.deactivate(returning_exception_number);
+            self.set_event();
+            self.memcheck_exception_return(frameptr);
             self.pop_stack(frameptr, exc_return)?;
+            self.profile_exception_return();
             if self.mode == ProcessorMode::HandlerMode && self.psr.get_isr_number() == 0 {
                 //ufsr.invpc = true;
                 self.push_stack(Exception::UsageFault, exc_return)?; // to negate pop_stack
@@ -505,7 +613,8 @@ impl ExceptionHandling for Processor {
                 && nested_activation == 1 // deactivate() reduced one
                 && self.scr.get_bit(1)
             {
-                self.state.set_bit(1, true); // sleeping = true
+                // sleep-on-exit
+                self.sleep(false);
             }
 
             Ok(())
@@ -517,7 +626,7 @@ impl ExceptionHandling for Processor {
     #[inline(always)]
     fn check_exceptions(&mut self) {
         if let Some(exception) = self.get_pending_exception() {
-            self.state.set_bit(1, false); // sleeping == false
+            self.wake_up();
             self.clear_pending_exception(exception);
             let pc = self.get_pc();
             // TODO: handle failure to enter exception
@@ -582,6 +691,7 @@ mod tests {
     use crate::core::exception::ExceptionHandling;
     #[cfg(any(armv7m, armv7em))]
     use crate::core::executor::Executor;
+    use crate::peripheral::scb::SystemControlBlock;
     #[cfg(any(armv7m, armv7em))]
     use crate::core::instruction::Instruction;
 
@@ -606,7 +716,7 @@ mod tests {
             core.psr.value = 0xffff_ffff;
 
             // act
-            core.push_stack(Self::HardFault, 99).unwrap();
+            core.push_stack(Exception::HardFault, 99).unwrap();
 
             assert_eq!(core.msp, STACK_START - 32);
             core.get_r(Reg::LR)
@@ -627,6 +737,46 @@ mod tests {
         assert_eq!(lr, 0xffff_fff9);
     }
 
+    #[test]
+    fn test_push_pop_stack_realign() {
+        const STACK_START: u32 = 0x2000_0104;
+        let mut core = Processor::new();
+        core.set_msp(STACK_START);
+        core.psr.value = 0;
+
+        // act
+        core.push_stack(Exception::HardFault, 99).unwrap();
+
+        // assert: frame is 8 byte aligned and xPSR[9] marks the padding
+        assert_eq!(core.get_msp(), STACK_START - 0x24);
+        assert_eq!(core.read32(STACK_START - 0x24 + 28).unwrap(), 1 << 9);
+
+        // act
+        let frameptr = core.get_msp();
+        core.pop_stack(frameptr, 0xFFFF_FFF9).unwrap();
+
+        // assert
+        assert_eq!(core.get_msp(), STACK_START);
+        assert_eq!(core.psr.value, 0);
+    }
+
+    #[cfg(any(armv7m, armv7em))]
+    #[test]
+    fn test_push_stack_no_stkalign() {
+        const STACK_START: u32 = 0x2000_0104;
+        let mut core = Processor::new();
+        core.write_ccr(0);
+        core.set_msp(STACK_START);
+        core.psr.value = 0;
+
+        // act
+        core.push_stack(Exception::HardFault, 99).unwrap();
+
+        // assert
+        assert_eq!(core.get_msp(), STACK_START - 0x20);
+        assert_eq!(core.read32(STACK_START - 0x20 + 28).unwrap(), 0);
+    }
+
     #[test]
     fn test_exception_taken() {
         // Arrange
@@ -646,6 +796,20 @@ mod tests {
         assert_eq!(core.exception_active(Exception::BusFault), true);
     }
 
+    #[test]
+    fn test_exception_taken_uses_vtor() {
+        // Arrange
+        let mut processor = Processor::new();
+        processor.write32(0x2000_0100 + 15 * 4, 0x0800_1235).unwrap();
+        processor.write_vtor(0x2000_0100);
+
+        // Act
+        processor.exception_taken(Exception::SysTick).unwrap();
+
+        // Assert
+        assert_eq!(processor.get_pc(), 0x0800_1234);
+    }
+
     #[test]
     fn test_exception_priority() {
         // Arrange
@@ -705,6 +869,59 @@ mod tests {
         assert_eq!(processor.get_pending_exception(), None);
     }
 
+    #[cfg(any(armv7m, armv7em))]
+    #[test]
+    fn test_fault_escalation() {
+        // Arrange
+        let mut processor = Processor::new();
+        processor.reset().unwrap();
+        processor.set_msp(0x2000_1000);
+
+        // Act
+        processor.fault_entry(Fault::DivByZero, 0).unwrap();
+
+        // Assert
+        assert!(processor.exception_active(Exception::HardFault));
+        assert_eq!(processor.cfsr, 1 << 25);
+        assert_eq!(processor.hfsr, 1 << 30);
+    }
+
+    #[test]
+    fn test_lockup() {
+        // Arrange
+        let mut processor = Processor::new();
+        processor.reset().unwrap();
+        processor.set_msp(0x2000_1000);
+        processor.state.set_bit(0, true);
+        processor.fault_entry(Fault::Forced, 0x100).unwrap();
+
+        // Act
+        processor.fault_entry(Fault::Forced, 0x200).unwrap();
+
+        // Assert
+        assert_eq!(processor.lockup, Some(0x200));
+        assert_eq!(processor.state & 1, 0);
+    }
+
+    #[cfg(any(armv7m, armv7em))]
+    #[test]
+    fn test_fault_enabled() {
+        // Arrange
+        let mut processor = Processor::new();
+        processor.reset().unwrap();
+        processor.set_msp(0x2000_1000);
+        processor.write_shcsr(1 << 18); // USGFAULTENA
+
+        // Act
+        processor.fault_entry(Fault::DivByZero, 0).unwrap();
+
+        // Assert
+        assert!(processor.exception_active(Exception::UsageFault));
+        assert!(!processor.exception_active(Exception::HardFault));
+        assert_eq!(processor.cfsr, 1 << 25);
+        assert_eq!(processor.hfsr, 0);
+    }
+
     #[test]
     fn test_exception_entry_clears_nvic() {
         // Arrange
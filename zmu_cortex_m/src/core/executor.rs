@@ -5,14 +5,20 @@
 use crate::bus::Bus;
 use crate::core::bits::Bits;
 use crate::core::condition::Condition;
+use crate::core::coverage::CoverageRecording;
 use crate::core::exception::Exception;
 use crate::core::exception::ExceptionHandling;
 use crate::core::fault::Fault;
+use crate::core::icache::InstructionCache;
+use crate::core::memcheck::Memchecking;
+use crate::core::profile::Profiling;
+use crate::core::stack::StackMonitoring;
 use crate::core::instruction::{Imm32Carry, Instruction, SRType, SetFlags};
 use crate::core::operation::condition_test;
 use crate::core::operation::{add_with_carry, ror, shift, shift_c, sign_extend};
 use crate::core::register::{Apsr, BaseReg, Reg};
-use crate::memory::map::MapMemory;
+use crate::core::sleep::Sleep;
+use crate::device::peripheral::Peripherals;
 use crate::peripheral::dwt::Dwt;
 use crate::peripheral::systick::SysTick;
 use crate::semihosting::decode_semihostcmd;
@@ -312,7 +318,9 @@ impl ExecutorHelper for Processor {
             }
             #[cfg(armv6m)]
             Instruction::CPS { im } => {
-                if !im {
+                if !self.current_mode_is_privileged() {
+                    // CPS is ignored in unprivileged mode
+                } else if !im {
                     self.primask = false;
                 } else {
                     self.primask = true;
@@ -327,7 +335,9 @@ impl ExecutorHelper for Processor {
                 affect_pri,
                 affect_fault,
             } => {
-                if *im {
+                if !self.current_mode_is_privileged() {
+                    // CPS is ignored in unprivileged mode
+                } else if *im {
                     if *affect_pri {
                         self.primask = true;
                     }
@@ -397,15 +407,19 @@ impl ExecutorHelper for Processor {
                                 value.set_bits(27..32, self.psr.value.get_bits(27..32));
                             }
                         }
-                        0b00001 => match sysm.get_bits(0..3) {
-                            0 => {
-                                value = self.msp;
-                            }
-                            1 => {
-                                value = self.psp;
+                        0b00001 => {
+                            if self.current_mode_is_privileged() {
+                                match sysm.get_bits(0..3) {
+                                    0 => {
+                                        value = self.msp;
+                                    }
+                                    1 => {
+                                        value = self.psp;
+                                    }
+                                    _ => (),
+                                }
                             }
-                            _ => (),
-                        },
+                        }
                         0b00010 => match sysm.get_bits(0..3) {
                             0b000 => {
                                 value.set_bit(0, self.primask);
@@ -421,9 +435,7 @@ impl ExecutorHelper for Processor {
                                 value.set_bit(0, self.faultmask);
                             }
                             0b100 => {
-                                //let ctrl = u8::from(self.control) as u32;
-                                //value.set_bits(0..2, ctrl);
-                                panic!("unimplemented CONTROL");
+                                value = u32::from(u8::from(self.control));
                             }
                             _ => (),
                         },
@@ -451,11 +463,18 @@ impl ExecutorHelper for Processor {
                                 }
                             }
                         }
-                        0b00001 => match sysm.get_bits(0..3) {
-                            0 => self.msp = r_n,
-                            1 => self.psp = r_n,
-                            _ => (),
-                        },
+                        0b00001 => {
+                            if self.current_mode_is_privileged() {
+                                match sysm.get_bits(0..3) {
+                                    0 => self.msp = r_n & 0xffff_fffc,
+                                    1 => self.psp = r_n & 0xffff_fffc,
+                                    _ => (),
+                                }
+                            }
+                        }
+                        0b00010 if !self.current_mode_is_privileged() => {
+                            // priority masks and CONTROL are ignored in unprivileged mode
+                        }
                         0b00010 => match sysm.get_bits(0..3) {
                             0b000 => {
                                 self.primask = r_n.get_bit(0);
@@ -467,7 +486,7 @@ impl ExecutorHelper for Processor {
                             }
                             0b010 => {
                                 let low_rn = r_n.get_bits(0..8) as u8;
-                                if low_rn != 0 && low_rn < self.basepri || self.basepri == 0 {
+                                if low_rn != 0 && (low_rn < self.basepri || self.basepri == 0) {
                                     self.basepri = low_rn;
                                     self.execution_priority = self.get_execution_priority();
                                 }
@@ -484,6 +503,10 @@ impl ExecutorHelper for Processor {
                                 if self.mode == ProcessorMode::ThreadMode {
                                     self.control.sp_sel = r_n.get_bit(1);
                                 }
+                                #[cfg(armv7em)]
+                                {
+                                    self.control.fpca = r_n.get_bit(2);
+                                }
                             }
                             _ => (),
                         },
@@ -2332,23 +2355,35 @@ impl ExecutorHelper for Processor {
                 }
                 Ok(ExecuteResult::NotTaken)
             }
-            Instruction::SVC { imm32 } => {
+            Instruction::SVC { .. } => {
                 if self.condition_passed() {
-                    println!("SVC {}", imm32);
+                    // SVCall that cannot preempt the current execution escalates to HardFault
+                    if self.get_exception_priority(Exception::SVCall) >= self.execution_priority {
+                        return Err(Fault::Forced);
+                    }
+                    self.set_exception_pending(Exception::SVCall);
                     return Ok(ExecuteResult::Taken { cycles: 1 });
                 }
                 Ok(ExecuteResult::NotTaken)
             }
             Instruction::SEV { .. } => {
                 if self.condition_passed() {
-                    println!("SEV");
+                    self.set_event();
+                    return Ok(ExecuteResult::Taken { cycles: 1 });
+                }
+                Ok(ExecuteResult::NotTaken)
+            }
+            Instruction::WFE { .. } => {
+                if self.condition_passed() {
+                    if !self.clear_event() && self.get_pending_exception() == None {
+                        self.sleep(true);
+                    }
                     return Ok(ExecuteResult::Taken { cycles: 1 });
                 }
                 Ok(ExecuteResult::NotTaken)
             }
-            Instruction::WFE { .. } | Instruction::YIELD { .. } => {
+            Instruction::YIELD { .. } => {
                 if self.condition_passed() {
-                    //TODO
                     return Ok(ExecuteResult::Taken { cycles: 1 });
                 }
                 Ok(ExecuteResult::NotTaken)
@@ -2356,7 +2391,7 @@ impl ExecutorHelper for Processor {
             Instruction::WFI { .. } => {
                 if self.condition_passed() {
                     if self.get_pending_exception() == None {
-                        self.state.set_bit(1, true); // sleeping == true
+                        self.sleep(false);
                     }
                     return Ok(ExecuteResult::Taken { cycles: 1 });
                 }
@@ -2589,7 +2624,9 @@ impl ExecutorHelper for Processor {
 impl Executor for Processor {
     #[inline(always)]
     fn step_sleep(&mut self) {
+        self.cycle_count += 1;
         self.syst_step(1);
+        self.peripherals_tick(1);
         self.check_exceptions();
         self.dwt_tick(1);
     }
@@ -2597,12 +2634,19 @@ impl Executor for Processor {
     #[inline(always)]
     fn step(&mut self) {
         let pc = self.get_pc();
-        let mapped_pc = (self.map_address(pc) >> 1) as usize;
-        let (instruction, instruction_size) = self.instruction_cache[mapped_pc];
-        let count = self.execute(&instruction, instruction_size);
+        let count = match self.fetch_decoded(pc) {
+            Ok((instruction, instruction_size)) => self.execute(&instruction, instruction_size),
+            Err(fault) => {
+                self.fault_entry(fault, pc)
+                    .expect("error handling on exception entry not implemented");
+                //TODO: proper amount of cycles calcuation
+                12
+            }
+        };
         self.cycle_count += u64::from(count);
         self.dwt_tick(count);
         self.syst_step(count);
+        self.peripherals_tick(count);
         self.check_exceptions();
         //TODO exception entry also burns cycles that should be accounted for
         //DWT and SYST ticking
@@ -2613,15 +2657,22 @@ impl Executor for Processor {
         self.instruction_count += 1;
 
         let in_it_block = self.in_it_block();
-
-        match self.execute_internal(&instruction) {
-            Err(_fault) => {
-                // all faults are mapped to hardfaults on armv6m
+        let pc = self.pc;
+        self.memcheck_before(instruction, in_it_block);
+        self.profile_before(pc);
+
+        let result = self.execute_internal(&instruction);
+        let taken = match result {
+            Ok(ExecuteResult::Taken { .. }) | Ok(ExecuteResult::Branched { .. }) => true,
+            Ok(ExecuteResult::NotTaken) | Err(_) => false,
+        };
+        let branched = matches!(result, Ok(ExecuteResult::Branched { .. }));
+        let cycles = match result {
+            Err(fault) => {
                 let new_pc = self.get_pc();
 
-                //TODO: map to correct exception
                 //TODO: cycles not correctly accumulated yet for exception entry
-                self.exception_entry(Exception::HardFault, new_pc)
+                self.fault_entry(fault, new_pc)
                     .expect("error handling on exception entry not implemented");
                 //TODO: proper amount of cycles calcuation
                 12
@@ -2642,7 +2693,12 @@ impl Executor for Processor {
                 }
                 cycles
             }
-        }
+        };
+        self.memcheck_after(instruction_size, taken, result.is_err());
+        self.stack_monitor_update(instruction, pc);
+        self.profile_instruction(instruction, pc, cycles, branched);
+        self.coverage_update(instruction, pc, in_it_block, taken, branched);
+        cycles
     }
 }
 
@@ -2652,6 +2708,7 @@ mod tests {
     use crate::core::condition::Condition;
     use crate::core::instruction::instruction_size;
     use crate::core::instruction::{ITCondition, SetFlags};
+    use crate::core::reset::Reset;
 
     #[test]
     fn test_udiv() {
@@ -2676,6 +2733,58 @@ mod tests {
         assert_eq!(core.get_r(Reg::R1), 0x3);
     }
 
+    #[test]
+    fn test_big_endian_data() {
+        let mut core = Processor::new();
+        core.big_endian(true);
+        for (i, byte) in [0x12, 0x34, 0x56, 0x78].iter().enumerate() {
+            core.write8(0x2000_0000 + i as u32, *byte).unwrap();
+        }
+        core.set_r(Reg::R1, 0x2000_0000);
+
+        let load = Instruction::LDR_imm {
+            rt: Reg::R0,
+            rn: Reg::R1,
+            imm32: 0,
+            index: true,
+            add: true,
+            wback: false,
+            thumb32: false,
+        };
+        core.execute_internal(&load).unwrap();
+        assert_eq!(core.get_r(Reg::R0), 0x1234_5678);
+
+        // REV converts the big-endian view to the little-endian one
+        core.execute_internal(&Instruction::REV {
+            rd: Reg::R2,
+            rm: Reg::R0,
+            thumb32: false,
+        })
+        .unwrap();
+        core.big_endian(false);
+        core.execute_internal(&load).unwrap();
+        assert_eq!(core.get_r(Reg::R0), core.get_r(Reg::R2));
+
+        core.big_endian(true);
+        core.write16(0x2000_0004, 0xabcd).unwrap();
+        assert_eq!(core.read8(0x2000_0004).unwrap(), 0xab);
+        assert_eq!(core.read16(0x2000_0004).unwrap(), 0xabcd);
+
+        // the private peripheral bus stays little-endian
+        core.write32(0xE000_ED08, 0x0000_0100).unwrap();
+        assert_eq!(core.vtor, 0x0000_0100);
+        assert_eq!(core.read32(0xE000_ED0C).unwrap() & (1 << 15), 1 << 15);
+
+        // so do the bit-band aliases
+        #[cfg(any(armv7m, armv7em))]
+        {
+            core.write32(0x2000_0008, 0).unwrap();
+            core.write32(0x2200_0100, 1).unwrap();
+            assert_eq!(core.read8(0x2000_0008).unwrap(), 0x01);
+            assert_eq!(core.read32(0x2200_0100).unwrap(), 1);
+        }
+    }
+
     #[test]
     fn test_mla() {
         // arrange
@@ -2827,6 +2936,94 @@ mod tests {
         assert_eq!(core.get_r(Reg::R6), 0);
     }
 
+    #[test]
+    fn test_svc() {
+        // arrange
+        let mut core = Processor::new();
+        core.reset().unwrap();
+        core.set_msp(0x2000_0100);
+
+        // act
+        core.execute(&Instruction::SVC { imm32: 0 }, 2);
+
+        // assert
+        assert!(core.exception_pending(Exception::SVCall));
+
+        // arrange
+        core.clear_pending_exception(Exception::SVCall);
+        core.primask = true;
+        core.execution_priority = core.get_execution_priority();
+
+        // act: SVCall is masked by PRIMASK
+        core.execute(&Instruction::SVC { imm32: 0 }, 2);
+
+        // assert
+        assert!(!core.exception_pending(Exception::SVCall));
+        assert!(core.exception_active(Exception::HardFault));
+    }
+
+    #[test]
+    fn test_unprivileged_msr_mrs() {
+        // arrange
+        let mut core = Processor::new();
+        core.psr.value = 0;
+        core.set_r(Reg::R0, 0b11);
+        core.set_r(Reg::R1, 1);
+        core.set_psp(0x2000_0100);
+
+        // act: drop privileges and switch to PSP
+        core.execute_internal(&Instruction::MSR_reg {
+            rn: Reg::R0,
+            sysm: 0b10100,
+            mask: 0,
+        })
+        .unwrap();
+
+        // act: privileged only registers are ignored
+        core.execute_internal(&Instruction::MSR_reg {
+            rn: Reg::R1,
+            sysm: 0b10000,
+            mask: 0,
+        })
+        .unwrap();
+        core.execute_internal(&Instruction::MSR_reg {
+            rn: Reg::R1,
+            sysm: 0b10100,
+            mask: 0,
+        })
+        .unwrap();
+        core.execute_internal(&Instruction::MRS {
+            rd: Reg::R2,
+            sysm: 0b10100,
+        })
+        .unwrap();
+        core.execute_internal(&Instruction::MRS {
+            rd: Reg::R3,
+            sysm: 0b01001,
+        })
+        .unwrap();
+
+        // assert
+        assert!(!core.current_mode_is_privileged());
+        assert!(!core.primask);
+        assert_eq!(core.get_r(Reg::R2), 0b11);
+        assert_eq!(core.get_r(Reg::R3), 0);
+        assert_eq!(core.get_r(Reg::SP), 0x2000_0100);
+    }
+
+    #[test]
+    fn test_unprivileged_scs_access_faults() {
+        // arrange
+        let mut core = Processor::new();
+        core.control.n_priv = true;
+
+        // act & assert
+        assert_eq!(core.read32(0xE000_ED08), Err(Fault::DAccViol));
+        assert_eq!(core.write32(0xE000_ED08, 0), Err(Fault::DAccViol));
+        assert_eq!(core.write32(0xE000_0000, 0x41), Ok(()));
+        assert_eq!(core.write32(0x2000_0000, 0x41), Ok(()));
+    }
+
     #[test]
     fn test_smlabb() {
         // arrange
@@ -2,7 +2,7 @@
 //! Fetching instructions for execution
 //!
 //!
-use crate::bus::Bus;
+use crate::bus::BusInternal;
 use crate::core::fault::Fault;
 use crate::core::thumb::ThumbCode;
 use crate::decoder::is_thumb32;
@@ -21,10 +21,10 @@ impl Fetch for Processor {
     // PC location. Depending on instruction type, fetches
     // one or two half-words.
     fn fetch(&self, pc: u32) -> Result<ThumbCode, Fault> {
-        let hw = self.read16(pc)?;
+        let hw = self.bus_read16(pc)?;
 
         if is_thumb32(hw) {
-            let hw2 = self.read16(pc + 2)?;
+            let hw2 = self.bus_read16(pc + 2)?;
             Ok(ThumbCode::Thumb32 {
                 opcode: (u32::from(hw) << 16) + u32::from(hw2),
             })
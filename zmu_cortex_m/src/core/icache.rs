@@ -0,0 +1,188 @@
+//!
+//! Cache of decoded instructions
+//!
+//! Instructions are decoded once and kept per half-word address of each
+//! executable memory. Writes to memory invalidate the affected entries so
+//! that code in RAM can be modified and executed again.
+//!
+
+use crate::core::fault::Fault;
+use crate::core::fetch::Fetch;
+use crate::core::instruction::{instruction_size, Instruction};
+use crate::decoder::Decoder;
+use crate::memory::map::MapMemory;
+use crate::Processor;
+
+///
+/// Decoded instructions of a single memory range
+///
+pub struct DecodeCache {
+    start_address: u32,
+    entries: Vec<Option<(Instruction, usize)>>,
+}
+
+impl DecodeCache {
+    ///
+    /// Create an empty cache for `size` bytes of memory starting from `start_address`
+    ///
+    #[must_use]
+    pub fn new(start_address: u32, size: usize) -> Self {
+        Self {
+            start_address,
+            entries: vec![None; size.div_ceil(2)],
+        }
+    }
+
+    ///
+    /// Check if address is covered by this cache
+    ///
+    #[must_use]
+    pub fn in_range(&self, address: u32) -> bool {
+        address >= self.start_address
+            && ((address - self.start_address) >> 1) < self.entries.len() as u32
+    }
+
+    ///
+    /// Get the cached instruction at given address, if any
+    ///
+    #[must_use]
+    pub fn get(&self, address: u32) -> Option<(Instruction, usize)> {
+        self.entries[((address - self.start_address) >> 1) as usize]
+    }
+
+    ///
+    /// Store a decoded instruction for given address
+    ///
+    pub fn insert(&mut self, address: u32, instruction: Instruction, size: usize) {
+        self.entries[((address - self.start_address) >> 1) as usize] = Some((instruction, size));
+    }
+
+    ///
+    /// Drop the entries of instructions that overlap `len` bytes from `address`.
+    /// A 32 bit instruction starting one half-word before `address` is included.
+    ///
+    pub fn invalidate(&mut self, address: u32, len: usize) {
+        let end = address.saturating_add(len as u32 - 1);
+        if end < self.start_address {
+            return;
+        }
+        let first = (address.saturating_sub(2).max(self.start_address) - self.start_address) >> 1;
+        let last = (end - self.start_address) >> 1;
+        for index in first..=last {
+            if let Some(entry) = self.entries.get_mut(index as usize) {
+                *entry = None;
+            }
+        }
+    }
+}
+
+///
+/// Fetching of instructions through the decode cache
+///
+pub trait InstructionCache {
+    ///
+    /// Fetch and decode the instruction at `pc`, using the cache when possible.
+    ///
+    /// # Errors
+    ///
+    /// Fails with `IAccViol` if the address is not in executable memory.
+    ///
+    fn fetch_decoded(&mut self, pc: u32) -> Result<(Instruction, usize), Fault>;
+
+    ///
+    /// Invalidate the cached instructions overlapping `len` bytes written at `address`.
+    ///
+    fn invalidate_decoded(&mut self, address: u32, len: usize);
+}
+
+impl InstructionCache for Processor {
+    #[inline(always)]
+    fn fetch_decoded(&mut self, pc: u32) -> Result<(Instruction, usize), Fault> {
+        let address = self.map_address(pc);
+        let index = self
+            .instruction_cache
+            .iter()
+            .position(|cache| cache.in_range(address))
+            .ok_or(Fault::IAccViol)?;
+
+        if let Some(decoded) = self.instruction_cache[index].get(address) {
+            return Ok(decoded);
+        }
+
+        let thumb = self.fetch(address).map_err(|_| Fault::IAccViol)?;
+        let instruction = self.decode(thumb);
+        let size = instruction_size(&instruction);
+        self.instruction_cache[index].insert(address, instruction, size);
+        Ok((instruction, size))
+    }
+
+    #[inline(always)]
+    fn invalidate_decoded(&mut self, address: u32, len: usize) {
+        if len == 0 {
+            return;
+        }
+        for cache in &mut self.instruction_cache {
+            if cache.in_range(address) || cache.in_range(address + len as u32 - 1) {
+                cache.invalidate(address, len);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::core::executor::Executor;
+    use crate::core::register::{BaseReg, Reg};
+
+    #[test]
+    fn test_invalidate_range() {
+        let mut cache = DecodeCache::new(0x2000_0000, 16);
+        for address in (0x2000_0000..0x2000_0010).step_by(2) {
+            cache.insert(address, Instruction::NOP { thumb32: false }, 2);
+        }
+
+        cache.invalidate(0x2000_0008, 2);
+
+        assert!(cache.get(0x2000_0004).is_some());
+        assert!(cache.get(0x2000_0006).is_none());
+        assert!(cache.get(0x2000_0008).is_none());
+        assert!(cache.get(0x2000_000A).is_some());
+
+        // invalidating at the very start must not underflow
+        cache.invalidate(0x2000_0000, 1);
+        assert!(cache.get(0x2000_0000).is_none());
+    }
+
+    #[test]
+    fn test_execute_from_ram_after_write() {
+        // Arrange
+        let mut processor = Processor::new();
+        processor.cache_instructions();
+
+        // movs r0, #1
+        processor.write16(0x2000_0000, 0x2001).unwrap();
+        processor.set_pc(0x2000_0000);
+        processor.step();
+        assert_eq!(processor.get_r(Reg::R0), 1);
+
+        // Act: patch the instruction to movs r0, #2
+        processor.write16(0x2000_0000, 0x2002).unwrap();
+        processor.set_pc(0x2000_0000);
+        processor.step();
+
+        // Assert
+        assert_eq!(processor.get_r(Reg::R0), 2);
+    }
+
+    #[test]
+    fn test_fetch_from_non_executable_memory() {
+        let mut processor = Processor::new();
+        processor.memory_regions(&["ram,base=0x20000000,size=1K,access=rw".parse().unwrap()]);
+        processor.cache_instructions();
+
+        assert_eq!(processor.fetch_decoded(0x2000_0000), Err(Fault::IAccViol));
+        assert_eq!(processor.fetch_decoded(0x6000_0000), Err(Fault::IAccViol));
+    }
+}
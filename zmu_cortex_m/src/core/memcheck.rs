@@ -0,0 +1,1109 @@
+//!
+//! Detection of uninitialised memory use and invalid memory accesses
+//!
+//! Memcheck keeps a shadow bit per byte of RAM telling if the byte has been
+//! written, and a shadow bit per register telling if its value is derived
+//! from memory that was never written. Uninitialised values can be copied
+//! around freely. An error is reported only when such a value decides a
+//! conditional branch, is used to form an address or is used as a jump
+//! target. Accesses to unmapped memory and accesses to the stack below the
+//! current stack pointer are reported as well.
+//!
+
+use crate::bus::trace::BusTracing;
+use crate::core::condition::Condition;
+use crate::core::instruction::{Instruction, SetFlags};
+use crate::core::register::{BaseReg, Reg};
+use crate::memory::map::MapMemory;
+use crate::Processor;
+use crate::ProcessorMode;
+use enum_set::EnumSet;
+use std::collections::HashSet;
+use std::fmt;
+use std::mem;
+
+// A larger jump of the stack pointer is taken as a switch to another stack.
+const STACK_SWITCH_DISTANCE: u32 = 0x1_0000;
+
+// Registers with an unknown value after reset: r0-r12.
+const RESET_UNDEFINED_REGISTERS: u16 = 0x1fff;
+
+// Registers restored from an exception frame: r0-r3, r12 and lr.
+const FRAME_REGISTERS: u16 = 0x500f;
+
+// The same registers in their order in the exception frame.
+const STACKED_REGISTERS: [Reg; 6] = [Reg::R0, Reg::R1, Reg::R2, Reg::R3, Reg::R12, Reg::LR];
+
+///
+/// Kinds of errors detected by memcheck
+///
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub enum MemcheckErrorKind {
+    /// a conditional branch or conditional execution depends on an uninitialised value
+    UninitialisedCondition,
+    /// an address is computed from an uninitialised value
+    UninitialisedAddress,
+    /// a jump target is an uninitialised value
+    UninitialisedJump,
+    /// access to an address with no memory or peripheral
+    UnmappedAccess {
+        /// accessed address
+        address: u32,
+        /// access size in bytes
+        size: u8,
+        /// true for writes
+        write: bool,
+    },
+    /// access to the stack below the stack pointer
+    BelowStackPointer {
+        /// accessed address
+        address: u32,
+        /// access size in bytes
+        size: u8,
+        /// true for writes
+        write: bool,
+        /// value of the stack pointer
+        sp: u32,
+    },
+}
+
+impl fmt::Display for MemcheckErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let access = |write: bool| if write { "write" } else { "read" };
+        match *self {
+            Self::UninitialisedCondition => {
+                write!(f, "Conditional jump or move depends on uninitialised value")
+            }
+            Self::UninitialisedAddress => write!(f, "Use of uninitialised value in address"),
+            Self::UninitialisedJump => write!(f, "Jump to uninitialised address"),
+            Self::UnmappedAccess {
+                address,
+                size,
+                write,
+            } => write!(
+                f,
+                "Invalid {} of size {} at unmapped address 0x{:08x}",
+                access(write),
+                size,
+                address
+            ),
+            Self::BelowStackPointer {
+                address,
+                size,
+                write,
+                sp,
+            } => write!(
+                f,
+                "Invalid {} of size {} at 0x{:08x}, below stack pointer 0x{:08x}",
+                access(write),
+                size,
+                address,
+                sp
+            ),
+        }
+    }
+}
+
+///
+/// An error detected by memcheck
+///
+#[derive(PartialEq, Debug, Clone)]
+pub struct MemcheckError {
+    /// kind of the error
+    pub kind: MemcheckErrorKind,
+    /// address of the instruction causing the error
+    pub pc: u32,
+    /// `pc` followed by the addresses of the calls and exception entries leading to it
+    pub backtrace: Vec<u32>,
+}
+
+#[derive(PartialEq, Debug, Copy, Clone, Default)]
+enum Transfer {
+    #[default]
+    None,
+    One(Reg),
+    Two(Reg, Reg),
+    List(EnumSet<Reg>),
+}
+
+// How values flow through a single instruction.
+#[allow(clippy::struct_excessive_bools)]
+#[derive(Default, Copy, Clone)]
+struct Dataflow {
+    sources: u16,
+    dests: u16,
+    address: u16,
+    target: u16,
+    condition: u16,
+    conditional: bool,
+    flags_in: bool,
+    flags_out: bool,
+    call: bool,
+    loads: Transfer,
+    stores: Transfer,
+}
+
+fn bit(reg: Reg) -> u16 {
+    1 << reg.value()
+}
+
+fn sets(setflags: SetFlags, in_it_block: bool) -> bool {
+    match setflags {
+        SetFlags::True => true,
+        SetFlags::False => false,
+        SetFlags::NotInITBlock => !in_it_block,
+    }
+}
+
+impl Dataflow {
+    fn data(&mut self, rd: Reg, sources: u16) {
+        self.dests |= bit(rd);
+        self.sources |= sources;
+    }
+}
+
+#[allow(clippy::too_many_lines)]
+fn dataflow(instruction: &Instruction, in_it_block: bool) -> Dataflow {
+    let mut flow = Dataflow::default();
+    match *instruction {
+        Instruction::ADD_reg {
+            rd,
+            rn,
+            rm,
+            setflags,
+            ..
+        }
+        | Instruction::AND_reg {
+            rd,
+            rn,
+            rm,
+            setflags,
+            ..
+        }
+        | Instruction::ASR_reg {
+            rd,
+            rn,
+            rm,
+            setflags,
+            ..
+        }
+        | Instruction::BIC_reg {
+            rd,
+            rn,
+            rm,
+            setflags,
+            ..
+        }
+        | Instruction::EOR_reg {
+            rd,
+            rn,
+            rm,
+            setflags,
+            ..
+        }
+        | Instruction::LSL_reg {
+            rd,
+            rn,
+            rm,
+            setflags,
+            ..
+        }
+        | Instruction::LSR_reg {
+            rd,
+            rn,
+            rm,
+            setflags,
+            ..
+        }
+        | Instruction::MUL {
+            rd,
+            rn,
+            rm,
+            setflags,
+            ..
+        }
+        | Instruction::ORR_reg {
+            rd,
+            rn,
+            rm,
+            setflags,
+            ..
+        }
+        | Instruction::ROR_reg {
+            rd,
+            rn,
+            rm,
+            setflags,
+            ..
+        }
+        | Instruction::SUB_reg {
+            rd,
+            rn,
+            rm,
+            setflags,
+            ..
+        } => {
+            flow.data(rd, bit(rn) | bit(rm));
+            flow.flags_out = sets(setflags, in_it_block);
+        }
+        Instruction::ADC_reg {
+            rd,
+            rn,
+            rm,
+            setflags,
+            ..
+        }
+        | Instruction::SBC_reg {
+            rd,
+            rn,
+            rm,
+            setflags,
+            ..
+        } => {
+            flow.data(rd, bit(rn) | bit(rm));
+            flow.flags_in = true;
+            flow.flags_out = sets(setflags, in_it_block);
+        }
+        Instruction::ORN_reg {
+            rd,
+            rn,
+            rm,
+            setflags,
+            ..
+        }
+        | Instruction::RSB_reg {
+            rd,
+            rn,
+            rm,
+            setflags,
+            ..
+        } => {
+            flow.data(rd, bit(rn) | bit(rm));
+            flow.flags_out = setflags;
+        }
+        Instruction::UDIV { rd, rn, rm }
+        | Instruction::SDIV { rd, rn, rm }
+        | Instruction::UXTAB { rd, rn, rm, .. }
+        | Instruction::SMUL { rd, rn, rm, .. } => flow.data(rd, bit(rn) | bit(rm)),
+        Instruction::UADD8 { rd, rn, rm } => {
+            flow.data(rd, bit(rn) | bit(rm));
+            flow.flags_out = true;
+        }
+        Instruction::SEL { rd, rn, rm } => {
+            flow.data(rd, bit(rn) | bit(rm));
+            flow.flags_in = true;
+        }
+        Instruction::ADD_imm {
+            rd, rn, setflags, ..
+        }
+        | Instruction::RSB_imm {
+            rd, rn, setflags, ..
+        }
+        | Instruction::SUB_imm {
+            rd, rn, setflags, ..
+        } => {
+            flow.data(rd, bit(rn));
+            flow.flags_out = sets(setflags, in_it_block);
+        }
+        Instruction::ADC_imm {
+            rd, rn, setflags, ..
+        } => {
+            flow.data(rd, bit(rn));
+            flow.flags_in = true;
+            flow.flags_out = sets(setflags, in_it_block);
+        }
+        Instruction::AND_imm {
+            rd, rn, setflags, ..
+        }
+        | Instruction::BIC_imm {
+            rd, rn, setflags, ..
+        }
+        | Instruction::EOR_imm {
+            rd, rn, setflags, ..
+        }
+        | Instruction::ORR_imm {
+            rd, rn, setflags, ..
+        } => {
+            flow.data(rd, bit(rn));
+            flow.flags_out = setflags;
+        }
+        Instruction::SBC_imm {
+            rd, rn, setflags, ..
+        } => {
+            flow.data(rd, bit(rn));
+            flow.flags_in = true;
+            flow.flags_out = setflags;
+        }
+        Instruction::UBFX { rd, rn, .. } => flow.data(rd, bit(rn)),
+        Instruction::BFI { rd, rn, .. } => flow.data(rd, bit(rn) | bit(rd)),
+        Instruction::ASR_imm {
+            rd, rm, setflags, ..
+        }
+        | Instruction::LSL_imm {
+            rd, rm, setflags, ..
+        }
+        | Instruction::LSR_imm {
+            rd, rm, setflags, ..
+        }
+        | Instruction::MVN_reg {
+            rd, rm, setflags, ..
+        } => {
+            flow.data(rd, bit(rm));
+            flow.flags_out = sets(setflags, in_it_block);
+        }
+        Instruction::ROR_imm {
+            rd, rm, setflags, ..
+        }
+        | Instruction::MOV_reg {
+            rd, rm, setflags, ..
+        } => {
+            flow.data(rd, bit(rm));
+            flow.flags_out = setflags;
+        }
+        Instruction::RRX { rd, rm, setflags } => {
+            flow.data(rd, bit(rm));
+            flow.flags_in = true;
+            flow.flags_out = setflags;
+        }
+        Instruction::CLZ { rd, rm }
+        | Instruction::REV { rd, rm, .. }
+        | Instruction::REV16 { rd, rm, .. }
+        | Instruction::REVSH { rd, rm, .. }
+        | Instruction::SXTB { rd, rm, .. }
+        | Instruction::SXTH { rd, rm, .. }
+        | Instruction::UXTB { rd, rm, .. }
+        | Instruction::UXTH { rd, rm, .. } => flow.data(rd, bit(rm)),
+        Instruction::ADD_sp_reg {
+            rd, rm, setflags, ..
+        } => {
+            flow.data(rd, bit(Reg::SP) | bit(rm));
+            flow.flags_out = setflags;
+        }
+        Instruction::ADR { rd, .. } | Instruction::MRS { rd, .. } => flow.data(rd, 0),
+        Instruction::MOVT { rd, .. } => flow.data(rd, bit(rd)),
+        Instruction::MOV_imm { rd, setflags, .. } => {
+            flow.data(rd, 0);
+            flow.flags_out = sets(setflags, in_it_block);
+        }
+        Instruction::MVN_imm { rd, setflags, .. } => {
+            flow.data(rd, 0);
+            flow.flags_out = setflags;
+        }
+        Instruction::MLA { rd, rn, rm, ra }
+        | Instruction::MLS { rd, rn, rm, ra }
+        | Instruction::SMLA { rd, rn, rm, ra, .. } => flow.data(rd, bit(rn) | bit(rm) | bit(ra)),
+        Instruction::UMULL { rdlo, rdhi, rn, rm } | Instruction::SMULL { rdlo, rdhi, rn, rm } => {
+            flow.data(rdlo, bit(rn) | bit(rm));
+            flow.data(rdhi, 0);
+        }
+        Instruction::UMLAL { rdlo, rdhi, rn, rm } | Instruction::SMLAL { rdlo, rdhi, rn, rm } => {
+            flow.data(rdlo, bit(rn) | bit(rm) | bit(rdlo) | bit(rdhi));
+            flow.data(rdhi, 0);
+        }
+        Instruction::CMN_reg { rn, rm, .. }
+        | Instruction::CMP_reg { rn, rm, .. }
+        | Instruction::TST_reg { rn, rm, .. }
+        | Instruction::TEQ_reg { rn, rm, .. } => {
+            flow.sources = bit(rn) | bit(rm);
+            flow.flags_out = true;
+        }
+        Instruction::CMN_imm { rn, .. }
+        | Instruction::CMP_imm { rn, .. }
+        | Instruction::TST_imm { rn, .. }
+        | Instruction::TEQ_imm { rn, .. } => {
+            flow.sources = bit(rn);
+            flow.flags_out = true;
+        }
+        // SYSm values 0..3 are the APSR views
+        Instruction::MSR_reg { rn, sysm, .. } if sysm < 4 => {
+            flow.sources = bit(rn);
+            flow.flags_out = true;
+        }
+        Instruction::B_t13 { cond, .. } => flow.conditional = cond != Condition::AL,
+        Instruction::BL { .. } => {
+            flow.data(Reg::LR, 0);
+            flow.call = true;
+        }
+        Instruction::BLX { rm } => {
+            flow.target = bit(rm);
+            flow.data(Reg::LR, 0);
+            flow.call = true;
+        }
+        Instruction::BX { rm } => flow.target = bit(rm),
+        Instruction::CBZ { rn, .. } => flow.condition = bit(rn),
+        Instruction::TBB { rn, rm } | Instruction::TBH { rn, rm } => {
+            flow.address = bit(rn) | bit(rm);
+        }
+        Instruction::LDR_imm { rt, rn, .. }
+        | Instruction::LDRB_imm { rt, rn, .. }
+        | Instruction::LDRH_imm { rt, rn, .. }
+        | Instruction::LDRSB_imm { rt, rn, .. }
+        | Instruction::LDRSH_imm { rt, rn, .. } => {
+            flow.address = bit(rn);
+            flow.loads = Transfer::One(rt);
+        }
+        Instruction::LDR_reg { rt, rn, rm, .. }
+        | Instruction::LDRB_reg { rt, rn, rm, .. }
+        | Instruction::LDRH_reg { rt, rn, rm, .. }
+        | Instruction::LDRSB_reg { rt, rn, rm, .. }
+        | Instruction::LDRSH_reg { rt, rn, rm, .. } => {
+            flow.address = bit(rn) | bit(rm);
+            flow.loads = Transfer::One(rt);
+        }
+        Instruction::LDR_lit { rt, .. } => flow.loads = Transfer::One(rt),
+        Instruction::LDRD_imm { rt, rt2, rn, .. } => {
+            flow.address = bit(rn);
+            flow.loads = Transfer::Two(rt, rt2);
+        }
+        Instruction::LDM { rn, registers, .. } => {
+            flow.address = bit(rn);
+            flow.loads = Transfer::List(registers);
+        }
+        Instruction::POP { registers, .. } => {
+            flow.address = bit(Reg::SP);
+            flow.loads = Transfer::List(registers);
+        }
+        Instruction::LDC_imm { rn, .. } | Instruction::LDC2_imm { rn, .. } => {
+            flow.address = bit(rn);
+        }
+        Instruction::STR_imm { rt, rn, .. }
+        | Instruction::STRB_imm { rt, rn, .. }
+        | Instruction::STRH_imm { rt, rn, .. } => {
+            flow.address = bit(rn);
+            flow.stores = Transfer::One(rt);
+        }
+        Instruction::STR_reg { rt, rn, rm, .. }
+        | Instruction::STRB_reg { rt, rn, rm, .. }
+        | Instruction::STRH_reg { rt, rn, rm, .. } => {
+            flow.address = bit(rn) | bit(rm);
+            flow.stores = Transfer::One(rt);
+        }
+        Instruction::STRD_imm { rt, rt2, rn, .. } => {
+            flow.address = bit(rn);
+            flow.stores = Transfer::Two(rt, rt2);
+        }
+        Instruction::STM { rn, registers, .. } | Instruction::STMDB { rn, registers, .. } => {
+            flow.address = bit(rn);
+            flow.stores = Transfer::List(registers);
+        }
+        Instruction::PUSH { registers, .. } => {
+            flow.address = bit(Reg::SP);
+            flow.stores = Transfer::List(registers);
+        }
+        _ => (),
+    }
+    flow
+}
+
+// Lowest and highest stack pointer values seen on a stack.
+#[derive(Default, Copy, Clone)]
+struct StackExtent {
+    low: u32,
+    top: u32,
+    sp: u32,
+}
+
+impl StackExtent {
+    // Follow the stack pointer, returning the range released when it moves up.
+    fn update(&mut self, sp: u32) -> Option<(u32, u32)> {
+        if sp == 0 {
+            return None;
+        }
+        if self.top == 0 || sp > self.top || (sp < self.sp && self.sp - sp > STACK_SWITCH_DISTANCE)
+        {
+            *self = Self {
+                low: sp,
+                top: sp,
+                sp,
+            };
+            return None;
+        }
+        let previous = self.sp;
+        self.sp = sp;
+        self.low = self.low.min(sp);
+        if sp > previous {
+            Some((previous, sp))
+        } else {
+            None
+        }
+    }
+}
+
+///
+/// State of the memcheck mode
+///
+pub struct Memcheck {
+    report_func: Box<dyn FnMut(&MemcheckError)>,
+    shadow: Vec<(u32, Vec<bool>)>,
+    undefined_regs: u16,
+    undefined_flags: bool,
+    active: bool,
+    pc: u32,
+    sp: u32,
+    next_pc: u32,
+    flow: Dataflow,
+    loads: Vec<bool>,
+    stores: Vec<bool>,
+    next_store: usize,
+    accesses: Vec<(u32, u8, bool)>,
+    exception_frame: Option<(u16, bool)>,
+    msp: StackExtent,
+    psp: StackExtent,
+    calls: Vec<(u32, u32)>,
+    reported: HashSet<(mem::Discriminant<MemcheckErrorKind>, u32)>,
+    error_count: usize,
+}
+
+impl Memcheck {
+    ///
+    /// Create memcheck state reporting the errors to `report_func`.
+    /// Repeated errors of the same kind at the same address are reported once.
+    ///
+    #[must_use]
+    pub fn new(report_func: Box<dyn FnMut(&MemcheckError)>) -> Self {
+        Self {
+            report_func,
+            shadow: Vec::new(),
+            undefined_regs: RESET_UNDEFINED_REGISTERS,
+            undefined_flags: true,
+            active: false,
+            pc: 0,
+            sp: 0,
+            next_pc: 0,
+            flow: Dataflow::default(),
+            loads: Vec::new(),
+            stores: Vec::new(),
+            next_store: 0,
+            accesses: Vec::new(),
+            exception_frame: None,
+            msp: StackExtent::default(),
+            psp: StackExtent::default(),
+            calls: Vec::new(),
+            reported: HashSet::new(),
+            error_count: 0,
+        }
+    }
+
+    ///
+    /// Number of errors detected, including the repeated ones
+    ///
+    #[must_use]
+    pub fn error_count(&self) -> usize {
+        self.error_count
+    }
+
+    ///
+    /// Number of distinct errors reported
+    ///
+    #[must_use]
+    pub fn unique_error_count(&self) -> usize {
+        self.reported.len()
+    }
+
+    fn report(&mut self, kind: MemcheckErrorKind) {
+        self.error_count += 1;
+        if !self.reported.insert((mem::discriminant(&kind), self.pc)) {
+            return;
+        }
+        let mut backtrace = vec![self.pc];
+        backtrace.extend(self.calls.iter().rev().map(|&(call_site, _)| call_site));
+        let error = MemcheckError {
+            kind,
+            pc: self.pc,
+            backtrace,
+        };
+        (self.report_func)(&error);
+    }
+
+    fn shadow_byte(&mut self, address: u32) -> Option<&mut bool> {
+        self.shadow
+            .iter_mut()
+            .find(|(base, bytes)| address >= *base && ((address - base) as usize) < bytes.len())
+            .map(|(base, bytes)| &mut bytes[(address - *base) as usize])
+    }
+
+    fn is_defined(&mut self, address: u32, size: u8) -> bool {
+        (0..u32::from(size)).all(|i| {
+            self.shadow_byte(address.wrapping_add(i))
+                .is_none_or(|defined| *defined)
+        })
+    }
+
+    fn set_defined(&mut self, address: u32, size: u32, defined: bool) {
+        for i in 0..size {
+            if let Some(byte) = self.shadow_byte(address.wrapping_add(i)) {
+                *byte = defined;
+            }
+        }
+    }
+
+    fn is_undefined(&self, reg: Reg) -> bool {
+        self.undefined_regs & bit(reg) != 0
+    }
+
+    fn set_undefined(&mut self, reg: Reg, undefined: bool) {
+        if undefined {
+            self.undefined_regs |= bit(reg);
+        } else {
+            self.undefined_regs &= !bit(reg);
+        }
+    }
+
+    fn queue_stores(&mut self, transfer: Transfer) {
+        match transfer {
+            Transfer::None => (),
+            Transfer::One(rt) => self.stores.push(self.is_undefined(rt)),
+            Transfer::Two(rt, rt2) => {
+                self.stores.push(self.is_undefined(rt));
+                self.stores.push(self.is_undefined(rt2));
+            }
+            Transfer::List(registers) => {
+                for reg in &registers {
+                    self.stores.push(self.is_undefined(reg));
+                }
+            }
+        }
+    }
+
+    fn apply_loads(&mut self, transfer: Transfer) {
+        let loads = mem::take(&mut self.loads);
+        let mut loaded = loads.iter().copied();
+        let mut load = |memcheck: &mut Self, reg: Reg| {
+            let undefined = loaded.next().unwrap_or(false);
+            if reg == Reg::PC && undefined {
+                memcheck.report(MemcheckErrorKind::UninitialisedJump);
+            }
+            memcheck.set_undefined(reg, undefined);
+        };
+        match transfer {
+            Transfer::None => (),
+            Transfer::One(rt) => load(self, rt),
+            Transfer::Two(rt, rt2) => {
+                load(self, rt);
+                load(self, rt2);
+            }
+            Transfer::List(registers) => {
+                for reg in &registers {
+                    load(self, reg);
+                }
+            }
+        }
+        self.loads = loads;
+    }
+}
+
+///
+/// Trait for memcheck bookkeeping of the processor
+///
+pub trait Memchecking {
+    ///
+    /// Track a bus access, `faulted` telling if the access failed
+    ///
+    fn memcheck_access(&self, address: u32, size: u8, write: bool, faulted: bool);
+
+    ///
+    /// Check the uses of uninitialised values by an instruction about to be executed
+    ///
+    fn memcheck_before(&mut self, instruction: &Instruction, in_it_block: bool);
+
+    ///
+    /// Propagate the initialisation state through an executed instruction
+    ///
+    fn memcheck_after(&mut self, instruction_size: usize, taken: bool, faulted: bool);
+
+    ///
+    /// Record the initialisation state of registers in an exception frame just stacked
+    ///
+    fn memcheck_exception_entry(&mut self, frameptr: u32);
+
+    ///
+    /// Take the initialisation state of registers from an exception frame being unstacked
+    ///
+    fn memcheck_exception_return(&mut self, frameptr: u32);
+
+    ///
+    /// Prepare memcheck state on reset
+    ///
+    fn memcheck_reset(&mut self);
+
+    ///
+    /// Number of errors detected and number of distinct errors reported,
+    /// if memcheck is enabled
+    ///
+    fn memcheck_error_counts(&self) -> Option<(usize, usize)>;
+}
+
+impl Memchecking for Processor {
+    #[inline(always)]
+    fn memcheck_access(&self, address: u32, size: u8, write: bool, faulted: bool) {
+        if let Some(memcheck) = &self.memcheck {
+            let mut memcheck = memcheck.borrow_mut();
+            if faulted {
+                if self.region_name(address) == "unmapped" {
+                    memcheck.report(MemcheckErrorKind::UnmappedAccess {
+                        address,
+                        size,
+                        write,
+                    });
+                }
+                return;
+            }
+            let mapped = self.map_address(address);
+            if write {
+                let undefined = memcheck.active
+                    && memcheck
+                        .stores
+                        .get(memcheck.next_store)
+                        .copied()
+                        .unwrap_or(false);
+                memcheck.next_store += 1;
+                memcheck.set_defined(mapped, u32::from(size), !undefined);
+            } else if memcheck.active {
+                let defined = memcheck.is_defined(mapped, size);
+                memcheck.loads.push(!defined);
+            }
+            if memcheck.active {
+                memcheck.accesses.push((address, size, write));
+            }
+        }
+    }
+
+    #[inline(always)]
+    fn memcheck_before(&mut self, instruction: &Instruction, in_it_block: bool) {
+        if let Some(memcheck) = &self.memcheck {
+            let pc = self.pc;
+            let sp = self.get_r(Reg::SP);
+            let mut memcheck = memcheck.borrow_mut();
+
+            // the pc moved between instructions: an exception was entered
+            if pc != memcheck.next_pc {
+                let return_address = memcheck.next_pc;
+                memcheck.calls.push((return_address, return_address));
+            }
+
+            memcheck.pc = pc;
+            memcheck.sp = sp;
+            memcheck.active = true;
+            memcheck.loads.clear();
+            memcheck.stores.clear();
+            memcheck.next_store = 0;
+            memcheck.accesses.clear();
+            memcheck.exception_frame = None;
+
+            let flow = dataflow(instruction, in_it_block);
+            memcheck.flow = flow;
+
+            if (flow.conditional || in_it_block) && memcheck.undefined_flags
+                || flow.condition & memcheck.undefined_regs != 0
+            {
+                memcheck.report(MemcheckErrorKind::UninitialisedCondition);
+            }
+            if flow.address & memcheck.undefined_regs != 0 {
+                memcheck.report(MemcheckErrorKind::UninitialisedAddress);
+            }
+            if flow.target & memcheck.undefined_regs != 0 {
+                memcheck.report(MemcheckErrorKind::UninitialisedJump);
+            }
+            memcheck.queue_stores(flow.stores);
+        }
+    }
+
+    #[inline(always)]
+    fn memcheck_after(&mut self, instruction_size: usize, taken: bool, faulted: bool) {
+        if let Some(memcheck) = &self.memcheck {
+            let pc = self.pc;
+            let sp = self.get_r(Reg::SP);
+            let (msp, psp) = (self.get_msp(), self.get_psp());
+            let process_stack = self.mode == ProcessorMode::ThreadMode && self.control.sp_sel;
+            let mut memcheck = memcheck.borrow_mut();
+            let flow = memcheck.flow;
+
+            if taken {
+                memcheck.apply_loads(flow.loads);
+
+                let undefined = flow.sources & memcheck.undefined_regs != 0
+                    || flow.flags_in && memcheck.undefined_flags;
+                if flow.dests & bit(Reg::PC) != 0 && undefined {
+                    memcheck.report(MemcheckErrorKind::UninitialisedJump);
+                }
+                if undefined {
+                    memcheck.undefined_regs |= flow.dests;
+                } else {
+                    memcheck.undefined_regs &= !flow.dests;
+                }
+                if flow.flags_out {
+                    memcheck.undefined_flags = undefined;
+                }
+                if flow.call {
+                    let call_site = memcheck.pc;
+                    memcheck
+                        .calls
+                        .push((call_site, call_site + instruction_size as u32));
+                }
+            }
+
+            if let Some((undefined_regs, undefined_flags)) = memcheck.exception_frame {
+                memcheck.undefined_regs =
+                    (memcheck.undefined_regs & !FRAME_REGISTERS) | undefined_regs;
+                memcheck.undefined_flags = undefined_flags;
+            } else if memcheck.active && !faulted {
+                let stack = if process_stack {
+                    memcheck.psp
+                } else {
+                    memcheck.msp
+                };
+                let lowest_sp = sp.min(memcheck.sp);
+                let accesses = mem::take(&mut memcheck.accesses);
+                for &(address, size, write) in &accesses {
+                    if address >= stack.low && address < lowest_sp {
+                        memcheck.report(MemcheckErrorKind::BelowStackPointer {
+                            address,
+                            size,
+                            write,
+                            sp: lowest_sp,
+                        });
+                    }
+                }
+                memcheck.accesses = accesses;
+            }
+            memcheck.active = false;
+
+            // stack released by moving the stack pointer up is no longer initialised
+            if let Some((start, end)) = memcheck.msp.update(msp) {
+                memcheck.set_defined(start, end - start, false);
+            }
+            if let Some((start, end)) = memcheck.psp.update(psp) {
+                memcheck.set_defined(start, end - start, false);
+            }
+
+            if faulted {
+                let fault_address = memcheck.pc;
+                memcheck.calls.push((fault_address, fault_address));
+            } else if let Some(depth) = memcheck
+                .calls
+                .iter()
+                .rposition(|&(_, return_address)| return_address & !1 == pc & !1)
+            {
+                memcheck.calls.truncate(depth);
+            }
+            memcheck.next_pc = pc;
+        }
+    }
+
+    fn memcheck_exception_entry(&mut self, frameptr: u32) {
+        if let Some(memcheck) = &self.memcheck {
+            let mut memcheck = memcheck.borrow_mut();
+            for (i, reg) in STACKED_REGISTERS.iter().enumerate() {
+                let undefined = memcheck.is_undefined(*reg);
+                memcheck.set_defined(frameptr.wrapping_add(4 * i as u32), 4, !undefined);
+            }
+            let undefined_flags = memcheck.undefined_flags;
+            memcheck.set_defined(frameptr.wrapping_add(0x1c), 4, !undefined_flags);
+            // the handler is entered with EXC_RETURN in lr
+            memcheck.set_undefined(Reg::LR, false);
+        }
+    }
+
+    fn memcheck_exception_return(&mut self, frameptr: u32) {
+        if let Some(memcheck) = &self.memcheck {
+            let mut memcheck = memcheck.borrow_mut();
+            let mut undefined_regs = 0;
+            for (i, reg) in STACKED_REGISTERS.iter().enumerate() {
+                if !memcheck.is_defined(frameptr + 4 * i as u32, 4) {
+                    undefined_regs |= bit(*reg);
+                }
+            }
+            let undefined_flags = !memcheck.is_defined(frameptr + 0x1c, 4);
+            memcheck.exception_frame = Some((undefined_regs, undefined_flags));
+            memcheck.active = false;
+        }
+    }
+
+    fn memcheck_reset(&mut self) {
+        if let Some(memcheck) = &self.memcheck {
+            let mut memcheck = memcheck.borrow_mut();
+            if memcheck.shadow.is_empty() {
+                memcheck.shadow = self
+                    .regions
+                    .iter()
+                    .filter(|region| region.is_ram())
+                    .map(|region| (region.base, vec![false; region.size]))
+                    .collect();
+                for (address, size) in &self.loaded_ranges {
+                    memcheck.set_defined(*address, *size as u32, true);
+                }
+            }
+            memcheck.undefined_regs = RESET_UNDEFINED_REGISTERS;
+            memcheck.undefined_flags = true;
+            memcheck.active = false;
+            memcheck.msp = StackExtent::default();
+            memcheck.psp = StackExtent::default();
+            memcheck.calls.clear();
+            memcheck.next_pc = self.pc;
+            let msp = self.get_msp();
+            memcheck.msp.update(msp);
+        }
+    }
+
+    fn memcheck_error_counts(&self) -> Option<(usize, usize)> {
+        self.memcheck.as_ref().map(|memcheck| {
+            let memcheck = memcheck.borrow();
+            (memcheck.error_count(), memcheck.unique_error_count())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::core::exception::{Exception, ExceptionHandling};
+    use crate::core::executor::Executor;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn run(code: &[u16]) -> Vec<MemcheckError> {
+        run_with(code, &[], code.len(), |_, _| ())
+    }
+
+    // Run `steps` steps with data loaded to memory before memcheck is enabled,
+    // calling `after_step` with the processor and the number of the step taken
+    fn run_with<F>(
+        code: &[u16],
+        loaded: &[(u32, &[u8])],
+        steps: usize,
+        mut after_step: F,
+    ) -> Vec<MemcheckError>
+    where
+        F: FnMut(&mut Processor, usize),
+    {
+        let errors = Rc::new(RefCell::new(Vec::new()));
+        let sink = Rc::clone(&errors);
+        let mut processor = Processor::new();
+        for (address, data) in loaded {
+            processor.load_memory(*address, data).unwrap();
+        }
+        processor.memcheck(Some(Memcheck::new(Box::new(
+            move |error: &MemcheckError| sink.borrow_mut().push(error.clone()),
+        ))));
+        processor.cache_instructions();
+        processor.set_msp(0x2000_1000);
+        processor.set_pc(0x2000_0000);
+        processor.memcheck_reset();
+        for (i, halfword) in code.iter().enumerate() {
+            processor
+                .write16(0x2000_0000 + 2 * i as u32, *halfword)
+                .unwrap();
+        }
+        for step in 0..steps {
+            processor.step();
+            after_step(&mut processor, step);
+        }
+        let errors = errors.borrow().clone();
+        errors
+    }
+
+    // movs r0, #0x20; lsls r0, r0, #24; adds r0, #0x80
+    const R0_IS_0X20000080: [u16; 3] = [0x2020, 0x0600, 0x3080];
+
+    #[test]
+    fn test_uninitialised_condition() {
+        // ldr r1, [r0]; cmp r1, #0; beq
+        let errors = run(&[&R0_IS_0X20000080[..], &[0x6801, 0x2900, 0xD000]].concat());
+
+        assert_eq!(
+            errors,
+            vec![MemcheckError {
+                kind: MemcheckErrorKind::UninitialisedCondition,
+                pc: 0x2000_000A,
+                backtrace: vec![0x2000_000A],
+            }]
+        );
+    }
+
+    #[test]
+    fn test_initialised_condition() {
+        // movs r2, #5; str r2, [r0]; ldr r1, [r0]; cmp r1, #0; beq
+        let errors = run(&[
+            &R0_IS_0X20000080[..],
+            &[0x2205, 0x6002, 0x6801, 0x2900, 0xD000],
+        ]
+        .concat());
+
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn test_loaded_data_is_initialised() {
+        // ldr r1, [r0]; cmp r1, #0; beq
+        let code = [&R0_IS_0X20000080[..], &[0x6801, 0x2900, 0xD000]].concat();
+        let errors = run_with(
+            &code,
+            &[(0x2000_0080, &[1, 2, 3, 4])],
+            code.len(),
+            |_, _| (),
+        );
+
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn test_uninitialised_value_kept_over_exception() {
+        // ldr r1, [r0]; <PendSV taken>; cmp r1, #0; beq, with "bx lr" as the handler
+        let code = [&R0_IS_0X20000080[..], &[0x6801, 0x2900, 0xD000]].concat();
+        let errors = run_with(&code, &[], code.len() + 1, |processor, step| {
+            if step == 0 {
+                processor.write16(0x2000_0200, 0x4770).unwrap();
+                processor
+                    .write32(0x2000_0100 + 14 * 4, 0x2000_0201)
+                    .unwrap();
+                processor.vtor = 0x2000_0100;
+            }
+            if step == 3 {
+                processor.execution_priority = processor.get_execution_priority();
+                processor.set_exception_pending(Exception::PendSV);
+                processor.check_exceptions();
+            }
+        });
+
+        let kinds: Vec<_> = errors.iter().map(|error| error.kind).collect();
+        assert_eq!(kinds, vec![MemcheckErrorKind::UninitialisedCondition]);
+        assert_eq!(errors[0].pc, 0x2000_000A);
+    }
+
+    #[test]
+    fn test_uninitialised_address() {
+        // ldr r1, [r0]; ldr r2, [r1]
+        let errors = run(&[&R0_IS_0X20000080[..], &[0x6801, 0x680A]].concat());
+
+        let kinds: Vec<_> = errors.iter().map(|error| error.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                MemcheckErrorKind::UninitialisedAddress,
+                MemcheckErrorKind::UnmappedAccess {
+                    address: 0xcdcd_cdcd,
+                    size: 4,
+                    write: false
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn test_access_below_stack_pointer() {
+        // push {r0}; pop {r0}; mov r1, sp; subs r1, #4; ldr r2, [r1]
+        let errors = run(&[0xB401, 0xBC01, 0x4669, 0x3904, 0x680A]);
+
+        let kinds: Vec<_> = errors.iter().map(|error| error.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![MemcheckErrorKind::BelowStackPointer {
+                address: 0x2000_0FFC,
+                size: 4,
+                write: false,
+                sp: 0x2000_1000
+            }]
+        );
+    }
+}
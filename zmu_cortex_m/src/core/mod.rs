@@ -4,12 +4,18 @@
 
 pub mod bits;
 pub mod condition;
+pub mod coverage;
 pub mod exception;
 pub mod executor;
 pub mod fault;
 pub mod fetch;
+pub mod icache;
 pub mod instruction;
+pub mod memcheck;
 pub mod operation;
+pub mod profile;
 pub mod register;
 pub mod reset;
+pub mod sleep;
+pub mod stack;
 pub mod thumb;
@@ -0,0 +1,360 @@
+//!
+//! Function level profiling
+//!
+//! Executed instructions and cycles are attributed to the function being run.
+//! A shadow call stack follows the calls made with `BL` and `BLX` and the
+//! exception entries. A branch to the return address of a frame on the stack,
+//! such as `BX LR` or `POP {pc}`, returns from the frame and an exception
+//! return leaves the innermost exception handler.
+//!
+
+use crate::core::instruction::Instruction;
+use crate::Processor;
+use std::collections::HashMap;
+use std::ops::{AddAssign, Sub};
+
+///
+/// Cost of executing code
+///
+#[derive(PartialEq, Debug, Default, Copy, Clone)]
+pub struct Cost {
+    /// number of executed instructions
+    pub instructions: u64,
+    /// number of clock cycles
+    pub cycles: u64,
+}
+
+impl AddAssign for Cost {
+    fn add_assign(&mut self, other: Self) {
+        self.instructions += other.instructions;
+        self.cycles += other.cycles;
+    }
+}
+
+impl Sub for Cost {
+    type Output = Self;
+
+    fn sub(self, other: Self) -> Self {
+        Self {
+            instructions: self.instructions - other.instructions,
+            cycles: self.cycles - other.cycles,
+        }
+    }
+}
+
+///
+/// Calls from a call site to a function
+///
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct Call {
+    /// entry address of the calling function
+    pub caller: u32,
+    /// address of the calling instruction, or the interrupted instruction
+    pub call_site: u32,
+    /// entry address of the called function or exception handler
+    pub callee: u32,
+    /// number of calls
+    pub count: u64,
+    /// cost of the calls, including the functions called from the callee
+    pub inclusive: Cost,
+}
+
+// caller, call site and callee
+type CallKey = (u32, u32, u32);
+
+struct Frame {
+    function: u32,
+    return_address: u32,
+    exception: bool,
+    call: Option<CallKey>,
+    start: Cost,
+}
+
+///
+/// Profile of a simulation run
+///
+#[derive(Default)]
+pub struct Profiler {
+    total: Cost,
+    costs: HashMap<(u32, u32), Cost>,
+    calls: HashMap<CallKey, (u64, Cost)>,
+    stack: Vec<Frame>,
+    // function running the current instruction
+    function: u32,
+    // the current instruction returned from an exception
+    exception_returned: bool,
+}
+
+impl Profiler {
+    ///
+    /// Create an empty profile
+    ///
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    ///
+    /// Total cost of the run
+    ///
+    #[must_use]
+    pub fn total(&self) -> Cost {
+        self.total
+    }
+
+    ///
+    /// Cost of the instructions, keyed by the entry address of the function
+    /// and the address of the instruction
+    ///
+    #[must_use]
+    pub fn costs(&self) -> &HashMap<(u32, u32), Cost> {
+        &self.costs
+    }
+
+    ///
+    /// Calls made so far. Calls still in progress count up to the present.
+    ///
+    #[must_use]
+    pub fn calls(&self) -> Vec<Call> {
+        let mut calls = self.calls.clone();
+        for frame in &self.stack {
+            if let Some(key) = frame.call {
+                calls.entry(key).or_default().1 += self.total - frame.start;
+            }
+        }
+        calls
+            .into_iter()
+            .map(|((caller, call_site, callee), (count, inclusive))| Call {
+                caller,
+                call_site,
+                callee,
+                count,
+                inclusive,
+            })
+            .collect()
+    }
+
+    fn begin(&mut self, pc: u32) {
+        if self.stack.is_empty() {
+            self.stack.push(Frame {
+                function: pc,
+                return_address: 0,
+                exception: false,
+                call: None,
+                start: self.total,
+            });
+        }
+        self.function = self.stack.last().unwrap().function;
+    }
+
+    fn instruction(&mut self, pc: u32, cycles: u32) {
+        let cost = Cost {
+            instructions: 1,
+            cycles: u64::from(cycles),
+        };
+        *self.costs.entry((self.function, pc)).or_default() += cost;
+        self.total += cost;
+
+        // the returning instruction belongs to the handler
+        if self.exception_returned {
+            self.exception_returned = false;
+            if let Some(index) = self.stack.iter().rposition(|frame| frame.exception) {
+                self.unwind(index);
+            }
+        }
+    }
+
+    fn call(&mut self, call_site: u32, callee: u32, return_address: u32, exception: bool) {
+        let call = self
+            .stack
+            .last()
+            .map(|frame| (frame.function, call_site, callee));
+        if let Some(key) = call {
+            self.calls.entry(key).or_default().0 += 1;
+        }
+        self.stack.push(Frame {
+            function: callee,
+            return_address,
+            exception,
+            call,
+            start: self.total,
+        });
+    }
+
+    fn branch(&mut self, target: u32) {
+        let frame = self
+            .stack
+            .iter()
+            .rev()
+            .take_while(|frame| !frame.exception)
+            .position(|frame| frame.return_address == target);
+        if let Some(depth) = frame {
+            let len = self.stack.len() - depth - 1;
+            self.unwind(len);
+        }
+    }
+
+    fn exception_return(&mut self) {
+        self.exception_returned = true;
+    }
+
+    // Return from the frames above the given stack depth
+    fn unwind(&mut self, len: usize) {
+        while self.stack.len() > len {
+            let frame = self.stack.pop().unwrap();
+            if let Some(key) = frame.call {
+                self.calls.entry(key).or_default().1 += self.total - frame.start;
+            }
+        }
+    }
+}
+
+///
+/// Trait for profiling the execution of the processor
+///
+pub trait Profiling {
+    ///
+    /// Prepare for executing the instruction at `pc`
+    ///
+    fn profile_before(&mut self, pc: u32);
+
+    ///
+    /// Account an instruction executed at `pc`, `branched` telling if it
+    /// wrote the pc
+    ///
+    fn profile_instruction(
+        &mut self,
+        instruction: &Instruction,
+        pc: u32,
+        cycles: u32,
+        branched: bool,
+    );
+
+    ///
+    /// Follow an exception entry that interrupted the code at `return_address`
+    ///
+    fn profile_exception_entry(&mut self, return_address: u32);
+
+    ///
+    /// Follow a return from an exception handler
+    ///
+    fn profile_exception_return(&mut self);
+
+    ///
+    /// Profile so far, if profiling is enabled
+    ///
+    fn profile(&self) -> Option<&Profiler>;
+}
+
+impl Profiling for Processor {
+    #[inline(always)]
+    fn profile_before(&mut self, pc: u32) {
+        if let Some(profiler) = &mut self.profiler {
+            profiler.begin(pc);
+        }
+    }
+
+    #[inline(always)]
+    fn profile_instruction(
+        &mut self,
+        instruction: &Instruction,
+        pc: u32,
+        cycles: u32,
+        branched: bool,
+    ) {
+        if let Some(profiler) = &mut self.profiler {
+            profiler.instruction(pc, cycles);
+            if branched {
+                match instruction {
+                    Instruction::BL { .. } | Instruction::BLX { .. } => {
+                        profiler.call(pc, self.pc, self.lr & !1, false);
+                    }
+                    _ => profiler.branch(self.pc),
+                }
+            }
+        }
+    }
+
+    fn profile_exception_entry(&mut self, return_address: u32) {
+        if let Some(profiler) = &mut self.profiler {
+            profiler.call(return_address, self.pc, return_address, true);
+        }
+    }
+
+    fn profile_exception_return(&mut self) {
+        if let Some(profiler) = &mut self.profiler {
+            profiler.exception_return();
+        }
+    }
+
+    fn profile(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_call_graph() {
+        let mut profiler = Profiler::new();
+
+        // main calls f, which is interrupted by a handler
+        profiler.begin(0x100);
+        profiler.instruction(0x100, 1);
+        profiler.call(0x100, 0x200, 0x104, false);
+        profiler.begin(0x200);
+        profiler.instruction(0x200, 2);
+        profiler.call(0x202, 0x300, 0x202, true);
+        profiler.begin(0x300);
+        profiler.instruction(0x300, 1);
+        profiler.begin(0x302);
+        profiler.exception_return();
+        profiler.instruction(0x302, 3);
+        profiler.begin(0x202);
+        profiler.instruction(0x202, 1);
+        profiler.branch(0x104);
+        profiler.begin(0x104);
+        profiler.instruction(0x104, 1);
+
+        assert_eq!(
+            profiler.total(),
+            Cost {
+                instructions: 6,
+                cycles: 9
+            }
+        );
+        assert_eq!(profiler.costs()[&(0x200, 0x200)].cycles, 2);
+        assert_eq!(profiler.costs()[&(0x300, 0x302)].cycles, 3);
+        assert_eq!(profiler.costs()[&(0x100, 0x104)].instructions, 1);
+
+        let mut calls = profiler.calls();
+        calls.sort_by_key(|call| call.callee);
+        assert_eq!(
+            calls,
+            vec![
+                Call {
+                    caller: 0x100,
+                    call_site: 0x100,
+                    callee: 0x200,
+                    count: 1,
+                    inclusive: Cost {
+                        instructions: 4,
+                        cycles: 7
+                    },
+                },
+                Call {
+                    caller: 0x200,
+                    call_site: 0x202,
+                    callee: 0x300,
+                    count: 1,
+                    inclusive: Cost {
+                        instructions: 2,
+                        cycles: 4
+                    },
+                },
+            ]
+        );
+    }
+}
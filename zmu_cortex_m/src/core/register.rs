@@ -823,17 +823,26 @@ impl fmt::Display for SpecialReg {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone)]
 /// CONTROL register parts
 pub struct Control {
     /// Thread mode priviledge level
     pub n_priv: bool,
     /// selection of current active stack pointer, true = PSP, false = MSP
     pub sp_sel: bool,
+    /// floating point context active
+    #[cfg(armv7em)]
+    pub fpca: bool,
 }
 
 impl From<Control> for u8 {
+    #[cfg(not(armv7em))]
     fn from(control: Control) -> Self {
         control.n_priv as Self + ((control.sp_sel as Self) << 1)
     }
+
+    #[cfg(armv7em)]
+    fn from(control: Control) -> Self {
+        control.n_priv as Self + ((control.sp_sel as Self) << 1) + ((control.fpca as Self) << 2)
+    }
 }
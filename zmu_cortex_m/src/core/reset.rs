@@ -5,7 +5,10 @@
 use crate::bus::Bus;
 use crate::core::exception::ExceptionHandling;
 use crate::core::fault::Fault;
+use crate::core::memcheck::Memchecking;
 use crate::core::register::{BaseReg, PSR};
+use crate::core::sleep::Sleep;
+use crate::device::peripheral::Peripherals;
 use crate::Processor;
 use crate::ProcessorMode;
 
@@ -24,9 +27,15 @@ impl Reset for Processor {
             *r = 0;
         }
 
-        // Main stack pointer is read via vector table
+        // Peripherals first, they can change the memory mapping of the vector table
+        self.peripherals_reset();
+
+        // Main stack pointer is read via vector table, unless overridden
         let vtor = self.vtor;
-        let sp = self.read32(vtor)? & 0xffff_fffc;
+        let sp = match self.initial_sp {
+            Some(sp) => sp,
+            None => self.read32(vtor)?,
+        } & 0xffff_fffc;
         self.set_msp(sp);
 
         // Process stack pointer to zero
@@ -49,17 +58,53 @@ impl Reset for Processor {
         self.basepri = 0;
         self.control.sp_sel = false;
         self.control.n_priv = false;
+        #[cfg(armv7em)]
+        {
+            self.control.fpca = false;
+        }
 
         //TODO self.scs.reset();
         self.exceptions_reset();
 
-        //self.event_reg.clear();
+        self.clear_event();
 
         self.itstate = 0;
+        self.lockup = None;
+        self.exit_code = None;
         self.execution_priority = self.get_execution_priority();
 
-        let reset_vector = self.read32(vtor + 4)?;
+        // an overridden entry point is always Thumb code
+        let reset_vector = match self.entry_point {
+            Some(pc) => pc | 1,
+            None => self.read32(vtor + 4)?,
+        };
         self.blx_write_pc(reset_vector);
+        self.memcheck_reset();
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::register::Epsr;
+
+    #[test]
+    fn test_reset_overrides() {
+        let mut processor = Processor::new();
+        processor.vector_table(0x2000_0000);
+        processor.write32(0x2000_0000, 0x2000_1000).unwrap();
+        processor.write32(0x2000_0004, 0x0000_0101).unwrap();
+
+        processor.reset().unwrap();
+        assert_eq!(processor.msp, 0x2000_1000);
+        assert_eq!(processor.pc, 0x0000_0100);
+
+        processor.initial_sp(Some(0x2000_0803));
+        processor.entry_point(Some(0x0000_0200));
+        processor.reset().unwrap();
+        assert_eq!(processor.msp, 0x2000_0800);
+        assert_eq!(processor.pc, 0x0000_0200);
+        assert!(processor.psr.get_t());
+    }
+}
@@ -0,0 +1,252 @@
+//!
+//! Processor sleep states and the event register
+//!
+
+use crate::core::bits::Bits;
+use crate::Processor;
+
+const SCR_SLEEPDEEP: usize = 2;
+const SCR_SEVONPEND: usize = 4;
+
+const STATE_SLEEPING: usize = 1;
+const STATE_DEEP_SLEEP: usize = 2;
+const STATE_WAIT_FOR_EVENT: usize = 3;
+
+///
+/// Trait for controlling processor sleep and the WFE event register
+///
+pub trait Sleep {
+    ///
+    /// Set the event register. Wakes the processor if it is waiting for an event (WFE).
+    ///
+    fn set_event(&mut self);
+
+    ///
+    /// Clear the event register, returning the state it had before clearing.
+    ///
+    fn clear_event(&mut self) -> bool;
+
+    ///
+    /// Check if the event register is set
+    ///
+    fn event_registered(&self) -> bool;
+
+    ///
+    /// Set the event register as a consequence of an exception becoming pending.
+    /// Only has an effect if SCR.SEVONPEND is set.
+    ///
+    fn set_event_on_pend(&mut self);
+
+    ///
+    /// Put the processor to sleep. Sleep depth is selected with SCR.SLEEPDEEP.
+    /// Deep sleep is passed on to the device model, but its effects such as
+    /// stopped clocks are not simulated: the core wakes up the same way from
+    /// both sleep depths.
+    ///
+    /// If `wait_for_event` is set, any event wakes the processor (WFE),
+    /// otherwise only an exception that gets taken wakes it (WFI, sleep-on-exit).
+    ///
+    fn sleep(&mut self, wait_for_event: bool);
+
+    ///
+    /// Wake the processor up from sleep
+    ///
+    fn wake_up(&mut self);
+
+    ///
+    /// Check if processor is sleeping (either normal or deep sleep)
+    ///
+    fn sleeping(&self) -> bool;
+
+    ///
+    /// Check if processor is in deep sleep
+    ///
+    fn deep_sleeping(&self) -> bool;
+}
+
+impl Sleep for Processor {
+    fn set_event(&mut self) {
+        if self.state.get_bit(STATE_WAIT_FOR_EVENT) {
+            // event is consumed by the WFE that is waiting for it
+            self.wake_up();
+        } else {
+            self.event_reg = true;
+        }
+    }
+
+    fn clear_event(&mut self) -> bool {
+        let event = self.event_reg;
+        self.event_reg = false;
+        event
+    }
+
+    fn event_registered(&self) -> bool {
+        self.event_reg
+    }
+
+    fn set_event_on_pend(&mut self) {
+        if self.scr.get_bit(SCR_SEVONPEND) {
+            self.set_event();
+        }
+    }
+
+    fn sleep(&mut self, wait_for_event: bool) {
+        let deep = self.scr.get_bit(SCR_SLEEPDEEP);
+        self.state.set_bit(STATE_SLEEPING, true);
+        self.state.set_bit(STATE_DEEP_SLEEP, deep);
+        self.state.set_bit(STATE_WAIT_FOR_EVENT, wait_for_event);
+        self.device.sleep(deep);
+    }
+
+    fn wake_up(&mut self) {
+        if self.sleeping() {
+            self.state.set_bits(STATE_SLEEPING..STATE_WAIT_FOR_EVENT + 1, 0);
+            self.device.wake_up();
+        }
+    }
+
+    fn sleeping(&self) -> bool {
+        self.state.get_bit(STATE_SLEEPING)
+    }
+
+    fn deep_sleeping(&self) -> bool {
+        self.state.get_bit(STATE_SLEEPING) && self.state.get_bit(STATE_DEEP_SLEEP)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::exception::Exception;
+    use crate::core::exception::ExceptionHandling;
+    use crate::core::executor::Executor;
+    use crate::core::instruction::Instruction;
+    use crate::core::register::BaseReg;
+    use crate::core::reset::Reset;
+    use crate::peripheral::nvic::NVIC;
+    use crate::peripheral::scb::SystemControlBlock;
+
+    #[test]
+    fn test_sev_then_wfe_does_not_sleep() {
+        // Arrange
+        let mut processor = Processor::new();
+        processor.reset().unwrap();
+
+        // Act
+        processor.execute(&Instruction::SEV { thumb32: false }, 2);
+        processor.execute(&Instruction::WFE { thumb32: false }, 2);
+
+        // Assert
+        assert!(!processor.sleeping());
+        assert!(!processor.event_registered());
+    }
+
+    #[test]
+    fn test_wfe_sleeps_until_event() {
+        // Arrange
+        let mut processor = Processor::new();
+        processor.reset().unwrap();
+
+        // Act
+        processor.execute(&Instruction::WFE { thumb32: false }, 2);
+
+        // Assert
+        assert!(processor.sleeping());
+        assert!(!processor.deep_sleeping());
+
+        // Act
+        processor.set_event();
+
+        // Assert
+        assert!(!processor.sleeping());
+        assert!(!processor.event_registered());
+    }
+
+    #[test]
+    fn test_wfi_deep_sleep() {
+        // Arrange
+        let mut processor = Processor::new();
+        processor.reset().unwrap();
+        processor.write_scr(1 << SCR_SLEEPDEEP);
+
+        // Act
+        processor.execute(&Instruction::WFI { thumb32: false }, 2);
+
+        // Assert
+        assert!(processor.sleeping());
+        assert!(processor.deep_sleeping());
+
+        // WFI does not wake up on events
+        processor.set_event();
+        assert!(processor.deep_sleeping());
+        assert!(processor.event_registered());
+    }
+
+    #[test]
+    fn test_sevonpend_wakes_wfe_on_disabled_interrupt() {
+        // Arrange
+        let mut processor = Processor::new();
+        processor.reset().unwrap();
+        processor.write_scr(1 << SCR_SEVONPEND);
+        processor.execute(&Instruction::WFE { thumb32: false }, 2);
+        assert!(processor.sleeping());
+
+        // Act
+        processor.nvic_write_ispr(0, 1);
+        processor.step_sleep();
+
+        // Assert
+        assert!(!processor.sleeping());
+        assert_eq!(processor.get_pending_exception(), None);
+    }
+
+    #[test]
+    fn test_sevonpend_wakes_wfe_on_systick() {
+        // Arrange
+        let mut processor = Processor::new();
+        processor.reset().unwrap();
+        processor.write_scr(1 << SCR_SEVONPEND);
+        processor.execute(&Instruction::WFE { thumb32: false }, 2);
+
+        // Act
+        processor.set_exception_pending(Exception::SysTick);
+
+        // Assert
+        assert!(!processor.sleeping());
+        assert!(!processor.event_registered());
+    }
+
+    #[cfg(any(armv7m, armv7em))]
+    #[test]
+    fn test_sevonpend_wakes_wfe_on_software_interrupt() {
+        // Arrange
+        let mut processor = Processor::new();
+        processor.reset().unwrap();
+        processor.write_scr(1 << SCR_SEVONPEND);
+        processor.nvic_write_iser(0, 1);
+        processor.execute(&Instruction::WFE { thumb32: false }, 2);
+
+        // Act
+        processor.write_stir(0);
+
+        // Assert
+        assert!(!processor.sleeping());
+        // a single event, consumed by the WFE
+        assert!(!processor.event_registered());
+    }
+
+    #[test]
+    fn test_exception_entry_sets_event() {
+        // Arrange
+        let mut processor = Processor::new();
+        processor.reset().unwrap();
+        processor.set_msp(0x2000_0100);
+
+        // Act
+        let pc = processor.get_pc();
+        processor.exception_entry(Exception::SysTick, pc).unwrap();
+
+        // Assert
+        assert!(processor.event_registered());
+    }
+}
@@ -0,0 +1,236 @@
+//!
+//! Stack usage monitoring
+//!
+//! The main stack and the process stacks are followed after every executed
+//! instruction to find their low-water marks. Writing PSP with `MSR` is
+//! taken as a context switch: the new value selects the process stack whose
+//! range it falls into, or starts tracking a new one. Optionally the
+//! simulation is stopped when the main stack pointer drops below a limit.
+//!
+
+use crate::core::bits::Bits;
+use crate::core::instruction::Instruction;
+use crate::Processor;
+
+// SYSm value of PSP in MSR
+const SYSM_PSP: u8 = 0b01001;
+
+///
+/// Usage of a single stack
+///
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct StackUsage {
+    /// highest stack pointer value seen
+    pub top: u32,
+    /// lowest stack pointer value seen
+    pub low: u32,
+}
+
+impl StackUsage {
+    fn new(sp: u32) -> Self {
+        Self { top: sp, low: sp }
+    }
+
+    fn update(&mut self, sp: u32) {
+        self.top = self.top.max(sp);
+        self.low = self.low.min(sp);
+    }
+
+    fn contains(self, sp: u32) -> bool {
+        sp >= self.low && sp <= self.top
+    }
+
+    ///
+    /// Maximum depth of the stack in bytes
+    ///
+    #[must_use]
+    pub fn max_depth(&self) -> u32 {
+        self.top - self.low
+    }
+}
+
+///
+/// Main stack pointer dropping below the configured limit
+///
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct StackOverflow {
+    /// address of the instruction that moved the stack pointer below the limit
+    pub pc: u32,
+    /// value of the main stack pointer
+    pub sp: u32,
+    /// configured limit
+    pub limit: u32,
+}
+
+///
+/// Stack usage of a simulation run
+///
+#[derive(Debug, Clone)]
+pub struct StackMonitor {
+    msp_limit: Option<u32>,
+    main_stack: Option<StackUsage>,
+    process_stacks: Vec<StackUsage>,
+    current_process_stack: Option<usize>,
+    overflow: Option<StackOverflow>,
+}
+
+impl StackMonitor {
+    ///
+    /// Create a monitor, optionally stopping the simulation when the main
+    /// stack pointer drops below `msp_limit`
+    ///
+    #[must_use]
+    pub fn new(msp_limit: Option<u32>) -> Self {
+        Self {
+            msp_limit,
+            main_stack: None,
+            process_stacks: Vec::new(),
+            current_process_stack: None,
+            overflow: None,
+        }
+    }
+
+    ///
+    /// Usage of the main stack
+    ///
+    #[must_use]
+    pub fn main_stack(&self) -> Option<&StackUsage> {
+        self.main_stack.as_ref()
+    }
+
+    ///
+    /// Usage of the distinct process stacks, in the order they were first used
+    ///
+    #[must_use]
+    pub fn process_stacks(&self) -> &[StackUsage] {
+        &self.process_stacks
+    }
+
+    ///
+    /// The main stack overflow that stopped the simulation, if any
+    ///
+    #[must_use]
+    pub fn overflow(&self) -> Option<StackOverflow> {
+        self.overflow
+    }
+
+    fn update(&mut self, msp: u32, psp: u32, psp_written: bool) {
+        match &mut self.main_stack {
+            Some(usage) => usage.update(msp),
+            None => self.main_stack = Some(StackUsage::new(msp)),
+        }
+
+        if psp == 0 {
+            return;
+        }
+        if psp_written || self.current_process_stack.is_none() {
+            let found = self.process_stacks.iter().position(|s| s.contains(psp));
+            let index = if let Some(index) = found {
+                index
+            } else {
+                self.process_stacks.push(StackUsage::new(psp));
+                self.process_stacks.len() - 1
+            };
+            self.current_process_stack = Some(index);
+        }
+        if let Some(index) = self.current_process_stack {
+            self.process_stacks[index].update(psp);
+        }
+    }
+}
+
+///
+/// Trait for monitoring the stack usage of the processor
+///
+pub trait StackMonitoring {
+    ///
+    /// Follow the stack pointers after executing the instruction at `pc`
+    ///
+    fn stack_monitor_update(&mut self, instruction: &Instruction, pc: u32);
+
+    ///
+    /// Stack usage so far, if monitoring is enabled
+    ///
+    fn stack_usage(&self) -> Option<&StackMonitor>;
+}
+
+impl StackMonitoring for Processor {
+    #[inline(always)]
+    fn stack_monitor_update(&mut self, instruction: &Instruction, pc: u32) {
+        if let Some(monitor) = &mut self.stack_monitor {
+            let psp_written = match instruction {
+                Instruction::MSR_reg { sysm, .. } => *sysm == SYSM_PSP,
+                _ => false,
+            };
+            let msp = self.msp;
+            monitor.update(msp, self.psp, psp_written);
+
+            if let Some(limit) = monitor.msp_limit {
+                if msp < limit && monitor.overflow.is_none() {
+                    monitor.overflow = Some(StackOverflow { pc, sp: msp, limit });
+                    // stop the simulation
+                    self.state.set_bit(0, false);
+                }
+            }
+        }
+    }
+
+    fn stack_usage(&self) -> Option<&StackMonitor> {
+        self.stack_monitor.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::register::{BaseReg, Reg};
+
+    #[test]
+    fn test_process_stacks() {
+        let mut monitor = StackMonitor::new(None);
+
+        monitor.update(0x2000_1000, 0, false);
+        monitor.update(0x2000_0FE0, 0, false);
+        monitor.update(0x2000_1000, 0, false);
+        // task 1
+        monitor.update(0x2000_1000, 0x2000_2000, true);
+        monitor.update(0x2000_1000, 0x2000_1F00, false);
+        // task 2
+        monitor.update(0x2000_1000, 0x2000_3000, true);
+        monitor.update(0x2000_1000, 0x2000_2F80, false);
+        // back to task 1
+        monitor.update(0x2000_1000, 0x2000_1F40, true);
+        monitor.update(0x2000_1000, 0x2000_1E00, false);
+
+        assert_eq!(monitor.main_stack().unwrap().max_depth(), 0x20);
+        let depths: Vec<_> = monitor
+            .process_stacks()
+            .iter()
+            .map(StackUsage::max_depth)
+            .collect();
+        assert_eq!(depths, vec![0x200, 0x80]);
+    }
+
+    #[test]
+    fn test_main_stack_limit() {
+        let mut processor = Processor::new();
+        processor.stack_monitor(Some(StackMonitor::new(Some(0x2000_0F00))));
+        processor.state.set_bit(0, true);
+        processor.set_msp(0x2000_1000);
+        processor.stack_monitor_update(&Instruction::NOP { thumb32: false }, 0x100);
+        assert_eq!(processor.state & 1, 1);
+
+        processor.set_r(Reg::SP, 0x2000_0EFC);
+        processor.stack_monitor_update(&Instruction::NOP { thumb32: false }, 0x100);
+
+        assert_eq!(processor.state & 1, 0);
+        assert_eq!(
+            processor.stack_usage().unwrap().overflow(),
+            Some(StackOverflow {
+                pc: 0x100,
+                sp: 0x2000_0EFC,
+                limit: 0x2000_0F00,
+            })
+        );
+    }
+}
@@ -5,6 +5,7 @@
 
 use crate::bus::Bus;
 use crate::core::fault::Fault;
+use crate::memory::flash::{FlashErase, FlashMemory};
 
 ///
 ///
@@ -16,6 +17,31 @@ impl Device {
     pub fn new() -> Self {
         Self {}
     }
+
+    ///
+    /// Core entered sleep, `deep` is set for deep sleep (SCR.SLEEPDEEP)
+    pub fn sleep(&mut self, _deep: bool) {}
+
+    ///
+    /// Core woke up from sleep
+    pub fn wake_up(&mut self) {}
+
+    ///
+    /// Program a half-word of flash memory. Flash is read-only without a flash controller.
+    pub fn flash_program(
+        &mut self,
+        flash: &mut FlashMemory,
+        addr: u32,
+        value: u16,
+    ) -> Result<(), Fault> {
+        flash.write16(addr, value)
+    }
+
+    ///
+    /// Take the erase operation started via the flash controller, if any
+    pub fn flash_erase_request(&mut self) -> Option<FlashErase> {
+        None
+    }
 }
 
 impl Bus for Device {
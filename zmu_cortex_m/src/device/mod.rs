@@ -3,4 +3,6 @@
 //!
 
 pub mod generic;
+pub mod peripheral;
+pub mod remap;
 pub mod stm32f1xx;
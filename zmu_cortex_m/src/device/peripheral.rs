@@ -0,0 +1,432 @@
+//!
+//! Pluggable memory-mapped peripherals
+//!
+//! Peripheral models implementing the `Peripheral` trait can be attached to a
+//! `Processor` at an address range with `Processor::attach_peripheral`. Accesses
+//! to the range are routed to the model with offsets relative to the start
+//! of the range, and the model is clocked after every executed instruction.
+//!
+
+use crate::core::bits::Bits;
+use crate::core::exception::{Exception, ExceptionHandling};
+use crate::core::fault::Fault;
+use crate::memory::map::MemoryRemap;
+use crate::peripheral::nvic::NVIC;
+use crate::Processor;
+use std::cell::RefCell;
+
+const MAX_INTERRUPT_LINES: usize = 512;
+
+///
+/// Interrupt lines driven by peripherals.
+///
+/// Raising a line pends the interrupt. A line that is kept raised behaves
+/// like a level sensitive interrupt: it gets pended again after its handler
+/// has completed. Lines beyond the 512 supported interrupts are ignored.
+///
+#[derive(Default)]
+pub struct InterruptLines {
+    levels: [u32; 16],
+    edges: [u32; 16],
+}
+
+impl InterruptLines {
+    ///
+    /// Raise interrupt line `irqn`, making the interrupt pending
+    ///
+    pub fn raise(&mut self, irqn: usize) {
+        if irqn >= MAX_INTERRUPT_LINES {
+            return;
+        }
+        if !self.is_raised(irqn) {
+            self.edges[irqn >> 5].set_bit(irqn & 31, true);
+        }
+        self.levels[irqn >> 5].set_bit(irqn & 31, true);
+    }
+
+    ///
+    /// Lower interrupt line `irqn`. An interrupt that already became pending stays pending.
+    ///
+    pub fn lower(&mut self, irqn: usize) {
+        if irqn >= MAX_INTERRUPT_LINES {
+            return;
+        }
+        self.levels[irqn >> 5].set_bit(irqn & 31, false);
+    }
+
+    ///
+    /// Raise and lower interrupt line `irqn`, pending the interrupt once
+    ///
+    pub fn pulse(&mut self, irqn: usize) {
+        self.raise(irqn);
+        self.lower(irqn);
+    }
+
+    ///
+    /// Check if interrupt line `irqn` is raised
+    ///
+    #[must_use]
+    pub fn is_raised(&self, irqn: usize) -> bool {
+        irqn < MAX_INTERRUPT_LINES && self.levels[irqn >> 5].get_bit(irqn & 31)
+    }
+}
+
+///
+/// A memory-mapped peripheral model.
+///
+/// Offsets are relative to the start of the address range the peripheral
+/// is attached to. Only 32 bit accesses need to be implemented, the
+/// narrower accesses default to accessing the containing word.
+///
+pub trait Peripheral {
+    ///
+    /// Name of the peripheral, used in diagnostics
+    ///
+    fn name(&self) -> &str;
+
+    ///
+    /// Read a 32 bit register
+    ///
+    /// # Errors
+    ///
+    /// Fails with a bus fault if the register does not exist or does not
+    /// allow the access.
+    ///
+    fn read32(&mut self, offset: u32) -> Result<u32, Fault>;
+
+    ///
+    /// Write a 32 bit register
+    ///
+    /// # Errors
+    ///
+    /// Fails with a bus fault if the register does not exist or does not
+    /// allow the access.
+    ///
+    fn write32(&mut self, offset: u32, value: u32) -> Result<(), Fault>;
+
+    ///
+    /// Read 16 bits
+    ///
+    /// # Errors
+    ///
+    /// Fails if the access to the containing word fails.
+    ///
+    fn read16(&mut self, offset: u32) -> Result<u16, Fault> {
+        Ok((self.read32(offset & !3)? >> ((offset & 2) * 8)) as u16)
+    }
+
+    ///
+    /// Read 8 bits
+    ///
+    /// # Errors
+    ///
+    /// Fails if the access to the containing word fails.
+    ///
+    fn read8(&mut self, offset: u32) -> Result<u8, Fault> {
+        Ok((self.read32(offset & !3)? >> ((offset & 3) * 8)) as u8)
+    }
+
+    ///
+    /// Write 16 bits, by default with a read-modify-write of the containing word
+    ///
+    /// # Errors
+    ///
+    /// Fails if the access to the containing word fails.
+    ///
+    fn write16(&mut self, offset: u32, value: u16) -> Result<(), Fault> {
+        let shift = (offset & 2) * 8;
+        let word = self.read32(offset & !3)?;
+        self.write32(
+            offset & !3,
+            (word & !(0xffff << shift)) | (u32::from(value) << shift),
+        )
+    }
+
+    ///
+    /// Write 8 bits, by default with a read-modify-write of the containing word
+    ///
+    /// # Errors
+    ///
+    /// Fails if the access to the containing word fails.
+    ///
+    fn write8(&mut self, offset: u32, value: u8) -> Result<(), Fault> {
+        let shift = (offset & 3) * 8;
+        let word = self.read32(offset & !3)?;
+        self.write32(
+            offset & !3,
+            (word & !(0xff << shift)) | (u32::from(value) << shift),
+        )
+    }
+
+    ///
+    /// Advance the peripheral by `cycles` processor clock cycles
+    ///
+    fn tick(&mut self, _cycles: u32, _interrupts: &mut InterruptLines) {}
+
+    ///
+    /// Reset the peripheral state
+    ///
+    fn reset(&mut self) {}
+
+    ///
+    /// Take the change of memory mapping requested via the peripheral
+    /// registers, if any. Polled after writes to the peripheral and after reset.
+    ///
+    fn memory_remap_request(&mut self) -> Option<MemoryRemap> {
+        None
+    }
+}
+
+///
+/// A peripheral attached to an address range
+///
+pub struct AttachedPeripheral {
+    /// start address of the range
+    pub base: u32,
+    /// size of the range in bytes
+    pub size: usize,
+    /// the peripheral model, borrowed mutably also on reads
+    pub peripheral: RefCell<Box<dyn Peripheral>>,
+}
+
+impl AttachedPeripheral {
+    ///
+    /// Check if the address belongs to the range of this peripheral
+    ///
+    pub fn in_range(&self, address: u32) -> bool {
+        address >= self.base && ((address - self.base) as usize) < self.size
+    }
+}
+
+///
+/// Trait for driving the attached peripherals
+///
+pub trait Peripherals {
+    ///
+    /// Find the peripheral attached to the given address
+    ///
+    fn attached_peripheral(&self, address: u32) -> Option<&AttachedPeripheral>;
+
+    ///
+    /// Clock the attached peripherals and pend the interrupts they raise
+    ///
+    fn peripherals_tick(&mut self, cycles: u32);
+
+    ///
+    /// Reset the attached peripherals
+    ///
+    fn peripherals_reset(&mut self);
+
+    ///
+    /// Apply the memory mapping changes requested by the attached peripherals
+    ///
+    fn peripherals_remap(&mut self);
+}
+
+impl Peripherals for Processor {
+    fn attached_peripheral(&self, address: u32) -> Option<&AttachedPeripheral> {
+        self.peripherals.iter().find(|p| p.in_range(address))
+    }
+
+    #[inline(always)]
+    fn peripherals_tick(&mut self, cycles: u32) {
+        if self.peripherals.is_empty() {
+            return;
+        }
+        for attached in &self.peripherals {
+            attached
+                .peripheral
+                .borrow_mut()
+                .tick(cycles, &mut self.interrupt_lines);
+        }
+
+        for index in 0..16 {
+            let edges = self.interrupt_lines.edges[index];
+            let mut lines = self.interrupt_lines.levels[index] | edges;
+            if lines == 0 {
+                continue;
+            }
+            self.interrupt_lines.edges[index] = 0;
+            while lines != 0 {
+                let bit = lines.trailing_zeros() as usize;
+                lines &= lines - 1;
+                let exception = Exception::Interrupt {
+                    n: index * 32 + bit,
+                };
+                if !self.exceptions.contains_key(&usize::from(exception)) {
+                    continue;
+                }
+                // a raised line is pended again once the previous activation is over
+                if edges.get_bit(bit)
+                    || (!self.nvic_interrupt_pending[index].get_bit(bit)
+                        && !self.exception_active(exception))
+                {
+                    self.nvic_write_ispr(index, 1 << bit);
+                }
+            }
+        }
+    }
+
+    fn peripherals_reset(&mut self) {
+        self.interrupt_lines = InterruptLines::default();
+        for attached in &self.peripherals {
+            attached.peripheral.borrow_mut().reset();
+        }
+        self.peripherals_remap();
+    }
+
+    fn peripherals_remap(&mut self) {
+        let mut requests = Vec::new();
+        for attached in &self.peripherals {
+            while let Some(remap) = attached.peripheral.borrow_mut().memory_remap_request() {
+                requests.push(remap);
+            }
+        }
+        for remap in requests {
+            self.remap_memory(remap);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::core::reset::Reset;
+
+    struct Timer {
+        counter: u32,
+        reload: u32,
+    }
+
+    impl Peripheral for Timer {
+        fn name(&self) -> &str {
+            "TIMER"
+        }
+
+        fn read32(&mut self, offset: u32) -> Result<u32, Fault> {
+            match offset {
+                0 => Ok(self.counter),
+                4 => Ok(self.reload),
+                _ => Err(Fault::DAccViol),
+            }
+        }
+
+        fn write32(&mut self, offset: u32, value: u32) -> Result<(), Fault> {
+            match offset {
+                0 => self.counter = value,
+                4 => self.reload = value,
+                _ => return Err(Fault::DAccViol),
+            }
+            Ok(())
+        }
+
+        fn tick(&mut self, cycles: u32, interrupts: &mut InterruptLines) {
+            if self.reload == 0 {
+                return;
+            }
+            self.counter += cycles;
+            if self.counter >= self.reload {
+                self.counter = 0;
+                interrupts.pulse(3);
+            }
+        }
+
+        fn reset(&mut self) {
+            self.counter = 0;
+            self.reload = 0;
+        }
+    }
+
+    fn make_processor() -> Processor {
+        let mut processor = Processor::new();
+        processor.attach_peripheral(
+            0x5000_0000,
+            0x100,
+            Box::new(Timer {
+                counter: 0,
+                reload: 0,
+            }),
+        );
+        processor.reset().unwrap();
+        processor
+    }
+
+    #[test]
+    fn test_attached_peripheral_access() {
+        let mut processor = make_processor();
+
+        processor.write32(0x5000_0004, 0x1234_5678).unwrap();
+        assert_eq!(processor.read32(0x5000_0004).unwrap(), 0x1234_5678);
+        assert_eq!(processor.read16(0x5000_0006).unwrap(), 0x1234);
+        processor.write8(0x5000_0005, 0xAA).unwrap();
+        assert_eq!(processor.read8(0x5000_0005).unwrap(), 0xAA);
+        assert_eq!(processor.read32(0x5000_0004).unwrap(), 0x1234_AA78);
+        assert_eq!(processor.read32(0x5000_0008), Err(Fault::DAccViol));
+        assert_eq!(
+            processor
+                .attached_peripheral(0x5000_00FF)
+                .unwrap()
+                .peripheral
+                .borrow()
+                .name(),
+            "TIMER"
+        );
+        assert!(processor.attached_peripheral(0x5000_0100).is_none());
+    }
+
+    #[test]
+    fn test_peripheral_interrupt() {
+        let mut processor = make_processor();
+        processor.nvic_write_iser(0, 1 << 3);
+        processor.write32(0x5000_0004, 10).unwrap();
+
+        processor.peripherals_tick(5);
+        assert!(!processor.exception_pending(Exception::Interrupt { n: 3 }));
+
+        processor.peripherals_tick(5);
+        assert!(processor.exception_pending(Exception::Interrupt { n: 3 }));
+        assert_eq!(processor.nvic_read_ispr(0), 1 << 3);
+    }
+
+    #[test]
+    fn test_level_interrupt_is_pended_again() {
+        let mut processor = make_processor();
+        processor.nvic_write_iser(0, 1 << 1);
+
+        processor.interrupt_lines.raise(1);
+        processor.peripherals_tick(1);
+        assert_eq!(processor.nvic_read_ispr(0), 1 << 1);
+
+        processor.nvic_write_icpr(0, 1 << 1);
+        processor.peripherals_tick(1);
+        assert_eq!(processor.nvic_read_ispr(0), 1 << 1);
+
+        processor.interrupt_lines.lower(1);
+        processor.nvic_write_icpr(0, 1 << 1);
+        processor.peripherals_tick(1);
+        assert_eq!(processor.nvic_read_ispr(0), 0);
+    }
+
+    #[test]
+    fn test_reset_lowers_lines() {
+        let mut processor = make_processor();
+        processor.write32(0x5000_0004, 10).unwrap();
+        processor.interrupt_lines.raise(1);
+
+        processor.reset().unwrap();
+
+        assert!(!processor.interrupt_lines.is_raised(1));
+        assert_eq!(processor.read32(0x5000_0004).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_out_of_range_lines_are_ignored() {
+        let mut lines = InterruptLines::default();
+        lines.raise(512);
+        lines.pulse(usize::MAX);
+        assert!(!lines.is_raised(512));
+        lines.raise(511);
+        assert!(lines.is_raised(511));
+    }
+}
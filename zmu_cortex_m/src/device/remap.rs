@@ -0,0 +1,128 @@
+//!
+//! Memory remap register
+//!
+//! A generic model of a SYSCFG style memory remap register: the value of
+//! the register selects which memory is aliased at a fixed address range,
+//! typically the boot memory at address 0. At reset the register takes the
+//! value selected by the boot pins.
+//!
+
+use crate::core::fault::Fault;
+use crate::device::peripheral::Peripheral;
+use crate::memory::map::{MemoryMapConfig, MemoryRemap};
+
+///
+/// Memory remap register, attached as a peripheral
+///
+pub struct MemoryRemapRegister {
+    name: String,
+    source: u32,
+    length: usize,
+    targets: Vec<u32>,
+    boot: usize,
+    mode: usize,
+    changed: bool,
+}
+
+impl MemoryRemapRegister {
+    ///
+    /// Create a register aliasing `length` bytes from `source` to one of
+    /// `targets`, with `boot` selecting the target at reset
+    ///
+    #[must_use]
+    pub fn new(name: &str, source: u32, length: usize, targets: &[u32], boot: usize) -> Self {
+        Self {
+            name: name.to_string(),
+            source,
+            length,
+            targets: targets.to_vec(),
+            boot,
+            mode: boot,
+            changed: true,
+        }
+    }
+}
+
+impl Peripheral for MemoryRemapRegister {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn read32(&mut self, offset: u32) -> Result<u32, Fault> {
+        match offset {
+            0 => Ok(self.mode as u32),
+            _ => Ok(0),
+        }
+    }
+
+    fn write32(&mut self, offset: u32, value: u32) -> Result<(), Fault> {
+        // values without a target are reserved and ignored
+        let mode = value as usize;
+        if offset == 0 && mode < self.targets.len() && mode != self.mode {
+            self.mode = mode;
+            self.changed = true;
+        }
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.mode = self.boot;
+        self.changed = true;
+    }
+
+    fn memory_remap_request(&mut self) -> Option<MemoryRemap> {
+        if !self.changed {
+            return None;
+        }
+        self.changed = false;
+        Some(MemoryRemap::Map(MemoryMapConfig::new(
+            self.source,
+            self.targets[self.mode],
+            self.length,
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::core::reset::Reset;
+    use crate::memory::config::MemoryRegionConfig;
+    use crate::Processor;
+
+    #[test]
+    fn test_boot_remap() {
+        let mut processor = Processor::new();
+        processor.memory_regions(&[
+            MemoryRegionConfig::ram("SRAM", 0x2000_0000, 0x1000),
+            MemoryRegionConfig::remap(
+                "SYSCFG",
+                0x4001_3800,
+                0,
+                0x1000,
+                &[0x2000_1000, 0x2000_0000],
+                1,
+            ),
+        ]);
+        processor.write32(0x2000_0000, 0x2000_0800).unwrap();
+        processor.write32(0x2000_0004, 0x0000_0101).unwrap();
+        processor.reset().unwrap();
+
+        // boot from SRAM aliased at 0
+        assert_eq!(processor.msp, 0x2000_0800);
+        assert_eq!(processor.read32(0x4001_3800).unwrap(), 1);
+
+        // remap away from SRAM
+        processor.write32(0x4001_3800, 0).unwrap();
+        assert!(processor.read32(0).is_err());
+
+        // reserved value is ignored
+        processor.write32(0x4001_3800, 7).unwrap();
+        assert_eq!(processor.read32(0x4001_3800).unwrap(), 0);
+
+        // reset restores the boot mapping
+        processor.reset().unwrap();
+        assert_eq!(processor.read32(4).unwrap(), 0x0000_0101);
+    }
+}
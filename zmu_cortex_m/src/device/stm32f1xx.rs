@@ -72,10 +72,26 @@ const RCC_BASE: u32 = AHBPERIPH_BASE + 0x1000;
 const RCC_BASE_END: u32 = RCC_BASE + 0x24;
 
 const FLASH_R_BASE: u32 = AHBPERIPH_BASE + 0x2000;
-const FLASH_R_BASE_END: u32 = FLASH_R_BASE + 0x04;
+const FLASH_R_BASE_END: u32 = FLASH_R_BASE + 0x24;
+
+const FLASH_KEY1: u32 = 0x4567_0123;
+const FLASH_KEY2: u32 = 0xCDEF_89AB;
+const FLASH_PAGE_SIZE: usize = 1024;
+
+const FLASH_SR_BSY: usize = 0;
+const FLASH_SR_PGERR: usize = 2;
+const FLASH_SR_WRPRTERR: usize = 4;
+const FLASH_SR_EOP: usize = 5;
+
+const FLASH_CR_PG: usize = 0;
+const FLASH_CR_PER: usize = 1;
+const FLASH_CR_MER: usize = 2;
+const FLASH_CR_STRT: usize = 6;
+const FLASH_CR_LOCK: usize = 7;
 
 use crate::bus::Bus;
 use crate::core::fault::Fault;
+use crate::memory::flash::{FlashErase, FlashMemory};
 
 #[allow(non_snake_case)]
 struct RCCRegisters {
@@ -159,6 +175,33 @@ struct FLASHRegisters {
     /// 0
     ///
     ACR: u32,
+    ///
+    /// 0xc
+    ///
+    SR: u32,
+    ///
+    /// 0x10
+    ///
+    CR: u32,
+    ///
+    /// 0x14
+    ///
+    AR: u32,
+    ///
+    /// 0x1c
+    ///
+    OBR: u32,
+    ///
+    /// 0x20
+    ///
+    WRPR: u32,
+
+    /// KEY1 of the unlock sequence has been written
+    key1_written: bool,
+    /// a wrong unlock sequence keeps the FPEC locked until next reset
+    key_error: bool,
+    /// erase started via CR.STRT, waiting to be applied to the flash
+    erase_request: Option<FlashErase>,
 }
 
 #[allow(non_snake_case)]
@@ -328,7 +371,17 @@ impl Device {
                     LCKR: 0x0,
                 },
             ],
-            flash: FLASHRegisters { ACR: 0x30 },
+            flash: FLASHRegisters {
+                ACR: 0x30,
+                SR: 0,
+                CR: 0x80,
+                AR: 0,
+                OBR: 0x03FF_FFFC,
+                WRPR: 0xFFFF_FFFF,
+                key1_written: false,
+                key_error: false,
+                erase_request: None,
+            },
             tim1_8: [
                 AdvancedControlTimerRegisters {
                     gp: GeneralPurposeTimer2Registers {
@@ -606,6 +659,41 @@ impl Device {
             ],
         }
     }
+
+    ///
+    /// Core entered sleep, `deep` is set for deep sleep (SCR.SLEEPDEEP).
+    /// Deep sleep corresponds to the Stop/Standby modes of the device.
+    pub fn sleep(&mut self, _deep: bool) {}
+
+    ///
+    /// Core woke up from sleep
+    pub fn wake_up(&mut self) {}
+
+    ///
+    /// Program a half-word of flash memory, as requested by a bus write to `addr`.
+    /// The flash controller needs to be unlocked and in programming mode (CR.PG).
+    pub fn flash_program(
+        &mut self,
+        flash: &mut FlashMemory,
+        addr: u32,
+        value: u16,
+    ) -> Result<(), Fault> {
+        if !self.flash.CR.get_bit(FLASH_CR_PG) || self.flash.CR.get_bit(FLASH_CR_LOCK) {
+            return Err(Fault::DAccViol);
+        }
+        if flash.program16(addr, value) {
+            self.flash.SR.set_bit(FLASH_SR_EOP, true);
+        } else {
+            self.flash.SR.set_bit(FLASH_SR_PGERR, true);
+        }
+        Ok(())
+    }
+
+    ///
+    /// Take the erase operation started via the flash controller, if any
+    pub fn flash_erase_request(&mut self) -> Option<FlashErase> {
+        self.flash.erase_request.take()
+    }
 }
 
 trait AFIO {
@@ -816,6 +904,59 @@ impl FLASH for Device {
                 // PRFTBE -> PRFTBS
                 self.flash.ACR.set_bit(5, self.flash.ACR.get_bit(4));
             }
+            0x4 => {
+                // KEYR: KEY1 followed by KEY2 unlocks the FPEC and CR
+                if self.flash.key_error || !self.flash.CR.get_bit(FLASH_CR_LOCK) {
+                    self.flash.key_error = true;
+                    return Err(Fault::DAccViol);
+                }
+                match (self.flash.key1_written, value) {
+                    (false, FLASH_KEY1) => self.flash.key1_written = true,
+                    (true, FLASH_KEY2) => {
+                        self.flash.key1_written = false;
+                        self.flash.CR.set_bit(FLASH_CR_LOCK, false);
+                    }
+                    _ => {
+                        self.flash.key_error = true;
+                        return Err(Fault::DAccViol);
+                    }
+                }
+            }
+            0x8 => {
+                // OPTKEYR: option bytes are not emulated
+            }
+            0xC => {
+                // PGERR, WRPRTERR and EOP are cleared by writing one
+                let w1c_mask = (1 << FLASH_SR_PGERR) | (1 << FLASH_SR_WRPRTERR) | (1 << FLASH_SR_EOP);
+                self.flash.SR &= !(value & w1c_mask);
+            }
+            0x10 => {
+                if self.flash.CR.get_bit(FLASH_CR_LOCK) {
+                    // only LOCK can be written while locked
+                    return Ok(());
+                }
+                // PG, PER, MER, OPTPG, OPTER, STRT, LOCK, OPTWRE, ERRIE, EOPIE
+                self.flash.CR = value & 0b1_0110_1111_0111;
+                if self.flash.CR.get_bit(FLASH_CR_STRT) {
+                    if self.flash.CR.get_bit(FLASH_CR_MER) {
+                        self.flash.erase_request = Some(FlashErase::Mass);
+                    } else if self.flash.CR.get_bit(FLASH_CR_PER) {
+                        self.flash.erase_request = Some(FlashErase::Page {
+                            address: self.flash.AR,
+                            size: FLASH_PAGE_SIZE,
+                        });
+                    }
+                    // erase completes immediately, BSY is never observed set
+                    self.flash.CR.set_bit(FLASH_CR_STRT, false);
+                    self.flash.SR.set_bit(FLASH_SR_BSY, false);
+                    self.flash.SR.set_bit(FLASH_SR_EOP, true);
+                }
+            }
+            0x14 => {
+                if !self.flash.CR.get_bit(FLASH_CR_LOCK) {
+                    self.flash.AR = value;
+                }
+            }
             _ => return Err(Fault::DAccViol),
         }
 
@@ -825,6 +966,11 @@ impl FLASH for Device {
     fn flash_read32(&mut self, offset: u32) -> Result<u32, Fault> {
         let result = match offset {
             0x0 => self.flash.ACR,
+            0x4 | 0x8 | 0x14 => 0,
+            0xC => self.flash.SR,
+            0x10 => self.flash.CR,
+            0x1C => self.flash.OBR,
+            0x20 => self.flash.WRPR,
             _ => return Err(Fault::DAccViol),
         };
 
@@ -1227,4 +1373,80 @@ mod tests {
         }
     }
 
+    fn unlock_flash(device: &mut Device) -> Result<(), Fault> {
+        device.flash_write32(0x4, FLASH_KEY1)?;
+        device.flash_write32(0x4, FLASH_KEY2)
+    }
+
+    #[test]
+    fn test_flash_unlock() -> Result<(), Fault> {
+        let mut device = Device::new();
+        assert_eq!(device.flash_read32(0x10)?, 0x80);
+
+        // CR is read-only while locked
+        device.flash_write32(0x10, 1)?;
+        assert_eq!(device.flash_read32(0x10)?, 0x80);
+
+        unlock_flash(&mut device)?;
+        assert_eq!(device.flash_read32(0x10)?, 0);
+
+        device.flash_write32(0x10, 0x80)?;
+        assert_eq!(device.flash_read32(0x10)?, 0x80);
+        Ok(())
+    }
+
+    #[test]
+    fn test_flash_wrong_key_locks_until_reset() {
+        let mut device = Device::new();
+        assert_eq!(device.flash_write32(0x4, 0x1234), Err(Fault::DAccViol));
+        assert_eq!(unlock_flash(&mut device), Err(Fault::DAccViol));
+        assert_eq!(device.flash_read32(0x10).unwrap(), 0x80);
+    }
+
+    #[test]
+    fn test_flash_program() -> Result<(), Fault> {
+        let mut device = Device::new();
+        let mut flash = FlashMemory::new_with_fill(0, 2048, 0xff);
+
+        assert_eq!(
+            device.flash_program(&mut flash, 0x10, 0x1234),
+            Err(Fault::DAccViol)
+        );
+
+        unlock_flash(&mut device)?;
+        device.flash_write32(0x10, 1 << FLASH_CR_PG)?;
+        device.flash_program(&mut flash, 0x10, 0x1234)?;
+        assert_eq!(flash.read16(0x10)?, 0x1234);
+        assert_eq!(device.flash_read32(0xC)?, 1 << FLASH_SR_EOP);
+
+        device.flash_write32(0xC, 1 << FLASH_SR_EOP)?;
+        device.flash_program(&mut flash, 0x10, 0x5678)?;
+        assert_eq!(flash.read16(0x10)?, 0x1234);
+        assert_eq!(device.flash_read32(0xC)?, 1 << FLASH_SR_PGERR);
+        Ok(())
+    }
+
+    #[test]
+    fn test_flash_erase_request() -> Result<(), Fault> {
+        let mut device = Device::new();
+        unlock_flash(&mut device)?;
+
+        device.flash_write32(0x10, 1 << FLASH_CR_PER)?;
+        device.flash_write32(0x14, 0x0800_0400)?;
+        device.flash_write32(0x10, (1 << FLASH_CR_PER) | (1 << FLASH_CR_STRT))?;
+        assert_eq!(
+            device.flash_erase_request(),
+            Some(FlashErase::Page {
+                address: 0x0800_0400,
+                size: FLASH_PAGE_SIZE
+            })
+        );
+        assert_eq!(device.flash_erase_request(), None);
+        assert_eq!(device.flash_read32(0x10)?, 1 << FLASH_CR_PER);
+
+        device.flash_write32(0x10, (1 << FLASH_CR_MER) | (1 << FLASH_CR_STRT))?;
+        assert_eq!(device.flash_erase_request(), Some(FlashErase::Mass));
+        Ok(())
+    }
+
 }
@@ -34,20 +34,32 @@ pub mod device;
 
 
 
+use crate::bus::trace::BusTrace;
+use crate::bus::Bus;
 use crate::core::instruction::instruction_size;
+use crate::core::bits::Bits;
+use crate::core::coverage::Coverage;
 
 use crate::core::exception::Exception;
 use crate::core::fetch::Fetch;
-use crate::core::instruction::Instruction;
+use crate::core::icache::{DecodeCache, InstructionCache};
+use crate::core::memcheck::Memcheck;
+use crate::core::profile::Profiler;
+use crate::core::stack::StackMonitor;
 use crate::core::register::{Apsr, BaseReg, Control, Reg, PSR};
 use crate::decoder::Decoder;
+use crate::system::simulation::RunLimits;
+use crate::device::peripheral::{AttachedPeripheral, InterruptLines, Peripheral};
+use crate::device::remap::MemoryRemapRegister;
 use crate::memory::flash::FlashMemory;
-use crate::memory::map::MemoryMapConfig;
-use crate::memory::ram::RAM;
+use crate::memory::config::{MemoryKind, MemoryRegionConfig};
+use crate::memory::map::{MemoryMapConfig, MemoryRemap};
+use crate::memory::region::MemoryRegion;
 use crate::semihosting::SemihostingCommand;
 use crate::semihosting::SemihostingResponse;
 
 use crate::core::exception::ExceptionState;
+use std::cell::RefCell;
 use std::collections::HashMap;
 use std::fmt;
 use std::io;
@@ -121,8 +133,15 @@ pub struct Processor {
     ///
     /// bit 0 : 1= simulation running, 0 : simulation terminating
     /// bit 1 : 1= processor sleeping, 0 : processor awake
+    /// bit 2 : 1= deep sleep, 0 : normal sleep (valid when sleeping)
+    /// bit 3 : 1= sleeping in WFE, woken up by any event
     pub state: u32,
 
+    ///
+    /// event register for WFE / SEV
+    ///
+    event_reg: bool,
+
     ///
     /// lookup table for exceptions and their states
     ///
@@ -146,9 +165,9 @@ pub struct Processor {
     ///
     pub code: FlashMemory,
     ///
-    /// ram data
+    /// RAM and ROM regions of the memory map
     ///
-    pub sram: RAM,
+    pub regions: Vec<MemoryRegion>,
 
     pub cpuid: u32,
     pub icsr: u32,
@@ -196,13 +215,45 @@ pub struct Processor {
     ///
     semihost_func: Option<Box<dyn FnMut(&SemihostingCommand) -> SemihostingResponse>>,
 
-    instruction_cache: Vec<(Instruction, usize)>,
+    instruction_cache: Vec<DecodeCache>,
 
     pub last_pc: u32,
 
-    mem_map: Option<MemoryMapConfig>,
+    /// Exception number the traced instruction ran in, 0 in thread mode
+    pub last_isr_number: usize,
+
+    mem_map: Vec<MemoryMapConfig>,
+
+    pub device : Device,
+
+    peripherals: Vec<AttachedPeripheral>,
+
+    interrupt_lines: InterruptLines,
+
+    bus_trace: Option<BusTrace>,
+
+    memcheck: Option<RefCell<Memcheck>>,
+
+    /// Address ranges loaded to the memory regions with `load_memory`
+    loaded_ranges: Vec<(u32, usize)>,
+
+    stack_monitor: Option<StackMonitor>,
+
+    profiler: Option<Profiler>,
+
+    coverage: Option<Coverage>,
+
+    initial_sp: Option<u32>,
+
+    entry_point: Option<u32>,
 
-    pub device : Device
+    /// address of the fault that locked up the processor
+    lockup: Option<u32>,
+
+    limits: RunLimits,
+
+    /// exit status given by the application when stopping
+    exit_code: Option<u32>,
 }
 
 fn make_default_exception_priorities() -> HashMap<usize, ExceptionState> {
@@ -280,6 +331,8 @@ impl Processor {
             control: Control {
                 n_priv: false,
                 sp_sel: false,
+                #[cfg(armv7em)]
+                fpca: false,
             },
             r0_12: [0; 13],
             pc: 0,
@@ -287,10 +340,16 @@ impl Processor {
             psp: 0,
             lr: 0,
             code: FlashMemory::new(65536, &[0; 65536]),
-            // TODO make RAM size configurable
-            sram: RAM::new_with_fill(0x2000_0000, 128 * 1024, 0xcd),
+            // default memory map, replaced with `memory_regions`
+            regions: vec![MemoryRegion::from_config(&MemoryRegionConfig::ram(
+                "SRAM",
+                0x2000_0000,
+                128 * 1024,
+            ))
+            .unwrap()],
             itm_file: None,
             state: 0,
+            event_reg: false,
             cycle_count: 0,
             instruction_count: 0,
             exceptions: make_default_exception_priorities(),
@@ -302,7 +361,12 @@ impl Processor {
             icsr: 0,
             aircr: 0,
             scr: 0,
-            ccr: 0,
+            // STKALIGN, UNALIGN_TRP
+            #[cfg(armv6m)]
+            ccr: 0x208,
+            // STKALIGN
+            #[cfg(any(armv7m, armv7em))]
+            ccr: 0x200,
             shcsr: 0,
             cfsr: 0,
             dfsr: 0,
@@ -332,20 +396,258 @@ impl Processor {
             syst_csr: 0,
             instruction_cache: Vec::new(),
             last_pc: 0,
-            mem_map: None,
-            device : Device::new()
+            last_isr_number: 0,
+            mem_map: Vec::new(),
+            device : Device::new(),
+            peripherals: Vec::new(),
+            interrupt_lines: InterruptLines::default(),
+            bus_trace: None,
+            memcheck: None,
+            loaded_ranges: Vec::new(),
+            stack_monitor: None,
+            profiler: None,
+            coverage: None,
+            initial_sp: None,
+            entry_point: None,
+            lockup: None,
+            limits: RunLimits::default(),
+            exit_code: None,
         }
     }
 
+    ///
+    /// Check if the processor currently executes in privileged mode.
+    /// Handler mode is always privileged, thread mode depends on CONTROL.nPRIV.
+    ///
+    pub fn current_mode_is_privileged(&self) -> bool {
+        self.mode == ProcessorMode::HandlerMode || !self.control.n_priv
+    }
+
+    ///
+    /// Configure the data endianness, reported in AIRCR.ENDIANNESS. In the
+    /// big-endian (BE8) configuration data accesses are big-endian, while
+    /// instruction fetches and Private Peripheral Bus accesses stay little-endian.
+    ///
+    pub fn big_endian(&mut self, big_endian: bool) -> &mut Self {
+        self.aircr.set_bit(15, big_endian);
+        self
+    }
+
+    ///
+    /// Check if data accesses are big-endian
+    ///
+    pub fn is_big_endian(&self) -> bool {
+        self.aircr.get_bit(15)
+    }
+
+    ///
+    /// Set the vector table address used at reset, as on cores where the
+    /// reset value of VTOR is configurable
+    ///
+    pub fn vector_table(&mut self, address: u32) -> &mut Self {
+        self.vtor = address;
+        self
+    }
+
+    ///
+    /// Override the initial main stack pointer, otherwise read from the vector table at reset
+    ///
+    pub fn initial_sp(&mut self, sp: Option<u32>) -> &mut Self {
+        self.initial_sp = sp;
+        self
+    }
+
+    ///
+    /// Override the entry point, otherwise read from the reset vector at reset
+    ///
+    pub fn entry_point(&mut self, pc: Option<u32>) -> &mut Self {
+        self.entry_point = pc;
+        self
+    }
+
     /// Configure flash memory
     pub fn flash_memory<'a>(&'a mut self, flash_size: usize, code: &[u8]) -> &'a mut Self {
-        self.code = FlashMemory::new(flash_size, code);
+        self.flash_memory_at(0, flash_size, code)
+    }
+
+    /// Configure flash memory starting from given address
+    pub fn flash_memory_at<'a>(
+        &'a mut self,
+        base: u32,
+        flash_size: usize,
+        code: &[u8],
+    ) -> &'a mut Self {
+        self.code = FlashMemory::new_at(base, flash_size, code);
         self
     }
 
-    /// Configure memory mapping
+    /// Add memory mapping
     pub fn memory_map(&mut self, map: Option<MemoryMapConfig>) -> &mut Self {
-        self.mem_map = map;
+        if let Some(map) = map {
+            self.mem_map.push(map);
+        }
+        self
+    }
+
+    ///
+    /// Change the memory mappings at runtime. A new mapping takes precedence
+    /// over the existing mappings it overlaps.
+    ///
+    pub fn remap_memory(&mut self, remap: MemoryRemap) -> &mut Self {
+        match remap {
+            MemoryRemap::Map(map) => {
+                self.mem_map.retain(|m| m.source() != map.source());
+                self.mem_map.insert(0, map);
+            }
+            MemoryRemap::Unmap { source } => self.mem_map.retain(|m| m.source() != source),
+        }
+        self
+    }
+
+    ///
+    /// Current memory mappings, in the order of precedence
+    ///
+    pub fn memory_maps(&self) -> &[MemoryMapConfig] {
+        &self.mem_map
+    }
+
+    ///
+    /// Configure memory regions. RAM and ROM regions, if any are given,
+    /// replace the default RAM. Alias regions are added as memory mappings
+    /// and remap registers are attached as peripherals.
+    ///
+    pub fn memory_regions(&mut self, regions: &[MemoryRegionConfig]) -> &mut Self {
+        let memories: Vec<_> = regions
+            .iter()
+            .filter_map(MemoryRegion::from_config)
+            .collect();
+        if !memories.is_empty() {
+            self.regions = memories;
+        }
+        for region in regions {
+            match &region.kind {
+                MemoryKind::Alias { target } => {
+                    self.mem_map
+                        .push(MemoryMapConfig::new(region.base, *target, region.size));
+                }
+                MemoryKind::Remap {
+                    source,
+                    length,
+                    targets,
+                    boot,
+                } => {
+                    let register =
+                        MemoryRemapRegister::new(&region.name, *source, *length, targets, *boot);
+                    self.attach_peripheral(region.base, region.size, Box::new(register));
+                }
+                _ => (),
+            }
+        }
+        self
+    }
+
+    ///
+    /// Find the memory region containing given address
+    ///
+    pub fn memory_region(&self, address: u32) -> Option<&MemoryRegion> {
+        self.regions.iter().find(|region| region.in_range(address))
+    }
+
+    ///
+    /// Load data directly to the flash or the memory region containing
+    /// `address`, ignoring access rights, as a debugger would
+    ///
+    pub fn load_memory(&mut self, address: u32, data: &[u8]) -> Result<(), String> {
+        let end = u64::from(address) + data.len() as u64;
+        if let Some(region) = self.regions.iter_mut().find(|r| r.in_range(address)) {
+            if !region.contains(address, data.len()) {
+                return Err(format!(
+                    "data at 0x{:08x}..0x{:08x} does not fit in memory region '{}'",
+                    address, end, region.name
+                ));
+            }
+            region.load(address, data);
+            self.loaded_ranges.push((address, data.len()));
+        } else if self.code.in_range(address) {
+            if end > u64::from(self.code.start_address()) + self.code.len() as u64 {
+                return Err(format!(
+                    "data at 0x{:08x}..0x{:08x} does not fit in flash",
+                    address, end
+                ));
+            }
+            self.code.load(address, data);
+        } else {
+            return Err(format!(
+                "data at 0x{:08x}..0x{:08x} is not in any memory region",
+                address, end
+            ));
+        }
+        self.invalidate_decoded(address, data.len());
+        Ok(())
+    }
+
+    ///
+    /// Attach a memory-mapped peripheral model to `size` bytes starting from `base`
+    ///
+    pub fn attach_peripheral(
+        &mut self,
+        base: u32,
+        size: usize,
+        peripheral: Box<dyn Peripheral>,
+    ) -> &mut Self {
+        self.peripherals.push(AttachedPeripheral {
+            base,
+            size,
+            peripheral: RefCell::new(peripheral),
+        });
+        self
+    }
+
+    ///
+    /// Configure tracing of the data accesses on the bus
+    ///
+    pub fn bus_trace(&mut self, trace: Option<BusTrace>) -> &mut Self {
+        self.bus_trace = trace;
+        self
+    }
+
+    ///
+    /// Enable detection of uninitialised memory use and invalid accesses
+    ///
+    pub fn memcheck(&mut self, memcheck: Option<Memcheck>) -> &mut Self {
+        self.memcheck = memcheck.map(RefCell::new);
+        self
+    }
+
+    ///
+    /// Enable monitoring of the stack usage
+    ///
+    pub fn stack_monitor(&mut self, monitor: Option<StackMonitor>) -> &mut Self {
+        self.stack_monitor = monitor;
+        self
+    }
+
+    ///
+    /// Enable profiling of the executed functions
+    ///
+    pub fn profiler(&mut self, profiler: Option<Profiler>) -> &mut Self {
+        self.profiler = profiler;
+        self
+    }
+
+    ///
+    /// Enable recording of the code coverage
+    ///
+    pub fn coverage(&mut self, coverage: Option<Coverage>) -> &mut Self {
+        self.coverage = coverage;
+        self
+    }
+
+    ///
+    /// Limit the length of the simulation run
+    ///
+    pub fn run_limits(&mut self, limits: RunLimits) -> &mut Self {
+        self.limits = limits;
         self
     }
 
@@ -368,17 +670,22 @@ impl Processor {
     /// Pre cache (decode) instructions to speed up simulation
     ///
     pub fn cache_instructions(&mut self) {
-        // pre-cache the decoded instructions
-        {
-            let mut pc = 0;
+        let flash_start = self.code.start_address();
+        let flash_end = flash_start + self.code.len() as u32;
+        self.instruction_cache = vec![DecodeCache::new(flash_start, self.code.len())];
+        for region in self.regions.iter().filter(|region| region.access.execute) {
+            self.instruction_cache
+                .push(DecodeCache::new(region.base, region.size));
+        }
 
-            while pc < (self.code.len() as u32) {
-                let thumb = self.fetch(pc).unwrap();
+        // pre-cache the decoded flash instructions, other memories are decoded on demand
+        let mut pc = flash_start;
+        while pc < flash_end {
+            if let Ok(thumb) = self.fetch(pc) {
                 let instruction = self.decode(thumb);
-                self.instruction_cache
-                    .push((instruction, instruction_size(&instruction)));
-                pc += 2;
+                self.instruction_cache[0].insert(pc, instruction, instruction_size(&instruction));
             }
+            pc += 2;
         }
     }
 }
@@ -411,3 +718,40 @@ impl fmt::Display for Processor {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::Bus;
+
+    #[test]
+    fn test_load_memory() {
+        let mut processor = Processor::new();
+        processor.memory_regions(&[
+            MemoryRegionConfig::ram("SRAM", 0x2000_0000, 0x100),
+            MemoryRegionConfig::rom("ROM", 0x1000_0000, 0x100),
+        ]);
+        processor.flash_memory_at(0x0800_0000, 0x100, &[0xff; 0x100]);
+
+        processor.load_memory(0x2000_00fc, &[1, 2, 3, 4]).unwrap();
+        assert_eq!(processor.read32(0x2000_00fc).unwrap(), 0x0403_0201);
+        // ROM is written to despite its access rights
+        processor.load_memory(0x1000_0000, &[5, 6, 7, 8]).unwrap();
+        assert_eq!(processor.read32(0x1000_0000).unwrap(), 0x0807_0605);
+        processor.load_memory(0x0800_0010, &[9, 10]).unwrap();
+        assert_eq!(processor.read16(0x0800_0010).unwrap(), 0x0a09);
+
+        assert_eq!(
+            processor.load_memory(0x2000_00fe, &[1, 2, 3, 4]),
+            Err("data at 0x200000fe..0x20000102 does not fit in memory region 'SRAM'".to_string())
+        );
+        assert_eq!(
+            processor.load_memory(0x0800_00ff, &[1, 2]),
+            Err("data at 0x080000ff..0x08000101 does not fit in flash".to_string())
+        );
+        assert_eq!(
+            processor.load_memory(0x3000_0000, &[1]),
+            Err("data at 0x30000000..0x30000001 is not in any memory region".to_string())
+        );
+    }
+}
@@ -0,0 +1,114 @@
+//!
+//! Bit-band alias regions of the ARMv7-M memory map
+//!
+//! Each word in an alias region maps to a single bit in the corresponding
+//! bit-band region: the first 1 MB of SRAM and of the peripheral space.
+//!
+
+const SRAM_BITBAND_ALIAS: u32 = 0x2200_0000;
+const SRAM_BITBAND_REGION: u32 = 0x2000_0000;
+const PERIPHERAL_BITBAND_ALIAS: u32 = 0x4200_0000;
+const PERIPHERAL_BITBAND_REGION: u32 = 0x4000_0000;
+const ALIAS_SIZE: u32 = 0x0200_0000;
+
+///
+/// Resolve the byte address and bit number addressed by a bit-band alias address.
+/// Returns `None` if the address is not in a bit-band alias region.
+///
+#[must_use]
+pub fn bitband_target(address: u32) -> Option<(u32, usize)> {
+    let (alias, region) = if address.wrapping_sub(SRAM_BITBAND_ALIAS) < ALIAS_SIZE {
+        (SRAM_BITBAND_ALIAS, SRAM_BITBAND_REGION)
+    } else if address.wrapping_sub(PERIPHERAL_BITBAND_ALIAS) < ALIAS_SIZE {
+        (PERIPHERAL_BITBAND_ALIAS, PERIPHERAL_BITBAND_REGION)
+    } else {
+        return None;
+    };
+    let offset = address - alias;
+    Some((region + (offset >> 5), ((offset >> 2) & 7) as usize))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::core::fault::Fault;
+    use crate::device::peripheral::Peripheral;
+    use crate::Processor;
+
+    #[test]
+    fn test_bitband_target() {
+        assert_eq!(bitband_target(0x2200_0000), Some((0x2000_0000, 0)));
+        assert_eq!(bitband_target(0x2200_001C), Some((0x2000_0000, 7)));
+        assert_eq!(bitband_target(0x2200_0020), Some((0x2000_0001, 0)));
+        // unaligned accesses address the bit of the containing word
+        assert_eq!(bitband_target(0x2200_0006), Some((0x2000_0000, 1)));
+        assert_eq!(bitband_target(0x23FF_FFFC), Some((0x200F_FFFF, 7)));
+        assert_eq!(bitband_target(0x4221_0184), Some((0x4001_080C, 1)));
+        assert_eq!(bitband_target(0x2400_0000), None);
+        assert_eq!(bitband_target(0x21FF_FFFC), None);
+    }
+
+    #[test]
+    fn test_bitband_access() {
+        let mut processor = Processor::new();
+        processor.write32(0x2000_0004, 0).unwrap();
+
+        // set bit 3 of byte 0x2000_0005 with a word write, clear it with a byte write
+        processor.write32(0x2200_00AC, 0xffff_ffff).unwrap();
+        assert_eq!(processor.read32(0x2000_0004).unwrap(), 0x0000_0800);
+        assert_eq!(processor.read32(0x2200_00AC).unwrap(), 1);
+        assert_eq!(processor.read8(0x2200_00A8).unwrap(), 0);
+
+        processor.write8(0x2200_00AC, 0xfe).unwrap();
+        assert_eq!(processor.read32(0x2000_0004).unwrap(), 0);
+        assert_eq!(processor.read16(0x2200_00AC).unwrap(), 0);
+    }
+
+    // Register block that only implements word accesses.
+    struct WordRegisters {
+        data: u32,
+    }
+
+    impl Peripheral for WordRegisters {
+        fn name(&self) -> &str {
+            "WORD"
+        }
+
+        fn read32(&mut self, _offset: u32) -> Result<u32, Fault> {
+            Ok(self.data)
+        }
+
+        fn write32(&mut self, _offset: u32, value: u32) -> Result<(), Fault> {
+            self.data = value;
+            Ok(())
+        }
+
+        fn read8(&mut self, _offset: u32) -> Result<u8, Fault> {
+            Err(Fault::DAccViol)
+        }
+
+        fn write8(&mut self, _offset: u32, _value: u8) -> Result<(), Fault> {
+            Err(Fault::DAccViol)
+        }
+    }
+
+    #[test]
+    fn test_bitband_peripheral_word_access() {
+        let mut processor = Processor::new();
+        processor.attach_peripheral(
+            0x4000_0000,
+            0x100,
+            Box::new(WordRegisters { data: 0x8000_0000 }),
+        );
+
+        // bit 2 of byte 0x4000_0002 is bit 18 of the word at 0x4000_0000
+        processor.write32(0x4200_0048, 1).unwrap();
+        assert_eq!(processor.read32(0x4000_0000).unwrap(), 0x8004_0000);
+        assert_eq!(processor.read32(0x4200_0048).unwrap(), 1);
+        assert_eq!(processor.read32(0x4200_007C).unwrap(), 1);
+
+        processor.write32(0x4200_007C, 0).unwrap();
+        assert_eq!(processor.read32(0x4000_0000).unwrap(), 0x0004_0000);
+    }
+}
@@ -0,0 +1,556 @@
+//!
+//! Runtime memory map description
+//!
+//! A memory map is a list of regions, each described on a single line:
+//!
+//! ```text
+//! ram,name=SRAM1,base=0x20000000,size=112K,access=rwx,fill=0xcd
+//! rom,name=SYSMEM,base=0x1fff0000,size=30K
+//! alias,base=0x00000000,size=512K,target=0x08000000
+//! remap,name=SYSCFG,base=0x40013800,size=4,source=0,length=1M,targets=0x08000000:0x1fff0000:0x20000000,boot=0
+//! ```
+//!
+//! The first field is the region kind, the rest are `key=value` pairs.
+//! In a memory map file empty lines and lines starting with `#` are ignored.
+//!
+
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+///
+/// Kind of a memory region
+///
+#[derive(PartialEq, Debug, Clone)]
+pub enum MemoryKind {
+    /// Read-write memory
+    Ram,
+    /// Read-only memory, for example flash
+    Rom,
+    /// Address range that is redirected to another address range
+    Alias {
+        /// start of the range the alias points to
+        target: u32,
+    },
+    /// Memory remap register selecting the target of an alias at runtime
+    Remap {
+        /// start of the remapped range
+        source: u32,
+        /// length of the remapped range in bytes
+        length: usize,
+        /// targets selectable by the register value
+        targets: Vec<u32>,
+        /// register value at reset, as selected by the boot pins
+        boot: usize,
+    },
+}
+
+///
+/// Access rights of a memory region
+///
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct MemoryAccess {
+    /// data reads are allowed
+    pub read: bool,
+    /// data writes are allowed
+    pub write: bool,
+    /// instruction fetches are allowed
+    pub execute: bool,
+}
+
+impl FromStr for MemoryAccess {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut access = Self {
+            read: false,
+            write: false,
+            execute: false,
+        };
+        for c in s.chars() {
+            match c {
+                'r' => access.read = true,
+                'w' => access.write = true,
+                'x' => access.execute = true,
+                '-' => {}
+                _ => return Err(format!("invalid access right '{c}' in '{s}'")),
+            }
+        }
+        Ok(access)
+    }
+}
+
+impl fmt::Display for MemoryAccess {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}{}{}",
+            if self.read { 'r' } else { '-' },
+            if self.write { 'w' } else { '-' },
+            if self.execute { 'x' } else { '-' }
+        )
+    }
+}
+
+///
+/// Description of a single memory region
+///
+#[derive(PartialEq, Debug, Clone)]
+pub struct MemoryRegionConfig {
+    /// name of the region, used in diagnostics
+    pub name: String,
+    /// kind of the region
+    pub kind: MemoryKind,
+    /// start address
+    pub base: u32,
+    /// size in bytes
+    pub size: usize,
+    /// initial content of every byte of the region
+    pub fill: u8,
+    /// access rights
+    pub access: MemoryAccess,
+}
+
+impl MemoryRegionConfig {
+    ///
+    /// Describe a RAM region with default fill pattern and access rights
+    ///
+    #[must_use]
+    pub fn ram(name: &str, base: u32, size: usize) -> Self {
+        Self {
+            name: name.to_string(),
+            kind: MemoryKind::Ram,
+            base,
+            size,
+            fill: 0xcd,
+            access: MemoryAccess {
+                read: true,
+                write: true,
+                execute: true,
+            },
+        }
+    }
+
+    ///
+    /// Describe a ROM region with default fill pattern and access rights
+    ///
+    #[must_use]
+    pub fn rom(name: &str, base: u32, size: usize) -> Self {
+        Self {
+            name: name.to_string(),
+            kind: MemoryKind::Rom,
+            base,
+            size,
+            fill: 0xff,
+            access: MemoryAccess {
+                read: true,
+                write: false,
+                execute: true,
+            },
+        }
+    }
+
+    ///
+    /// Describe an alias region redirecting `size` bytes from `base` to `target`
+    ///
+    #[must_use]
+    pub fn alias(name: &str, base: u32, size: usize, target: u32) -> Self {
+        Self {
+            name: name.to_string(),
+            kind: MemoryKind::Alias { target },
+            base,
+            size,
+            fill: 0,
+            access: MemoryAccess {
+                read: true,
+                write: true,
+                execute: true,
+            },
+        }
+    }
+
+    ///
+    /// Describe a memory remap register at `base`, selecting which one of
+    /// `targets` the `length` bytes starting from `source` are aliased to
+    ///
+    #[must_use]
+    pub fn remap(
+        name: &str,
+        base: u32,
+        source: u32,
+        length: usize,
+        targets: &[u32],
+        boot: usize,
+    ) -> Self {
+        Self {
+            name: name.to_string(),
+            kind: MemoryKind::Remap {
+                source,
+                length,
+                targets: targets.to_vec(),
+                boot,
+            },
+            base,
+            size: 4,
+            fill: 0,
+            access: MemoryAccess {
+                read: true,
+                write: true,
+                execute: false,
+            },
+        }
+    }
+
+    ///
+    /// Check if address belongs to this region
+    ///
+    #[must_use]
+    pub fn contains(&self, address: u32) -> bool {
+        address >= self.base && u64::from(address) < u64::from(self.base) + self.size as u64
+    }
+}
+
+impl FromStr for MemoryRegionConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut fields = s.split(',').map(str::trim);
+        let kind = fields.next().unwrap_or("");
+
+        let mut name = None;
+        let mut base = None;
+        let mut size = None;
+        let mut fill = None;
+        let mut access = None;
+        let mut target = None;
+        let mut source = None;
+        let mut length = None;
+        let mut targets = None;
+        let mut boot = None;
+
+        for field in fields {
+            let mut kv = field.splitn(2, '=');
+            let key = kv.next().unwrap_or("").trim();
+            let value = kv
+                .next()
+                .ok_or_else(|| format!("expected key=value, got '{field}'"))?
+                .trim();
+            match key {
+                "name" => name = Some(value.to_string()),
+                "base" => base = Some(parse_u32(value)?),
+                "size" => size = Some(parse_size(value)?),
+                "fill" => fill = Some(parse_u8(value)?),
+                "access" => access = Some(value.parse::<MemoryAccess>()?),
+                "target" => target = Some(parse_u32(value)?),
+                "source" => source = Some(parse_u32(value)?),
+                "length" => length = Some(parse_size(value)?),
+                "targets" => {
+                    targets = Some(
+                        value
+                            .split(':')
+                            .map(|t| parse_u32(t.trim()))
+                            .collect::<Result<Vec<_>, _>>()?,
+                    );
+                }
+                "boot" => boot = Some(parse_u32(value)? as usize),
+                _ => return Err(format!("unknown memory region field '{key}'")),
+            }
+        }
+
+        let base = base.ok_or_else(|| format!("missing base address in '{s}'"))?;
+        let size = size.ok_or_else(|| format!("missing size in '{s}'"))?;
+        if size == 0 || u64::from(base) + size as u64 > 0x1_0000_0000 {
+            return Err(format!("region does not fit address space in '{s}'"));
+        }
+        let name = name.unwrap_or_else(|| kind.to_string());
+        let has_remap_fields =
+            source.is_some() || length.is_some() || targets.is_some() || boot.is_some();
+
+        let mut region = match kind {
+            "ram" => Self::ram(&name, base, size),
+            "rom" | "flash" => Self::rom(&name, base, size),
+            "alias" => {
+                let target = target.ok_or_else(|| format!("missing alias target in '{s}'"))?;
+                Self::alias(&name, base, size, target)
+            }
+            "remap" => {
+                let source = source.ok_or_else(|| format!("missing remap source in '{s}'"))?;
+                let length = length.ok_or_else(|| format!("missing remap length in '{s}'"))?;
+                let targets = targets.ok_or_else(|| format!("missing remap targets in '{s}'"))?;
+                let boot = boot.unwrap_or(0);
+                if boot >= targets.len() {
+                    return Err(format!("boot selects a missing remap target in '{s}'"));
+                }
+                let mut region = Self::remap(&name, base, source, length, &targets, boot);
+                region.size = size;
+                region
+            }
+            _ => return Err(format!("unknown memory region kind '{kind}'")),
+        };
+        if target.is_some() && !matches!(region.kind, MemoryKind::Alias { .. }) {
+            return Err(format!("target is only valid for aliases in '{s}'"));
+        }
+        if has_remap_fields && !matches!(region.kind, MemoryKind::Remap { .. }) {
+            return Err(format!(
+                "source, length, targets and boot are only valid for remap registers in '{s}'"
+            ));
+        }
+        if let Some(fill) = fill {
+            region.fill = fill;
+        }
+        if let Some(access) = access {
+            region.access = access;
+        }
+        Ok(region)
+    }
+}
+
+///
+/// Parse a memory map description, one region per line.
+///
+/// # Errors
+///
+/// Fails with the line number and the reason for the first invalid line.
+///
+pub fn parse_memory_map(text: &str) -> Result<Vec<MemoryRegionConfig>, String> {
+    text.lines()
+        .enumerate()
+        .map(|(n, line)| (n, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(n, line)| {
+            line.parse::<MemoryRegionConfig>()
+                .map_err(|e| format!("line {}: {}", n + 1, e))
+        })
+        .collect()
+}
+
+///
+/// Check that no two RAM/ROM regions, nor two alias regions, overlap.
+///
+/// # Errors
+///
+/// Fails naming the first pair of overlapping regions.
+///
+pub fn validate_memory_map(regions: &[MemoryRegionConfig]) -> Result<(), String> {
+    let is_alias = |region: &MemoryRegionConfig| matches!(region.kind, MemoryKind::Alias { .. });
+    for (i, a) in regions.iter().enumerate() {
+        for b in &regions[i + 1..] {
+            let end_a = u64::from(a.base) + a.size as u64;
+            let end_b = u64::from(b.base) + b.size as u64;
+            if is_alias(a) == is_alias(b) && u64::from(a.base) < end_b && u64::from(b.base) < end_a
+            {
+                return Err(format!(
+                    "memory region '{}' overlaps with '{}'",
+                    a.name, b.name
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn parse_u32(value: &str) -> Result<u32, String> {
+    let result = if value.starts_with("0x") || value.starts_with("0X") {
+        u32::from_str_radix(&value[2..], 16)
+    } else {
+        value.parse::<u32>()
+    };
+    result.map_err(|_| format!("invalid number '{value}'"))
+}
+
+///
+/// Parse an address, either decimal or hexadecimal with a 0x prefix
+///
+/// # Errors
+///
+/// Fails if the value is not a valid 32-bit number.
+///
+pub fn parse_address(value: &str) -> Result<u32, String> {
+    parse_u32(value)
+}
+
+fn parse_u8(value: &str) -> Result<u8, String> {
+    let number = parse_u32(value)?;
+    if number > 0xff {
+        return Err(format!("fill value '{value}' does not fit in a byte"));
+    }
+    Ok(number as u8)
+}
+
+///
+/// Parse a size in bytes, with an optional K or M suffix
+///
+/// # Errors
+///
+/// Fails if the value without the suffix is not a valid 32-bit number.
+///
+pub fn parse_size(value: &str) -> Result<usize, String> {
+    let (number, multiplier) = match value.chars().last() {
+        Some('K' | 'k') => (&value[..value.len() - 1], 1024),
+        Some('M' | 'm') => (&value[..value.len() - 1], 1024 * 1024),
+        _ => (value, 1),
+    };
+    Ok(parse_u32(number)? as usize * multiplier)
+}
+
+///
+/// Parse an address range, given either as `start-end` with inclusive end
+/// or as `start+size`
+///
+/// # Errors
+///
+/// Fails if the range is malformed, empty or does not fit the address space.
+///
+pub fn parse_address_range(value: &str) -> Result<RangeInclusive<u32>, String> {
+    let (start, end) = if let Some(pos) = value.find('+') {
+        let start = parse_u32(value[..pos].trim())?;
+        let size = parse_size(value[pos + 1..].trim())?;
+        if size == 0 || u64::from(start) + size as u64 > 0x1_0000_0000 {
+            return Err(format!(
+                "address range '{value}' does not fit address space"
+            ));
+        }
+        (start, start + (size - 1) as u32)
+    } else if let Some(pos) = value.find('-') {
+        (
+            parse_u32(value[..pos].trim())?,
+            parse_u32(value[pos + 1..].trim())?,
+        )
+    } else {
+        return Err(format!("expected start-end or start+size, got '{value}'"));
+    };
+    if end < start {
+        return Err(format!("address range '{value}' ends before it starts"));
+    }
+    Ok(start..=end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ram_region() {
+        let region = "ram,name=SRAM1,base=0x20000000,size=112K,access=rw,fill=0"
+            .parse::<MemoryRegionConfig>()
+            .unwrap();
+
+        assert_eq!(region.name, "SRAM1");
+        assert_eq!(region.kind, MemoryKind::Ram);
+        assert_eq!(region.base, 0x2000_0000);
+        assert_eq!(region.size, 112 * 1024);
+        assert_eq!(region.fill, 0);
+        assert_eq!(region.access.to_string(), "rw-");
+        assert!(region.contains(0x2001_BFFF));
+        assert!(!region.contains(0x2001_C000));
+    }
+
+    #[test]
+    fn test_parse_defaults() {
+        let region = "rom,base=0x1fff0000,size=0x7800"
+            .parse::<MemoryRegionConfig>()
+            .unwrap();
+        assert_eq!(region, MemoryRegionConfig::rom("rom", 0x1fff_0000, 0x7800));
+
+        let region = "alias,base=0,size=1M,target=0x08000000"
+            .parse::<MemoryRegionConfig>()
+            .unwrap();
+        assert_eq!(
+            region.kind,
+            MemoryKind::Alias {
+                target: 0x0800_0000
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_remap_region() {
+        let region = "remap,name=SYSCFG,base=0x40013800,size=1K,source=0,length=1M,\
+                      targets=0x08000000:0x1fff0000:0x20000000,boot=1"
+            .parse::<MemoryRegionConfig>()
+            .unwrap();
+        assert_eq!(region.name, "SYSCFG");
+        assert_eq!(region.size, 1024);
+        assert_eq!(
+            region.kind,
+            MemoryKind::Remap {
+                source: 0,
+                length: 1024 * 1024,
+                targets: vec![0x0800_0000, 0x1fff_0000, 0x2000_0000],
+                boot: 1,
+            }
+        );
+
+        assert!("remap,base=0,size=4,source=0,length=1K,targets=0,boot=1"
+            .parse::<MemoryRegionConfig>()
+            .is_err());
+        assert!("ram,base=0,size=1K,boot=1"
+            .parse::<MemoryRegionConfig>()
+            .is_err());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!("ram,size=1K".parse::<MemoryRegionConfig>().is_err());
+        assert!("ram,base=0".parse::<MemoryRegionConfig>().is_err());
+        assert!("eeprom,base=0,size=1K"
+            .parse::<MemoryRegionConfig>()
+            .is_err());
+        assert!("alias,base=0,size=1K"
+            .parse::<MemoryRegionConfig>()
+            .is_err());
+        assert!("ram,base=0,size=1K,access=rq"
+            .parse::<MemoryRegionConfig>()
+            .is_err());
+        assert!("ram,base=0xffff0000,size=1M"
+            .parse::<MemoryRegionConfig>()
+            .is_err());
+    }
+
+    #[test]
+    fn test_parse_memory_map() {
+        let map = parse_memory_map(
+            "# STM32F4 style map\n\
+             ram,name=SRAM1,base=0x20000000,size=112K\n\
+             \n\
+             ram,name=SRAM2,base=0x2001c000,size=16K\n",
+        )
+        .unwrap();
+        assert_eq!(map.len(), 2);
+        assert_eq!(map[1].name, "SRAM2");
+
+        let err = parse_memory_map("ram,base=0,size=1K\nram,base=0").unwrap_err();
+        assert!(err.starts_with("line 2:"));
+    }
+
+    #[test]
+    fn test_validate_memory_map() {
+        let mut map = vec![
+            MemoryRegionConfig::ram("a", 0x2000_0000, 0x1000),
+            MemoryRegionConfig::ram("b", 0x2000_1000, 0x1000),
+            MemoryRegionConfig::alias("c", 0x2000_0000, 0x1000, 0),
+        ];
+        assert!(validate_memory_map(&map).is_ok());
+
+        map.push(MemoryRegionConfig::rom("d", 0x2000_1ffc, 4));
+        assert_eq!(
+            validate_memory_map(&map),
+            Err("memory region 'b' overlaps with 'd'".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_address_range() {
+        assert_eq!(
+            parse_address_range("0x40000000-0x400003ff"),
+            Ok(0x4000_0000..=0x4000_03FF)
+        );
+        assert_eq!(
+            parse_address_range("0x20000000+1K"),
+            Ok(0x2000_0000..=0x2000_03FF)
+        );
+        assert!(parse_address_range("0x20000000").is_err());
+        assert!(parse_address_range("0x2000-0x1000").is_err());
+        assert!(parse_address_range("0xffffff00+1K").is_err());
+    }
+}
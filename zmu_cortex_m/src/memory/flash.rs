@@ -7,19 +7,59 @@ use crate::bus::Bus;
 use crate::core::fault::Fault;
 use byteorder::{ByteOrder, LittleEndian};
 
+///
+/// Erase operation requested from a flash controller
+///
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub enum FlashErase {
+    /// Erase the page of `size` bytes containing `address`
+    Page {
+        /// bus address within the page
+        address: u32,
+        /// page size in bytes
+        size: usize,
+    },
+    /// Erase the whole flash
+    Mass,
+}
+
 #[derive(Debug)]
 /// Flash memory with configurable start address and data content
 pub struct FlashMemory {
+    start_address: u32,
     data: Box<[u8]>,
 }
 
 impl FlashMemory {
-    /// make a flash data instance with given start address, size and data content
+    /// make a flash data instance at address 0 with given size and data content
     pub fn new(size: usize, new_data: &[u8]) -> Self {
+        Self::new_at(0, size, new_data)
+    }
+
+    /// make a flash data instance with given start address, size and data content
+    pub fn new_at(start_address: u32, size: usize, new_data: &[u8]) -> Self {
         let mut data = vec![0_u8; size].into_boxed_slice();
         data.copy_from_slice(new_data);
 
-        Self { data }
+        Self {
+            start_address,
+            data,
+        }
+    }
+
+    /// make an erased flash data instance with given start address and size
+    pub fn new_with_fill(start_address: u32, size: usize, fill: u8) -> Self {
+        let data = vec![fill; size].into_boxed_slice();
+
+        Self {
+            start_address,
+            data,
+        }
+    }
+
+    /// start address of the flash
+    pub fn start_address(&self) -> u32 {
+        self.start_address
     }
 
     ///
@@ -31,21 +71,69 @@ impl FlashMemory {
     pub fn is_empty(&self) -> bool {
         self.data.len() == 0
     }
+
+    /// flash contents
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    ///
+    /// Program a half-word. Programming succeeds only if the location
+    /// is erased or when writing zero, as flash bits can only be cleared
+    /// by programming. Returns false on programming error.
+    ///
+    pub fn program16(&mut self, addr: u32, value: u16) -> bool {
+        let a = (addr - self.start_address) as usize;
+        let current = LittleEndian::read_u16(&self.data[a..a + 2]);
+        if current != 0xffff && value != 0 {
+            return false;
+        }
+        LittleEndian::write_u16(&mut self.data[a..a + 2], value);
+        true
+    }
+
+    ///
+    /// Erase `size` bytes starting from `addr`, clipped to the flash area
+    ///
+    pub fn erase(&mut self, addr: u32, size: usize) {
+        let start = (addr - self.start_address) as usize;
+        let end = std::cmp::min(start + size, self.data.len());
+        for byte in &mut self.data[start..end] {
+            *byte = 0xff;
+        }
+    }
+
+    ///
+    /// Copy data to the flash starting from given address, as a flash programmer would
+    ///
+    pub fn load(&mut self, addr: u32, data: &[u8]) {
+        let a = (addr - self.start_address) as usize;
+        self.data[a..a + data.len()].copy_from_slice(data);
+    }
+
+    ///
+    /// Erase the whole flash
+    ///
+    pub fn erase_all(&mut self) {
+        for byte in self.data.iter_mut() {
+            *byte = 0xff;
+        }
+    }
 }
 
 impl Bus for FlashMemory {
     fn read8(&self, addr: u32) -> Result<u8, Fault> {
-        let a = addr as usize;
+        let a = (addr - self.start_address) as usize;
         Ok(self.data[a])
     }
     fn read16(&self, addr: u32) -> Result<u16, Fault> {
-        let a = addr as usize;
+        let a = (addr - self.start_address) as usize;
 
         Ok(LittleEndian::read_u16(&self.data[a..a + 2]))
     }
 
     fn read32(&mut self, addr: u32) -> Result<u32, Fault> {
-        let a = addr as usize;
+        let a = (addr - self.start_address) as usize;
         Ok(LittleEndian::read_u32(&self.data[a..a + 4]))
     }
 
@@ -61,7 +149,8 @@ impl Bus for FlashMemory {
     }
 
     fn in_range(&self, addr: u32) -> bool {
-        addr < (self.data.len() as u32)
+        addr >= self.start_address
+            && u64::from(addr) < u64::from(self.start_address) + self.data.len() as u64
     }
 }
 
@@ -83,6 +172,26 @@ fn test_load() {
     );
 }
 
+#[test]
+fn test_program_and_erase() {
+    let mut mem = FlashMemory::new_with_fill(0x0800_0000, 2048, 0xff);
+
+    assert!(mem.program16(0x0800_0400, 0x1234));
+    assert_eq!(mem.read16(0x0800_0400).unwrap(), 0x1234);
+    // programmed location can only be cleared
+    assert!(!mem.program16(0x0800_0400, 0x5678));
+    assert!(mem.program16(0x0800_0400, 0));
+    assert_eq!(mem.read16(0x0800_0400).unwrap(), 0);
+
+    mem.program16(0x0800_0000, 0xaaaa);
+    mem.erase(0x0800_0400, 1024);
+    assert_eq!(mem.read16(0x0800_0400).unwrap(), 0xffff);
+    assert_eq!(mem.read16(0x0800_0000).unwrap(), 0xaaaa);
+
+    mem.erase_all();
+    assert_eq!(mem.read16(0x0800_0000).unwrap(), 0xffff);
+}
+
 #[test]
 fn test_in_range() {
     {
@@ -93,4 +202,14 @@ fn test_in_range() {
         assert!(!mem.in_range(1024));
         assert!(!mem.in_range(0xFFFF_FFFF));
     }
+
+    {
+        /* offset of 0x0800_0000 */
+        let mut mem = FlashMemory::new_with_fill(0x0800_0000, 1024, 0xff);
+        assert!(!mem.in_range(0));
+        assert!(mem.in_range(0x0800_0000));
+        assert!(mem.in_range(0x0800_03ff));
+        assert!(!mem.in_range(0x0800_0400));
+        assert_eq!(mem.read32(0x0800_0000).unwrap(), 0xffff_ffff);
+    }
 }
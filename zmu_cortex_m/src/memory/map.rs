@@ -17,6 +17,7 @@ pub trait MapMemory {
 
 ///
 /// Mapping of memory range to another range
+#[derive(PartialEq, Debug, Clone)]
 pub struct MemoryMapConfig {
     /// source of mapping
     source_start: u32,
@@ -31,17 +32,53 @@ impl MemoryMapConfig {
     pub fn new(from: u32, to: u32, len: usize) -> Self {
         Self {
             source_start: from,
-            source_end: from + len as u32,
+            source_end: from.wrapping_add(len as u32),
             target_start: to,
         }
     }
 
+    /// start of the mapped range
+    pub fn source(&self) -> u32 {
+        self.source_start
+    }
+
+    /// start of the range the mapping points to
+    pub fn target(&self) -> u32 {
+        self.target_start
+    }
+
+    /// length of the mapped range in bytes
+    pub fn len(&self) -> usize {
+        self.source_end.wrapping_sub(self.source_start) as usize
+    }
+
+    /// check if the mapping is empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
     /// check if address is affected by mapping
     pub fn contains(&self, address: u32) -> bool {
-        address >= self.source_start && address < self.source_end
+        address >= self.source_start
+            && address - self.source_start < self.source_end.wrapping_sub(self.source_start)
     }
 }
 
+///
+/// Change of memory mapping done at runtime, for example by a device model
+/// emulating a boot remap register
+///
+#[derive(PartialEq, Debug, Clone)]
+pub enum MemoryRemap {
+    /// Add a mapping, replacing the mapping with the same source address if any
+    Map(MemoryMapConfig),
+    /// Remove the mapping starting at given source address
+    Unmap {
+        /// start of the mapped range
+        source: u32,
+    },
+}
+
 impl MapMemory for MemoryMapConfig {
     fn map_address(&self, address: u32) -> u32 {
         if self.contains(address) {
@@ -54,10 +91,11 @@ impl MapMemory for MemoryMapConfig {
 
 impl MapMemory for Processor {
     fn map_address(&self, address: u32) -> u32 {
-        if let Some(map) = &self.mem_map {
-            map.map_address(address)
-        } else {
-            address
+        for map in &self.mem_map {
+            if map.contains(address) {
+                return map.map_address(address);
+            }
         }
+        address
     }
 }
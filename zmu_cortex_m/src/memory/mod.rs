@@ -6,3 +6,7 @@
 pub mod flash;
 pub mod ram;
 pub mod map;
+pub mod config;
+pub mod region;
+#[cfg(any(armv7m, armv7em))]
+pub mod bitband;
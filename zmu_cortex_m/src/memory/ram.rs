@@ -33,6 +33,12 @@ impl RAM {
             data,
         }
     }
+
+    /// Copy data to the memory starting from given address
+    pub fn load(&mut self, addr: u32, data: &[u8]) {
+        let a = (addr - self.start_address) as usize;
+        self.data[a..a + data.len()].copy_from_slice(data);
+    }
 }
 
 impl Bus for RAM {
@@ -72,10 +78,8 @@ impl Bus for RAM {
     }
 
     fn in_range(&self, addr: u32) -> bool {
-        if (addr >= self.start_address) && (addr < (self.start_address + self.data.len() as u32)) {
-            return true;
-        }
-        false
+        addr >= self.start_address
+            && u64::from(addr) < u64::from(self.start_address) + self.data.len() as u64
     }
 }
 
@@ -0,0 +1,212 @@
+//!
+//! Memory regions of a configurable memory map
+//!
+
+use crate::bus::Bus;
+use crate::core::fault::Fault;
+use crate::memory::config::{MemoryAccess, MemoryKind, MemoryRegionConfig};
+use crate::memory::flash::FlashMemory;
+use crate::memory::ram::RAM;
+
+#[derive(Debug)]
+enum Storage {
+    Ram(RAM),
+    Rom(FlashMemory),
+}
+
+#[derive(Debug)]
+///
+/// A RAM or ROM region with a name and access rights
+///
+pub struct MemoryRegion {
+    /// name of the region, used in diagnostics
+    pub name: String,
+    /// start address of the region
+    pub base: u32,
+    /// size of the region in bytes
+    pub size: usize,
+    /// access rights
+    pub access: MemoryAccess,
+    storage: Storage,
+}
+
+impl MemoryRegion {
+    ///
+    /// Create a region from a configuration. Returns `None` for aliases and
+    /// remap registers, as they have no storage of their own.
+    ///
+    #[must_use]
+    pub fn from_config(config: &MemoryRegionConfig) -> Option<Self> {
+        let storage = match config.kind {
+            MemoryKind::Ram => {
+                Storage::Ram(RAM::new_with_fill(config.base, config.size, config.fill))
+            }
+            MemoryKind::Rom => Storage::Rom(FlashMemory::new_with_fill(
+                config.base,
+                config.size,
+                config.fill,
+            )),
+            MemoryKind::Alias { .. } | MemoryKind::Remap { .. } => return None,
+        };
+        Some(Self {
+            name: config.name.clone(),
+            base: config.base,
+            size: config.size,
+            access: config.access,
+            storage,
+        })
+    }
+
+    ///
+    /// Check if the region is RAM, as opposed to ROM
+    ///
+    #[must_use]
+    pub fn is_ram(&self) -> bool {
+        match self.storage {
+            Storage::Ram(_) => true,
+            Storage::Rom(_) => false,
+        }
+    }
+
+    ///
+    /// Check if `len` bytes starting from `address` fit in the region
+    ///
+    #[must_use]
+    pub fn contains(&self, address: u32, len: usize) -> bool {
+        address >= self.base
+            && u64::from(address) + len as u64 <= u64::from(self.base) + self.size as u64
+    }
+
+    ///
+    /// Copy data to the region starting from given address, ignoring the
+    /// access rights, as when loading an image
+    ///
+    pub fn load(&mut self, address: u32, data: &[u8]) {
+        match &mut self.storage {
+            Storage::Ram(ram) => ram.load(address, data),
+            Storage::Rom(rom) => rom.load(address, data),
+        }
+    }
+
+    fn check_read(&self) -> Result<(), Fault> {
+        if self.access.read {
+            Ok(())
+        } else {
+            Err(Fault::DAccViol)
+        }
+    }
+
+    fn check_write(&self) -> Result<(), Fault> {
+        if self.access.write {
+            Ok(())
+        } else {
+            Err(Fault::DAccViol)
+        }
+    }
+}
+
+impl Bus for MemoryRegion {
+    fn read8(&self, addr: u32) -> Result<u8, Fault> {
+        self.check_read()?;
+        match &self.storage {
+            Storage::Ram(ram) => ram.read8(addr),
+            Storage::Rom(rom) => rom.read8(addr),
+        }
+    }
+
+    fn read16(&self, addr: u32) -> Result<u16, Fault> {
+        self.check_read()?;
+        match &self.storage {
+            Storage::Ram(ram) => ram.read16(addr),
+            Storage::Rom(rom) => rom.read16(addr),
+        }
+    }
+
+    fn read32(&mut self, addr: u32) -> Result<u32, Fault> {
+        self.check_read()?;
+        match &mut self.storage {
+            Storage::Ram(ram) => ram.read32(addr),
+            Storage::Rom(rom) => rom.read32(addr),
+        }
+    }
+
+    fn write8(&mut self, addr: u32, value: u8) -> Result<(), Fault> {
+        self.check_write()?;
+        match &mut self.storage {
+            Storage::Ram(ram) => ram.write8(addr, value),
+            Storage::Rom(rom) => rom.write8(addr, value),
+        }
+    }
+
+    fn write16(&mut self, addr: u32, value: u16) -> Result<(), Fault> {
+        self.check_write()?;
+        match &mut self.storage {
+            Storage::Ram(ram) => ram.write16(addr, value),
+            Storage::Rom(rom) => rom.write16(addr, value),
+        }
+    }
+
+    fn write32(&mut self, addr: u32, value: u32) -> Result<(), Fault> {
+        self.check_write()?;
+        match &mut self.storage {
+            Storage::Ram(ram) => ram.write32(addr, value),
+            Storage::Rom(rom) => rom.write32(addr, value),
+        }
+    }
+
+    fn in_range(&self, addr: u32) -> bool {
+        match &self.storage {
+            Storage::Ram(ram) => ram.in_range(addr),
+            Storage::Rom(rom) => rom.in_range(addr),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ram_region() {
+        let config = "ram,base=0x10000000,size=64K,fill=0"
+            .parse::<MemoryRegionConfig>()
+            .unwrap();
+        let mut region = MemoryRegion::from_config(&config).unwrap();
+
+        assert!(region.in_range(0x1000_FFFF));
+        assert!(!region.in_range(0x1001_0000));
+        region.write32(0x1000_0000, 0x1234_5678).unwrap();
+        assert_eq!(region.read32(0x1000_0000).unwrap(), 0x1234_5678);
+    }
+
+    #[test]
+    fn test_rom_region_is_read_only() {
+        let config = MemoryRegionConfig::rom("boot", 0x1fff_0000, 1024);
+        let mut region = MemoryRegion::from_config(&config).unwrap();
+
+        assert_eq!(region.read32(0x1fff_0000).unwrap(), 0xffff_ffff);
+        assert_eq!(region.write8(0x1fff_0000, 0), Err(Fault::DAccViol));
+
+        assert!(region.contains(0x1fff_03fc, 4));
+        assert!(!region.contains(0x1fff_03fc, 5));
+        region.load(0x1fff_0000, &[0x78, 0x56, 0x34, 0x12]);
+        assert_eq!(region.read32(0x1fff_0000).unwrap(), 0x1234_5678);
+    }
+
+    #[test]
+    fn test_access_rights() {
+        let config = "ram,base=0,size=1K,access=w"
+            .parse::<MemoryRegionConfig>()
+            .unwrap();
+        let mut region = MemoryRegion::from_config(&config).unwrap();
+
+        region.write16(0, 0xffff).unwrap();
+        assert_eq!(region.read16(0), Err(Fault::DAccViol));
+    }
+
+    #[test]
+    fn test_alias_has_no_storage() {
+        let config = MemoryRegionConfig::alias("boot", 0, 1024, 0x0800_0000);
+        assert!(MemoryRegion::from_config(&config).is_none());
+    }
+}
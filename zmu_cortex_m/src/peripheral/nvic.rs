@@ -5,6 +5,7 @@
 use crate::core::bits::Bits;
 use crate::core::exception::Exception;
 use crate::core::exception::ExceptionHandling;
+use crate::core::sleep::Sleep;
 use crate::Processor;
 
 ///
@@ -117,10 +118,11 @@ fn clear_bits_array(array: &mut [u32; 16], index: usize, value: u32) {
 impl NVICHelper for Processor {
     fn nvic_set_pending_exceptions(&mut self, index: usize) {
         let mut active = self.nvic_interrupt_pending[index] & self.nvic_interrupt_enabled[index];
-        let mut irqn = index * 4;
+        let mut irqn = index * 32;
         while active != 0 {
-            if active & 1 != 0 {
-                self.set_exception_pending(Exception::Interrupt { n: irqn });
+            let irq = Exception::Interrupt { n: irqn };
+            if active & 1 != 0 && self.exceptions.contains_key(&irq.into()) {
+                self.set_exception_pending(irq);
             }
             active >>= 1;
             irqn += 1;
@@ -129,9 +131,10 @@ impl NVICHelper for Processor {
 
     fn nvic_clear_unpended_exceptions(&mut self, index: usize) {
         let mut active = self.nvic_interrupt_pending[index] & self.nvic_interrupt_enabled[index];
-        for irqn in (index * 4)..(index * 4) + 32 {
-            if active & 1 == 0 {
-                self.clear_pending_exception(Exception::Interrupt { n: irqn });
+        for irqn in (index * 32)..(index * 32) + 32 {
+            let irq = Exception::Interrupt { n: irqn };
+            if active & 1 == 0 && self.exceptions.contains_key(&irq.into()) {
+                self.clear_pending_exception(irq);
             }
             active >>= 1;
         }
@@ -164,7 +167,13 @@ impl NVIC for Processor {
     }
 
     fn nvic_write_ispr(&mut self, index: usize, value: u32) {
+        let newly_pending = value & !self.nvic_interrupt_pending[index];
         set_bits_array(&mut self.nvic_interrupt_pending, index, value);
+        if newly_pending & !self.nvic_interrupt_enabled[index] != 0 {
+            // also disabled interrupts becoming pending are wakeup events,
+            // enabled ones are signalled when their exception gets pended
+            self.set_event_on_pend();
+        }
         self.nvic_set_pending_exceptions(index);
     }
 
@@ -443,4 +452,20 @@ mod tests {
         }
     }
 
+
+    #[test]
+    fn test_nvic_ispr_upper_word() {
+        // Arrange
+        let mut processor = Processor::new();
+        processor.nvic_write_iser(0, 1 << 5);
+
+        // Act: interrupts above the configured ones are ignored
+        processor.nvic_write_iser(1, 1);
+        processor.nvic_write_ispr(1, 1);
+        processor.nvic_write_ispr(0, 1 << 5);
+
+        // Assert
+        assert!(processor.exception_pending(Exception::Interrupt { n: 5 }));
+        assert_eq!(processor.nvic_read_ispr(1), 1);
+    }
 }
@@ -8,6 +8,31 @@ use crate::core::exception::ExceptionHandling;
 use crate::Processor;
 
 use crate::core::register::Ipsr;
+use std::cmp;
+
+const SHCSR_SVCALLPENDED: usize = 15;
+
+#[cfg(any(armv7m, armv7em))]
+const SHCSR_ENABLE_MASK: u32 = 0b111 << 16;
+
+#[cfg(any(armv7m, armv7em))]
+const SHCSR_ACTIVE_BITS: [(usize, Exception); 7] = [
+    (0, Exception::MemoryManagementFault),
+    (1, Exception::BusFault),
+    (3, Exception::UsageFault),
+    (7, Exception::SVCall),
+    (8, Exception::DebugMonitor),
+    (10, Exception::PendSV),
+    (11, Exception::SysTick),
+];
+
+#[cfg(any(armv7m, armv7em))]
+const SHCSR_PENDED_BITS: [(usize, Exception); 4] = [
+    (12, Exception::UsageFault),
+    (13, Exception::MemoryManagementFault),
+    (14, Exception::BusFault),
+    (SHCSR_SVCALLPENDED, Exception::SVCall),
+];
 
 ///
 /// Register based API to SCB
@@ -141,6 +166,33 @@ pub trait SystemControlBlock {
     ///
     fn write_scr(&mut self, value: u32);
 
+    ///
+    /// Write Configuration and Control Register
+    ///
+    fn write_ccr(&mut self, value: u32);
+
+    ///
+    /// Read System Handler Control and State Register
+    ///
+    fn read_shcsr(&self) -> u32;
+
+    ///
+    /// Write System Handler Control and State Register
+    ///
+    fn write_shcsr(&mut self, value: u32);
+
+    ///
+    /// Write Configurable Fault Status Register (write one to clear)
+    ///
+    #[cfg(any(armv7m, armv7em))]
+    fn write_cfsr(&mut self, value: u32);
+
+    ///
+    /// Write HardFault Status Register (write one to clear)
+    ///
+    #[cfg(any(armv7m, armv7em))]
+    fn write_hfsr(&mut self, value: u32);
+
     ///
     /// Write Debug Exception and Monitor Control Register
     ///
@@ -198,7 +250,12 @@ impl SystemControlBlock for Processor {
     }
 
     fn write_vtor(&mut self, value: u32) {
-        self.vtor = value
+        // Vector table needs to be naturally aligned to its size (rounded up to
+        // next power of two), at minimum 128 bytes. Lower bits are read-as-zero.
+        let vectors = self.exceptions.keys().max().map_or(16, |highest| highest + 1);
+        let table_size = (vectors * 4).next_power_of_two();
+        let alignment = cmp::max(table_size, 128) as u32;
+        self.vtor = value & !(alignment - 1);
     }
 
     #[cfg(any(armv7m, armv7em))]
@@ -289,7 +346,74 @@ impl SystemControlBlock for Processor {
     }
 
     fn write_scr(&mut self, value: u32) {
-        self.scr = value;
+        // SLEEPONEXIT, SLEEPDEEP and SEVONPEND are the only writable bits
+        self.scr = value & 0b1_0110;
+    }
+
+    #[cfg(armv6m)]
+    fn write_ccr(&mut self, _value: u32) {
+        // STKALIGN and UNALIGN_TRP are read-as-one, rest reserved
+    }
+
+    #[cfg(any(armv7m, armv7em))]
+    fn write_ccr(&mut self, value: u32) {
+        // NONBASETHRDENA, USERSETMPEND, UNALIGN_TRP, DIV_0_TRP, BFHFNMIGN, STKALIGN
+        self.ccr = value & 0b11_0001_1011;
+    }
+
+    #[cfg(armv6m)]
+    fn read_shcsr(&self) -> u32 {
+        let mut value = 0;
+        value.set_bit(SHCSR_SVCALLPENDED, self.exception_pending(Exception::SVCall));
+        value
+    }
+
+    #[cfg(any(armv7m, armv7em))]
+    fn read_shcsr(&self) -> u32 {
+        let mut value = self.shcsr & SHCSR_ENABLE_MASK;
+
+        for (bit, exception) in &SHCSR_ACTIVE_BITS {
+            value.set_bit(*bit, self.exception_active(*exception));
+        }
+        for (bit, exception) in &SHCSR_PENDED_BITS {
+            value.set_bit(*bit, self.exception_pending(*exception));
+        }
+        value
+    }
+
+    #[cfg(armv6m)]
+    fn write_shcsr(&mut self, value: u32) {
+        if value.get_bit(SHCSR_SVCALLPENDED) {
+            self.set_exception_pending(Exception::SVCall);
+        } else {
+            self.clear_pending_exception(Exception::SVCall);
+        }
+    }
+
+    #[cfg(any(armv7m, armv7em))]
+    fn write_shcsr(&mut self, value: u32) {
+        self.shcsr = value & SHCSR_ENABLE_MASK;
+
+        for (bit, exception) in &SHCSR_ACTIVE_BITS {
+            self.set_exception_active(*exception, value.get_bit(*bit));
+        }
+        for (bit, exception) in &SHCSR_PENDED_BITS {
+            if value.get_bit(*bit) {
+                self.set_exception_pending(*exception);
+            } else {
+                self.clear_pending_exception(*exception);
+            }
+        }
+    }
+
+    #[cfg(any(armv7m, armv7em))]
+    fn write_cfsr(&mut self, value: u32) {
+        self.cfsr &= !value;
+    }
+
+    #[cfg(any(armv7m, armv7em))]
+    fn write_hfsr(&mut self, value: u32) {
+        self.hfsr &= !value;
     }
 
     fn write_demcr(&mut self, _value: u32) {}
@@ -380,7 +504,7 @@ impl SystemControlBlock for Processor {
     }
 
     fn read_scr(&self) -> u32 {
-        0
+        self.scr
     }
     fn read_vtor(&self) -> u32 {
         self.vtor
@@ -405,6 +529,73 @@ mod tests {
     use crate::core::exception::Exception;
     use crate::core::exception::ExceptionHandling;
 
+    #[test]
+    fn test_shcsr_active_and_pended_bits() {
+        // Arrange
+        let mut processor = Processor::new();
+        processor.set_exception_active(Exception::SVCall, true);
+        processor.set_exception_pending(Exception::BusFault);
+
+        // Act
+        let value = processor.read_shcsr();
+
+        // Assert
+        assert_eq!(value, (1 << 7) | (1 << 14));
+    }
+
+    #[test]
+    fn test_shcsr_write() {
+        // Arrange
+        let mut processor = Processor::new();
+
+        // Act
+        processor.write_shcsr((0b111 << 16) | (1 << 11) | (1 << 15));
+
+        // Assert
+        assert_eq!(processor.read_shcsr(), (0b111 << 16) | (1 << 11) | (1 << 15));
+        assert!(processor.exception_active(Exception::SysTick));
+        assert!(processor.exception_pending(Exception::SVCall));
+
+        // Act
+        processor.write_shcsr(0);
+
+        // Assert
+        assert_eq!(processor.read_shcsr(), 0);
+        assert!(!processor.exception_active(Exception::SysTick));
+        assert!(!processor.exception_pending(Exception::SVCall));
+    }
+
+    #[test]
+    fn test_vtor_alignment() {
+        // Arrange
+        let mut processor = Processor::new();
+
+        // Act
+        processor.write_vtor(0x0800_2000);
+
+        // Assert
+        assert_eq!(processor.read_vtor(), 0x0800_2000);
+
+        // Act
+        processor.write_vtor(0x2000_01ff);
+
+        // Assert (48 vectors, 256 byte alignment)
+        assert_eq!(processor.read_vtor(), 0x2000_0100);
+
+        // Arrange (17 interrupts)
+        for irqn in 17..32 {
+            processor
+                .exceptions
+                .remove(&Exception::Interrupt { n: irqn }.into());
+        }
+
+        // Act
+        processor.write_vtor(0x2000_0180);
+
+        // Assert (33 vectors, 256 byte alignment)
+        assert_eq!(processor.read_vtor(), 0x2000_0100);
+    }
+
     #[test]
     #[cfg(any(armv7m, armv7em))]
     fn test_shpr_read_write_32() {
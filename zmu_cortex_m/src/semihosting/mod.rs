@@ -2,7 +2,7 @@
 //! Cortex Semihosting simulation
 //!
 
-use crate::bus::Bus;
+use crate::bus::{Bus, BusInternal};
 use crate::core::bits::Bits;
 use crate::core::fault::Fault;
 use crate::core::register::BaseReg;
@@ -208,6 +208,8 @@ pub enum SemihostingResponse {
         success: bool,
         /// system is stopping
         stop: bool,
+        /// exit status of the application, when stopping
+        exit_code: u32,
     },
     /// sysexitextended command response
     SysExitExtended {
@@ -215,6 +217,8 @@ pub enum SemihostingResponse {
         success: bool,
         /// system is stopping
         stop: bool,
+        /// exit status of the application, when stopping
+        exit_code: u32,
     },
     /// sysclock command response
     SysClock {
@@ -329,9 +333,27 @@ pub fn decode_semihostcmd(
 
             SemihostingCommand::SysExitExtended { reason, subcode }
         }
-        SYS_EXIT => SemihostingCommand::SysException {
-            reason: SysExceptionReason::from_u32(r1),
-        },
+        SYS_EXIT => {
+            let reason = SysExceptionReason::from_u32(r1);
+            // semihosting v2 allows passing a parameter block with the reason
+            // and the exit code, as done on 64-bit targets. Probing for the
+            // block is not an access of the application, so it is not traced.
+            let block_reason = match reason {
+                SysExceptionReason::ADPStopped => processor
+                    .bus_read32(r1)
+                    .ok()
+                    .map(SysExceptionReason::from_u32)
+                    .filter(|reason| *reason != SysExceptionReason::ADPStopped),
+                _ => None,
+            };
+            match block_reason {
+                Some(reason) => SemihostingCommand::SysExitExtended {
+                    reason,
+                    subcode: processor.read32(r1.wrapping_add(4))?,
+                },
+                None => SemihostingCommand::SysException { reason },
+            }
+        }
         _ => {
             panic!("unknown semihosting command {}", r0);
         }
@@ -357,10 +379,21 @@ pub fn semihost_return(processor: &mut Processor, response: &SemihostingResponse
             Ok(response) => processor.set_r(Reg::R0, response),
             Err(error_code) => processor.set_r(Reg::R0, error_code as u32),
         },
-        SemihostingResponse::SysException { success, stop }
-        | SemihostingResponse::SysExitExtended { success, stop } => {
+        SemihostingResponse::SysException {
+            success,
+            stop,
+            exit_code,
+        }
+        | SemihostingResponse::SysExitExtended {
+            success,
+            stop,
+            exit_code,
+        } => {
             if success {
                 processor.state.set_bit(0, !stop);
+                if stop {
+                    processor.exit_code = Some(exit_code);
+                }
             }
         }
         SemihostingResponse::SysClose { success } | SemihostingResponse::SysSeek { success } => {
@@ -394,3 +427,53 @@ pub fn semihost_return(processor: &mut Processor, response: &SemihostingResponse
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::trace::{BusAccess, BusTrace};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_sys_exit() {
+        let mut processor = Processor::new();
+        processor.write32(0x2000_0000, 0x20026).unwrap();
+        processor.write32(0x2000_0004, 3).unwrap();
+        let accesses = Rc::new(RefCell::new(Vec::new()));
+        let sink = Rc::clone(&accesses);
+        processor.bus_trace(Some(BusTrace::new(
+            Box::new(move |access: &BusAccess| sink.borrow_mut().push(access.address)),
+            &[],
+        )));
+
+        assert_eq!(
+            decode_semihostcmd(SYS_EXIT, 0x20026, &mut processor),
+            Ok(SemihostingCommand::SysException {
+                reason: SysExceptionReason::ADPStoppedApplicationExit
+            })
+        );
+        // parameter block with the reason and the exit code
+        assert_eq!(
+            decode_semihostcmd(SYS_EXIT, 0x2000_0000, &mut processor),
+            Ok(SemihostingCommand::SysExitExtended {
+                reason: SysExceptionReason::ADPStoppedApplicationExit,
+                subcode: 3
+            })
+        );
+        // only the exit code of the parameter block is read by the application
+        assert_eq!(*accesses.borrow(), vec![0x2000_0004]);
+
+        processor.state.set_bit(0, true);
+        semihost_return(
+            &mut processor,
+            &SemihostingResponse::SysExitExtended {
+                success: true,
+                stop: true,
+                exit_code: 3,
+            },
+        );
+        assert_eq!(processor.state & 1, 0);
+        assert_eq!(processor.exit_code, Some(3));
+    }
+}
@@ -5,10 +5,11 @@
 use crate::core::bits::Bits;
 use crate::core::executor::Executor;
 use crate::core::fault::Fault;
-use crate::core::register::BaseReg;
+use crate::core::register::{BaseReg, Ipsr};
 use crate::core::reset::Reset;
 use crate::semihosting::SemihostingCommand;
 use crate::semihosting::SemihostingResponse;
+use crate::memory::config::MemoryRegionConfig;
 use crate::MemoryMapConfig;
 use crate::Processor;
 use std::io;
@@ -44,6 +45,125 @@ pub struct SimulationStatistics {
     /// Wallclock time spent for the simulation
     ///
     pub duration: Duration,
+
+    ///
+    /// Reason for the simulation to stop
+    ///
+    pub stop_reason: StopReason,
+
+    ///
+    /// Exit status given by the application through semihosting
+    ///
+    pub exit_code: Option<u32>,
+}
+
+///
+/// Reasons for a simulation run to stop
+///
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub enum StopReason {
+    ///
+    /// The simulation was terminated, for example by a semihosting exit call
+    ///
+    Exit,
+
+    ///
+    /// The instruction count reached the limit
+    ///
+    InstructionLimit,
+
+    ///
+    /// The cycle count reached the limit
+    ///
+    CycleLimit,
+
+    ///
+    /// The simulation ran longer than the wallclock time limit
+    ///
+    Timeout,
+
+    ///
+    /// A fault in the HardFault or NMI handler locked up the processor
+    ///
+    Lockup {
+        /// address of the faulting instruction
+        pc: u32,
+    },
+}
+
+///
+/// Limits for the length of a simulation run
+///
+#[derive(Debug, Default, Copy, Clone)]
+pub struct RunLimits {
+    /// maximum number of instructions to execute
+    pub max_instructions: Option<u64>,
+    /// maximum number of clock cycles to simulate
+    pub max_cycles: Option<u64>,
+    /// maximum wallclock time to run
+    pub timeout: Option<Duration>,
+}
+
+// Number of steps between the checks of the wallclock time
+const TIMEOUT_CHECK_INTERVAL: u32 = 0x1_0000;
+
+// Stops the simulation when a run limit is reached
+struct LimitCheck {
+    limits: RunLimits,
+    deadline: Option<Instant>,
+    steps: u32,
+    reason: Option<StopReason>,
+}
+
+impl LimitCheck {
+    fn new(limits: RunLimits, start: Instant) -> Self {
+        Self {
+            limits,
+            deadline: limits.timeout.map(|timeout| start + timeout),
+            steps: 0,
+            reason: None,
+        }
+    }
+
+    #[inline(always)]
+    fn check(&mut self, processor: &mut Processor) {
+        let reason = if self
+            .limits
+            .max_instructions
+            .is_some_and(|max| processor.instruction_count >= max)
+        {
+            StopReason::InstructionLimit
+        } else if self
+            .limits
+            .max_cycles
+            .is_some_and(|max| processor.cycle_count >= max)
+        {
+            StopReason::CycleLimit
+        } else if self.deadline.is_some_and(|deadline| {
+            self.steps = self.steps.wrapping_add(1);
+            self.steps % TIMEOUT_CHECK_INTERVAL == 0 && Instant::now() >= deadline
+        }) {
+            StopReason::Timeout
+        } else {
+            return;
+        };
+        self.reason = Some(reason);
+        processor.state.set_bit(0, false);
+    }
+
+    fn statistics(&self, processor: &Processor, start: Instant) -> SimulationStatistics {
+        let stop_reason = match processor.lockup {
+            Some(pc) => StopReason::Lockup { pc },
+            None => self.reason.unwrap_or(StopReason::Exit),
+        };
+        SimulationStatistics {
+            instruction_count: processor.instruction_count,
+            cycle_count: processor.cycle_count,
+            duration: Instant::now().duration_since(start),
+            stop_reason,
+            exit_code: processor.exit_code,
+        }
+    }
 }
 
 impl From<Fault> for SimulationError {
@@ -61,6 +181,7 @@ pub fn simulate(
     itm_file: Option<Box<dyn io::Write + 'static>>,
     map: Option<MemoryMapConfig>,
     flash_size: usize,
+    memory: &[MemoryRegionConfig],
 ) -> Result<SimulationStatistics, SimulationError> {
     let mut processor = Processor::new();
 
@@ -68,11 +189,22 @@ pub fn simulate(
     processor.semihost(Some(semihost_func));
     processor.memory_map(map);
     processor.flash_memory(flash_size, code);
-    //processor.ram_memory(ram_size);
+    processor.memory_regions(memory);
+
+    simulate_processor(&mut processor)
+}
 
+///
+/// Run simulation of an already configured processor, for example one
+/// with custom peripherals attached, until processing gets terminated
+///
+pub fn simulate_processor(
+    processor: &mut Processor,
+) -> Result<SimulationStatistics, SimulationError> {
     processor.cache_instructions();
 
     let start = Instant::now();
+    let mut limits = LimitCheck::new(processor.limits, start);
     processor.reset()?;
     processor.state.set_bit(0, true); // running
 
@@ -80,20 +212,17 @@ pub fn simulate(
         while processor.state == 0b01 {
             //running, !sleeping
             processor.step();
+            limits.check(processor);
         }
 
-        while processor.state == 0b11 {
-            //running, sleeping
+        while processor.state & 0b11 == 0b11 {
+            //running, sleeping (bit 2 tells if the sleep is deep)
             processor.step_sleep();
+            limits.check(processor);
         }
     }
-    let end = Instant::now();
 
-    Ok(SimulationStatistics {
-        instruction_count: processor.instruction_count,
-        cycle_count: processor.cycle_count,
-        duration: end.duration_since(start),
-    })
+    Ok(limits.statistics(processor, start))
 }
 
 ///
@@ -101,11 +230,12 @@ pub fn simulate(
 ///
 pub fn simulate_trace<F>(
     code: &[u8],
-    mut trace_func: F,
+    trace_func: F,
     semihost_func: Box<dyn FnMut(&SemihostingCommand) -> SemihostingResponse + 'static>,
     itm_file: Option<Box<dyn io::Write + 'static>>,
     map: Option<MemoryMapConfig>,
     flash_size: usize,
+    memory: &[MemoryRegionConfig],
 ) -> Result<SimulationStatistics, SimulationError>
 where
     F: FnMut(&Processor),
@@ -115,32 +245,95 @@ where
     processor.semihost(Some(semihost_func));
     processor.memory_map(map);
     processor.flash_memory(flash_size, code);
+    processor.memory_regions(memory);
+
+    simulate_processor_trace(&mut processor, trace_func)
+}
+
+///
+/// Run simulation of an already configured processor with tracing support
+///
+pub fn simulate_processor_trace<F>(
+    processor: &mut Processor,
+    mut trace_func: F,
+) -> Result<SimulationStatistics, SimulationError>
+where
+    F: FnMut(&Processor),
+{
     processor.cache_instructions();
 
     let start = Instant::now();
+    let mut limits = LimitCheck::new(processor.limits, start);
 
-    processor.reset().unwrap();
+    processor.reset()?;
     processor.state.set_bit(0, true); // running
 
     while processor.state & 1 == 1 {
         while processor.state == 0b01 {
             //running, !sleeping
             processor.last_pc = processor.get_pc();
+            processor.last_isr_number = processor.psr.get_isr_number();
             processor.step();
-            trace_func(&processor);
+            trace_func(processor);
+            limits.check(processor);
         }
         processor.last_pc = processor.get_pc();
-        while processor.state == 0b11 {
-            //running, sleeping
+        while processor.state & 0b11 == 0b11 {
+            //running, sleeping (bit 2 tells if the sleep is deep)
             processor.step_sleep();
+            limits.check(processor);
         }
     }
 
-    let end = Instant::now();
+    Ok(limits.statistics(processor, start))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run_limits() {
+        // vector table followed by "b ." at 0x8
+        let code = [
+            0x00, 0x10, 0x00, 0x20, 0x09, 0x00, 0x00, 0x00, 0xfe, 0xe7,
+        ];
+        let mut processor = Processor::new();
+        processor.flash_memory(code.len(), &code);
+        processor.run_limits(RunLimits {
+            max_instructions: Some(100),
+            ..RunLimits::default()
+        });
+
+        let statistics = simulate_processor(&mut processor).ok().unwrap();
+        assert_eq!(statistics.instruction_count, 100);
+        assert_eq!(statistics.stop_reason, StopReason::InstructionLimit);
 
-    Ok(SimulationStatistics {
-        instruction_count: processor.instruction_count,
-        cycle_count: processor.cycle_count,
-        duration: end.duration_since(start),
-    })
+        processor.run_limits(RunLimits {
+            max_cycles: Some(1000),
+            ..RunLimits::default()
+        });
+        let statistics = simulate_processor(&mut processor).ok().unwrap();
+        assert!(statistics.cycle_count >= 1000);
+        assert_eq!(statistics.stop_reason, StopReason::CycleLimit);
+    }
+
+    #[test]
+    fn test_cycle_limit_while_sleeping() {
+        // vector table followed by "wfi; b ." at 0x8, with nothing to wake up the core
+        let code = [
+            0x00, 0x10, 0x00, 0x20, 0x09, 0x00, 0x00, 0x00, 0x30, 0xbf, 0xfe, 0xe7,
+        ];
+        let mut processor = Processor::new();
+        processor.flash_memory(code.len(), &code);
+        processor.run_limits(RunLimits {
+            max_cycles: Some(1000),
+            ..RunLimits::default()
+        });
+
+        let statistics = simulate_processor(&mut processor).ok().unwrap();
+        assert_eq!(statistics.instruction_count, 1);
+        assert_eq!(statistics.cycle_count, 1000);
+        assert_eq!(statistics.stop_reason, StopReason::CycleLimit);
+    }
 }